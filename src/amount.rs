@@ -0,0 +1,129 @@
+use std::fmt;
+use std::str::FromStr;
+
+use failure::{format_err, Error};
+use serde::{Serialize, Deserialize};
+use crate::errors::Result;
+
+/// Number of decimal places shown for an amount
+pub const DECIMALS: usize = 8;
+
+/// Base units in one coin
+pub const COIN: u64 = 100_000_000;
+
+/// A non-negative amount of coins, stored as an integer number of base units
+/// so that balances never drift from floating point rounding.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    pub const MAX: Amount = Amount(u64::MAX);
+
+    pub const fn from_base_units(units: u64) -> Amount {
+        Amount(units)
+    }
+
+    /// Whole coins, panicking on overflow. Meant for constants.
+    pub const fn from_coins(coins: u64) -> Amount {
+        match coins.checked_mul(COIN) {
+            Some(units) => Amount(units),
+            None => panic!("Amount overflow"),
+        }
+    }
+
+    pub fn base_units(&self) -> u64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Sums amounts, failing instead of wrapping on overflow
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Result<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, |acc, a| {
+            acc.checked_add(a).ok_or_else(|| format_err!("ERROR: Amount overflow"))
+        })
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{:0width$}", self.0 / COIN, self.0 % COIN, width = DECIMALS)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = Error;
+
+    /// Parses a decimal amount such as `12`, `0.5` or `1.00000001`
+    fn from_str(s: &str) -> Result<Amount> {
+        let invalid = || format_err!("ERROR: Invalid amount '{}'", s);
+
+        let (whole, frac) = match s.split_once('.') {
+            Some((whole, frac)) => (whole, frac),
+            None => (s, ""),
+        };
+
+        if whole.is_empty() && frac.is_empty() {
+            return Err(invalid());
+        }
+        if !whole.chars().all(|c| c.is_ascii_digit()) || !frac.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        if frac.len() > DECIMALS {
+            return Err(format_err!("ERROR: Amount '{}' has more than {} decimal places", s, DECIMALS));
+        }
+
+        let whole: u64 = if whole.is_empty() { 0 } else { whole.parse().map_err(|_| invalid())? };
+        let frac: u64 = if frac.is_empty() {
+            0
+        } else {
+            format!("{:0<width$}", frac, width = DECIMALS).parse().map_err(|_| invalid())?
+        };
+
+        whole.checked_mul(COIN)
+            .and_then(|units| units.checked_add(frac))
+            .map(Amount)
+            .ok_or_else(|| format_err!("ERROR: Amount '{}' is too large", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!("1".parse::<Amount>().unwrap(), Amount::from_coins(1));
+        assert_eq!("0.5".parse::<Amount>().unwrap(), Amount::from_base_units(50_000_000));
+        assert_eq!(".00000001".parse::<Amount>().unwrap(), Amount::from_base_units(1));
+        assert_eq!("12.".parse::<Amount>().unwrap(), Amount::from_coins(12));
+
+        assert!("".parse::<Amount>().is_err());
+        assert!(".".parse::<Amount>().is_err());
+        assert!("-1".parse::<Amount>().is_err());
+        assert!("1.5e3".parse::<Amount>().is_err());
+        assert!("0.000000001".parse::<Amount>().is_err());
+        assert!("999999999999".parse::<Amount>().is_err());
+    }
+
+    #[test]
+    fn test_display_amount() {
+        assert_eq!(Amount::from_coins(100).to_string(), "100.00000000");
+        assert_eq!(Amount::from_base_units(1).to_string(), "0.00000001");
+        assert_eq!("3.14".parse::<Amount>().unwrap().to_string(), "3.14000000");
+    }
+
+    #[test]
+    fn test_checked_arithmetic() {
+        assert!(Amount::MAX.checked_add(Amount::from_base_units(1)).is_none());
+        assert!(Amount::ZERO.checked_sub(Amount::from_base_units(1)).is_none());
+        assert!(Amount::checked_sum(vec![Amount::MAX, Amount::from_base_units(1)]).is_err());
+    }
+}
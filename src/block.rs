@@ -5,116 +5,255 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
 use bincode;
 use crate::errors::{Result};
+use crate::merkle::{self, MerkleProof};
+use crate::pow::Target;
 use crate::transaction::{Transaction};
 
+/// Version written in the header of newly mined blocks
+pub const BLOCK_VERSION: u32 = 1;
 
-const TARGET_HEXT: usize = 4;
+/// The part of a block covered by the proof of work. It commits to the
+/// transactions through `merkle_root`, so headers can be stored, hashed and
+/// followed back to genesis without loading the transactions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlockHeader {
+    version: u32,
+    prev_hash: String,
+    merkle_root: String,
+    timestamp: u128,
+    bits: u32,
+    nonce: u32,
+    height: usize,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
-    timestamp: u128,
-    transactions: Vec<Transaction>,
-    prev_hash: String,
+    header: BlockHeader,
     hash: String,
-    height: usize,
-    nonce: i32,
+    transactions: Vec<Transaction>,
+}
+
+impl BlockHeader {
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    pub fn get_prev_hash(&self) -> String {
+        self.prev_hash.clone()
+    }
+
+    pub fn get_merkle_root(&self) -> String {
+        self.merkle_root.clone()
+    }
+
+    pub fn get_timestamp(&self) -> u128 {
+        self.timestamp
+    }
+
+    pub fn get_bits(&self) -> u32 {
+        self.bits
+    }
+
+    pub fn get_nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    pub fn get_height(&self) -> usize {
+        self.height
+    }
+
+    /// Hex encoded hash of the serialized header
+    pub fn hash(&self) -> Result<String> {
+        Ok(hex::encode(self.calculate_hash()?))
+    }
+
+    /// Whether the header hash satisfies the target encoded in `bits`
+    pub fn check_proof_of_work(&self) -> Result<bool> {
+        Ok(Target::from_compact(self.bits).is_met_by(&self.calculate_hash()?))
+    }
+
+    fn calculate_hash(&self) -> Result<Vec<u8>> {
+        let data = bincode::serialize(self)?;
+        let mut hasher = Sha256::new();
+        hasher.update(&data[..]);
+        Ok(hasher.finalize().to_vec())
+    }
+
+    fn mine(&mut self) -> Result<String> {
+        info!("Mining the block at height {}", self.height);
+
+        let target = Target::from_compact(self.bits);
+        let mut hash = self.calculate_hash()?;
+        while !target.is_met_by(&hash) {
+            if self.nonce == u32::MAX {
+                // Nonce space exhausted, a new timestamp gives a fresh one
+                self.timestamp = now().max(self.timestamp + 1);
+                self.nonce = 0;
+            } else {
+                self.nonce += 1;
+            }
+            hash = self.calculate_hash()?;
+        }
+
+        Ok(hex::encode(hash))
+    }
 }
 
 impl Block {
-    pub fn new(transactions: Vec<Transaction>, prev_hash: String, height: usize) -> Result<Block> {
-        let timestamp = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_millis();
-        let mut block = Block {
-            timestamp: timestamp,
-            transactions : transactions,
+    /// Mines a block stamped with the current time, or with `min_timestamp`
+    /// if the clock has not reached it yet
+    pub fn new(transactions: Vec<Transaction>, prev_hash: String, height: usize, bits: u32,
+               min_timestamp: u128) -> Result<Block> {
+        let merkle_root = merkle::merkle_root(&Block::txids(&transactions))?;
+        let mut header = BlockHeader {
+            version: BLOCK_VERSION,
             prev_hash,
-            hash: String::new(),
-            height,
+            merkle_root,
+            timestamp: now().max(min_timestamp),
+            bits,
             nonce: 0,
+            height,
         };
 
-        block.verify()?;
+        let hash = header.mine()?;
+        let block = Block {
+            header,
+            hash,
+            transactions,
+        };
         info!("Block created: {:?}", block);
 
         Ok(block)
     }
 
+    pub fn get_header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn get_prev_hash(&self) -> String {
-        self.prev_hash.clone()
+        self.header.get_prev_hash()
     }
 
     pub fn get_hash(&self) -> String {
         self.hash.clone()
     }
 
+    pub fn get_merkle_root(&self) -> String {
+        self.header.get_merkle_root()
+    }
+
     pub fn get_height(&self) -> usize {
-        self.height
+        self.header.get_height()
     }
 
-    pub fn get_nonce(&self) -> i32 {
-        self.nonce
+    pub fn get_bits(&self) -> u32 {
+        self.header.get_bits()
+    }
+
+    pub fn get_timestamp(&self) -> u128 {
+        self.header.get_timestamp()
+    }
+
+    pub fn get_nonce(&self) -> u32 {
+        self.header.get_nonce()
     }
 
     pub fn get_transactions(&self) -> &Vec<Transaction> {
         &self.transactions
     }
 
-    fn verify(&mut self) -> Result<()> {
-        log::info!("Mining the block {}", self.nonce);
-
-        while !self.validate()? {
-            self.nonce += 1;
+    /// Inclusion proof for `txid`, if the transaction is in this block
+    pub fn merkle_proof(&self, txid: &str) -> Result<Option<MerkleProof>> {
+        let txids = Block::txids(&self.transactions);
+        match txids.iter().position(|id| id == txid) {
+            Some(index) => Ok(Some(merkle::merkle_proof(&txids, index)?)),
+            None => Ok(None),
         }
-
-        let data = self.prepare_hash()?;
-
-        let mut hasher = Sha256::new();
-        hasher.update(&data[..]);
-        self.hash = hasher.finalize().to_vec().iter().map(|b| format!("{:02x}", b)).collect::<String>();
-        Ok(())
     }
 
-    fn prepare_hash(&self) -> Result<Vec<u8>>{
-        let content = (
-            self.prev_hash.clone(),
-            self.transactions.clone(),
-            self.timestamp,
-            TARGET_HEXT,
-            self.nonce
-        );
-
-        let bytes = bincode::serialize(&content)?;
-        Ok(bytes)
-    } 
+    /// Serialized size of a block without transactions, the space a block
+    /// template has to set aside for the header and the block hash
+    pub fn empty_size() -> Result<usize> {
+        let hash = "0".repeat(64);
+        let block = Block {
+            header: BlockHeader {
+                version: BLOCK_VERSION,
+                prev_hash: hash.clone(),
+                merkle_root: hash.clone(),
+                timestamp: 0,
+                bits: 0,
+                nonce: 0,
+                height: 0,
+            },
+            hash,
+            transactions: Vec::new(),
+        };
+        Ok(bincode::serialized_size(&block)? as usize)
+    }
 
-    fn validate(&self) -> Result<bool> {
-        let data = self.prepare_hash()?;
-        let mut hasher = Sha256::new();
-        hasher.update(&data[..]);
-        let mut vec = vec![];
-        vec.resize(TARGET_HEXT, '0' as u8);
-        println!("{:?}", vec);
-        let hash = hasher.finalize();
+    fn txids(transactions: &[Transaction]) -> Vec<String> {
+        transactions.iter().map(|tx| tx.get_id()).collect()
+    }
 
-        let hash_str = hash.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    /// Checks that every txid is the hash of its transaction, that the merkle
+    /// root commits to the txids, that the stored hash is the hash of the
+    /// header and that it satisfies the target encoded in `bits`
+    pub fn validate(&self) -> Result<bool> {
+        if self.transactions.is_empty()
+            || self.transactions.iter().any(|tx| tx.compute_id() != tx.get_id())
+            || merkle::merkle_root(&Block::txids(&self.transactions))? != self.header.merkle_root {
+            return Ok(false);
+        }
 
-        Ok(&hash_str[0..TARGET_HEXT] == String::from_utf8(vec)?)
-        
+        Ok(self.header.hash()? == self.hash && self.header.check_proof_of_work()?)
     }
 }
 
+/// Milliseconds since the Unix epoch, the unit of block timestamps
+pub fn now() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chainparams::ChainParams;
+    use crate::wallet::new_wallet;
 
     #[test]
     fn test_block() {
-        // let mut block = Block::new("Hello".to_string(), "0".to_string(), 0).unwrap();
-        // assert_eq!(block.transactions, "Hello");
-        // assert_eq!(block.prev_hash, "0");
-        // assert_eq!(block.height, 0);
-        // dbg!(block);
+        let params = ChainParams::main();
+        let address = new_wallet().get_address(params.address_version);
+        let tx = Transaction::new_coinbase(address, 0, &[], params.subsidy(0), &params).unwrap();
+        let block = Block::new(vec![tx], "0".to_string(), 0, params.pow_limit_bits, 0).unwrap();
+        assert_eq!(block.get_transactions().len(), 1);
+        assert_eq!(block.get_prev_hash(), "0");
+        assert_eq!(block.get_height(), 0);
+        assert!(block.validate().unwrap());
+
+        assert_eq!(block.get_header().hash().unwrap(), block.get_hash());
+
+        let mut tampered = block.clone();
+        tampered.header.nonce += 1;
+        assert!(!tampered.validate().unwrap());
+
+        // A coinbase paying elsewhere under the original txid is caught
+        let txid = block.get_transactions()[0].get_id();
+        let other = Transaction::new_coinbase(new_wallet().get_address(params.address_version), 0, &[],
+            params.subsidy(0), &params).unwrap();
+        // The id comes first, after its length
+        let mut encoded = bincode::serialize(&other).unwrap();
+        encoded[8..8 + txid.len()].copy_from_slice(txid.as_bytes());
+        let mut tampered = block.clone();
+        tampered.transactions = vec![bincode::deserialize(&encoded).unwrap()];
+        assert_eq!(tampered.transactions[0].get_id(), txid);
+        assert!(!tampered.validate().unwrap());
+
+        let proof = block.merkle_proof(&txid).unwrap().unwrap();
+        assert!(proof.verify(&block.get_merkle_root()).unwrap());
+        assert!(block.merkle_proof("missing").unwrap().is_none());
     }
 }
\ No newline at end of file
@@ -1,22 +1,31 @@
-
 use std::collections::HashMap;
-use std::hash::Hash;
 
 use bincode::{self, deserialize};
 use failure::format_err;
-use crate::block::Block;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use sled::Transactional;
+use crate::amount::Amount;
+use crate::block::{Block, BlockHeader};
+use crate::chainparams::ChainParams;
 use crate::errors::Result;
+use crate::mempool::{Mempool, MEMPOOL_TREE};
+use crate::pow::{retarget, Target};
 use crate::transaction::Transaction;
-use crate::tx::{TXInput, TXOutput};
+use crate::tx::TXOutputs;
+use crate::utxoset::{SpentOutput, UTXOSet, UTXO_TREE};
+use crate::validation::{self, MemoryCoinView};
 use log::info;
 
-const TARGET_HEXT: usize = 4;
-const GENESIS_COINBASE: &str = "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks";
+const HEADERS_TREE: &str = "headers";
+const WORK_TREE: &str = "chainwork";
+const UNDO_TREE: &str = "undo";
+const INVALID_TREE: &str = "invalid";
 
 #[derive(Debug, Clone)]
 pub struct Blockchain {
     tip: String,
     db: sled::Db,
+    params: ChainParams,
 }
 
 #[derive(Debug, Clone)]
@@ -25,162 +34,494 @@ pub struct BlockchainIterator<'a> {
     bc: &'a Blockchain,
 }
 
+/// Walks the chain from the tip following `prev_hash`, only loading headers
+#[derive(Debug, Clone)]
+pub struct HeaderIterator<'a> {
+    curr_hash: String,
+    bc: &'a Blockchain,
+}
+
 impl Blockchain {
-    pub fn new() -> Result<Blockchain> {
-        let db = sled::open("data/blocks")?;
-        let db_last = db.get("LAST_BLOCK")?.
-            expect("No last block found");
+    /// Loads the chain stored in `db`
+    pub fn new(db: sled::Db, params: ChainParams) -> Result<Blockchain> {
+        let db_last = db.get("LAST_BLOCK")?
+            .ok_or_else(|| format_err!("No existing blockchain found, create one first"))?;
         info!("Loading blockchain");
         let tip = String::from_utf8(db_last.to_vec())?;
         Ok(Blockchain {
             tip,
             db,
+            params,
         })
     }
 
-    pub fn create_blockchain(address: String) -> Result<Blockchain> {
-        info!("Creating a new blockchain");
+    /// Mines a genesis block paying `address` and stores it in `db`
+    pub fn create_blockchain(db: sled::Db, params: ChainParams, address: String) -> Result<Blockchain> {
+        info!("Creating a new {} blockchain", params.network);
 
-        let db = sled::open("data/blocks")?;
-        info!("Creating a new block database");
         let cbtx = Transaction::new_coinbase(
-            address, String::from(GENESIS_COINBASE)); 
+            address, 0, params.genesis_message.as_bytes(), params.subsidy(0), &params); 
         let tx = match cbtx {
             Ok(tx) => tx,
             Err(e) => {
                 panic!("Error generating genesis block. Cause: {}", e);
             }
         };
-        let genesis = Block::new(vec![tx], String::from("GENESIS ARRIVED"), TARGET_HEXT)?;
-        db.insert(genesis.get_hash(), bincode::serialize(&genesis)?)?;
-        db.insert("LAST_BLOCK", genesis.get_hash().as_bytes())?;
+        let genesis = Block::new(vec![tx], String::from("GENESIS ARRIVED"), 0, params.pow_limit_bits, 0)?;
 
-        let bc = Blockchain {
-            tip: genesis.get_hash(),
+        let mut bc = Blockchain {
+            tip: String::new(),
             db,
+            params,
         };
-        bc.db.flush()?;
+        bc.utxo_tree()?.clear()?;
+        bc.undo_tree()?.clear()?;
+        bc.store_block(&genesis, Target::from_compact(genesis.get_bits()).work())?;
+        bc.connect_block(&genesis)?;
 
         Ok(bc)
     }
 
-    pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<()> {
-        let last_block = self.db.get("LAST_BLOCK")?.unwrap();
+    /// Mines a block on top of the current tip and stores it once it passed
+    /// validation
+    pub fn add_block(&mut self, transactions: Vec<Transaction>) -> Result<Block> {
+        let prev = self.get_header(&self.tip)?;
+        let bits = self.next_bits(&prev)?;
+        let min_timestamp = validation::median_time_past(self, &prev)? + 1;
+        let new_block = Block::new(transactions, self.tip.clone(), prev.get_height() + 1, bits, min_timestamp)?;
+        self.accept_block(&new_block)?;
+        Ok(new_block)
+    }
+
+    /// Validates and stores a block building on any known block. A block
+    /// extending the tip is connected right away; a block on another branch
+    /// is kept aside and triggers a reorganization once its branch has more
+    /// cumulative work than the current one.
+    pub fn accept_block(&mut self, block: &Block) -> Result<()> {
+        let hash = block.get_hash();
+        let invalid = |e: failure::Error| format_err!(
+            "ERROR: Block {} at height {} is invalid: {}", hash, block.get_height(), e);
+
+        if self.headers_tree()?.contains_key(hash.as_bytes())? {
+            return Err(format_err!("ERROR: Block {} is already known", hash));
+        }
+        if self.invalid_tree()?.contains_key(hash.as_bytes())? {
+            return Err(format_err!("ERROR: Block {} is already known to be invalid", hash));
+        }
+        if self.invalid_tree()?.contains_key(block.get_prev_hash().as_bytes())? {
+            return Err(invalid(format_err!("builds on invalid block {}", block.get_prev_hash())));
+        }
+
+        let prev = self.get_header(&block.get_prev_hash())
+            .map_err(|_| invalid(format_err!("builds on unknown block {}", block.get_prev_hash())))?;
+        validation::check_header(self, block, Some(&prev)).map_err(invalid)?;
+
+        let chain_work = self.get_chain_work(&block.get_prev_hash())?
+            .saturating_add(Target::from_compact(block.get_bits()).work());
+
+        if block.get_prev_hash() == self.tip {
+            let median_time = validation::median_time_past(self, &prev)?;
+            validation::check_transactions(block, &UTXOSet::new(self), &self.params, median_time)
+                .map_err(invalid)?;
+            self.store_block(block, chain_work)?;
+            return self.connect_block(block);
+        }
+
+        self.store_block(block, chain_work)?;
+        if chain_work > self.get_chain_work(&self.tip)? {
+            self.reorganize(&hash)?;
+        }
 
-        let new_block = Block::new(transactions, String::from_utf8(last_block.to_vec())?, TARGET_HEXT)?;
-        self.db.insert(new_block.get_hash(), bincode::serialize(&new_block)?)?;
-        self.db.insert("LAST_BLOCK", new_block.get_hash().as_bytes())?;
-        self.db.flush()?;
-        self.tip = new_block.get_hash();
         Ok(())
     }
 
-    pub fn iter(&self) -> BlockchainIterator {
-        BlockchainIterator {
-            curr_hash: self.tip.clone(),
-            bc: &self
+    /// Makes `new_tip` the tip: the blocks of the current branch are
+    /// disconnected down to the fork point and the blocks of the new branch
+    /// are validated and connected. If one of them is invalid it is dropped
+    /// along with every block building on it, and the old branch is restored.
+    fn reorganize(&mut self, new_tip: &str) -> Result<()> {
+        let (fork, branch) = self.find_fork(new_tip)?;
+        info!("Reorganizing from {} to {} at fork {}", self.tip, new_tip, fork);
+
+        let mut disconnected = Vec::new();
+        while self.tip != fork {
+            disconnected.push(self.disconnect_tip()?);
+        }
+
+        for hash in &branch {
+            let block = self.get_block(hash)?;
+            let median_time = validation::median_time_past(self, &self.get_header(&block.get_prev_hash())?)?;
+            if let Err(e) = validation::check_transactions(&block, &UTXOSet::new(self), &self.params, median_time) {
+                while self.tip != fork {
+                    self.disconnect_tip()?;
+                }
+                for block in disconnected.iter().rev() {
+                    self.connect_block(block)?;
+                }
+                self.forget_branch(hash)?;
+
+                return Err(format_err!(
+                    "ERROR: Block {} at height {} is invalid: {}", hash, block.get_height(), e));
+            }
+            self.connect_block(&block)?;
         }
+
+        // Payments confirmed only by the losing branch go back to the
+        // mempool, unless the new branch confirmed or conflicts with them
+        let mempool = Mempool::new(self)?;
+        for block in disconnected.iter().rev() {
+            for tx in block.get_transactions().iter().filter(|tx| !tx.is_coinbase()) {
+                if let Err(e) = mempool.add(self, tx.clone()) {
+                    info!("Dropped transaction {} of disconnected block {}: {}", tx.get_id(), block.get_hash(), e);
+                }
+            }
+        }
+
+        Ok(())
     }
 
-    fn find_unspent_transactions(&self, address: &str) -> Vec<Transaction> {
-        let mut spent_tx0s = HashMap::new();
-        let mut unspent_tx0s = Vec::new();
+    /// Last block shared by the current branch and the branch ending at
+    /// `hash`, and the blocks of that branch after it, oldest first
+    fn find_fork(&self, hash: &str) -> Result<(String, Vec<String>)> {
+        let mut branch = Vec::new();
+        let mut side = hash.to_string();
+        let mut side_header = self.get_header(&side)?;
+        let mut main = self.tip.clone();
+        let mut main_header = self.get_header(&main)?;
+
+        while side != main {
+            if side_header.get_height() >= main_header.get_height() {
+                branch.push(side);
+                side = side_header.get_prev_hash();
+                side_header = self.get_header(&side)?;
+            } else {
+                main = main_header.get_prev_hash();
+                main_header = self.get_header(&main)?;
+            }
+        }
 
-        for block in self.iter() {
-            for tx in block.get_transactions() {
-                let txid = tx.get_id();
+        branch.reverse();
+        Ok((side, branch))
+    }
 
-                'outputs: for (out_idx, out) in tx.get_outs().iter().enumerate() {
-                    if spent_tx0s.contains_key(&txid) {
-                        let spent_outs = spent_tx0s.get(&txid).unwrap();
-                        for &spent_out in spent_outs {
-                            if  spent_out == out_idx as f32 {
-                                continue 'outputs;
-                            }
-                        }
-                    }
+    /// Stores a block, its header and the cumulative work of the chain
+    /// ending at it. This does not change the tip.
+    fn store_block(&self, block: &Block, chain_work: u128) -> Result<()> {
+        let encoded = bincode::serialize(block)?;
+        let header = bincode::serialize(block.get_header())?;
+        let headers = self.headers_tree()?;
+        let work = self.work_tree()?;
+
+        (&*self.db, &headers, &work).transaction(|(blocks, headers, work)| {
+            blocks.insert(block.get_hash().as_bytes(), encoded.as_slice())?;
+            headers.insert(block.get_hash().as_bytes(), header.as_slice())?;
+            work.insert(block.get_hash().as_bytes(), &chain_work.to_be_bytes())?;
+            Ok(())
+        }).map_err(tx_error)?;
 
-                    if out.can_be_unlocked_with(address.to_owned()) {
-                        unspent_tx0s.push(tx.clone());
-                    }
-                }
+        Ok(())
+    }
 
-                if !tx.is_coinbase() {
-                    for input in tx.get_ins() {
-                        if input.can_be_unlocked_with(address.to_owned()) {
-                            let in_txid = input.get_txid();
-                            let in_out = input.get_vout();
+    /// Removes a stored block that turned out to be invalid along with the
+    /// stored blocks building on it, so none of them is retried or counted
+    /// as a candidate tip, and marks them all invalid
+    fn forget_branch(&self, hash: &str) -> Result<()> {
+        let mut children: HashMap<String, Vec<String>> = HashMap::new();
+        for entry in self.headers_tree()?.iter() {
+            let (child, header) = entry?;
+            let header: BlockHeader = deserialize(&header)?;
+            children.entry(header.get_prev_hash()).or_default().push(String::from_utf8(child.to_vec())?);
+        }
 
-                            spent_tx0s.entry(in_txid).or_insert(vec![]).push(in_out);
-                        }
-                    }
-                }
+        let headers = self.headers_tree()?;
+        let work = self.work_tree()?;
+        let invalid = self.invalid_tree()?;
+        let mut pending = vec![hash.to_string()];
+        while let Some(hash) = pending.pop() {
+            (&*self.db, &headers, &work, &invalid).transaction(|(blocks, headers, work, invalid)| {
+                blocks.remove(hash.as_bytes())?;
+                headers.remove(hash.as_bytes())?;
+                work.remove(hash.as_bytes())?;
+                invalid.insert(hash.as_bytes(), &[])?;
+                Ok(())
+            }).map_err(tx_error)?;
+            pending.extend(children.remove(&hash).unwrap_or_default());
+        }
+
+        Ok(())
+    }
+
+    /// Applies a stored block on top of the tip. The UTXO index changes, the
+    /// undo data and the new tip are committed in one transaction.
+    fn connect_block(&mut self, block: &Block) -> Result<()> {
+        let utxos = self.utxo_tree()?;
+        let undo = self.undo_tree()?;
+
+        (&*self.db, &utxos, &undo).transaction(|(blocks, utxos, undo)| {
+            let spent = UTXOSet::update(utxos, block)?;
+            let encoded = bincode::serialize(&spent)
+                .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+            undo.insert(block.get_hash().as_bytes(), encoded)?;
+            blocks.insert("LAST_BLOCK", block.get_hash().as_bytes())?;
+            Ok(())
+        }).map_err(tx_error)?;
+
+        self.db.flush()?;
+        self.tip = block.get_hash();
+        Ok(())
+    }
+
+    /// Reverts the tip block using its undo data and makes its parent the tip
+    fn disconnect_tip(&mut self) -> Result<Block> {
+        let block = self.get_block(&self.tip)?;
+        if block.get_height() == 0 {
+            return Err(format_err!("ERROR: Cannot disconnect the genesis block"));
+        }
+
+        let utxos = self.utxo_tree()?;
+        let undo = self.undo_tree()?;
+        let encoded = undo.get(self.tip.as_bytes())?
+            .ok_or_else(|| format_err!("ERROR: No undo data for block {}", self.tip))?;
+        let spent: Vec<SpentOutput> = deserialize(&encoded)?;
+
+        (&*self.db, &utxos, &undo).transaction(|(blocks, utxos, undo)| {
+            UTXOSet::revert(utxos, &block, &spent)?;
+            undo.remove(block.get_hash().as_bytes())?;
+            blocks.insert("LAST_BLOCK", block.get_prev_hash().as_bytes())?;
+            Ok(())
+        }).map_err(tx_error)?;
+
+        self.db.flush()?;
+        self.tip = block.get_prev_hash();
+        Ok(block)
+    }
+
+    /// Replays the stored chain from genesis and re-runs the consensus checks
+    /// on its last `depth` blocks, or on every block if `depth` is `None`.
+    /// Returns the number of blocks checked, or an error naming the first
+    /// invalid block.
+    pub fn verify_chain(&self, depth: Option<usize>) -> Result<usize> {
+        let mut hashes = Vec::new();
+        for header in self.iter_headers() {
+            hashes.push(header.hash()?);
+        }
+        hashes.reverse();
+
+        let first_checked = depth.map_or(0, |depth| hashes.len().saturating_sub(depth));
+        let mut view = MemoryCoinView::default();
+        let mut prev: Option<BlockHeader> = None;
+        let mut checked = 0;
+
+        for (height, hash) in hashes.iter().enumerate() {
+            let block = self.get_block(hash)?;
+
+            if height >= first_checked {
+                let median_time = match &prev {
+                    Some(prev) => validation::median_time_past(self, prev)?,
+                    None => 0,
+                };
+                validation::check_header(self, &block, prev.as_ref())
+                    .and_then(|_| validation::check_transactions(&block, &view, &self.params, median_time))
+                    .map_err(|e| format_err!(
+                        "ERROR: Block {} at height {} is invalid: {}", hash, height, e))?;
+                checked += 1;
             }
+
+            view.apply_block(&block);
+            prev = Some(block.get_header().clone());
         }
 
-        unspent_tx0s
+        Ok(checked)
     }
 
-    // Finds and returns all unspent transaction outputs
-    pub fn find_utxo(&self, address: &str) -> HashMap<String, TXOutput> {
-        let mut utxos: HashMap<String, TXOutput> = HashMap::new();
-        let mut spent_utxos = HashMap::<String, Vec<f32>>::new();
+    /// Coins minted so far: what the coinbases of the chain paid out, minus
+    /// the fees they collected, which only move existing coins
+    pub fn issued_supply(&self) -> Result<Amount> {
+        let mut hashes = Vec::new();
+        for header in self.iter_headers() {
+            hashes.push(header.hash()?);
+        }
+
+        let overflow = || format_err!("ERROR: Amount overflow");
+        let mut values: HashMap<(String, u32), Amount> = HashMap::new();
+        let mut issued = Amount::ZERO;
+
+        for hash in hashes.iter().rev() {
+            let block = self.get_block(hash)?;
+            let mut fees = Amount::ZERO;
+            let mut paid = Amount::ZERO;
 
-        for block in self.iter() {
             for tx in block.get_transactions() {
-                for index in 0..tx.get_outs().len() {
-                    if let Some(ids) = spent_utxos.get(&tx.get_id()) {
-                        if ids.contains(&(index as f32)) {
-                            continue;
-                        }
+                if tx.is_coinbase() {
+                    paid = tx.value_out()?;
+                } else {
+                    let mut value_in = Amount::ZERO;
+                    for vin in tx.get_ins() {
+                        let value = values.remove(&(vin.get_txid(), vin.get_vout()))
+                            .ok_or_else(|| format_err!("ERROR: Output {}:{} not found", vin.get_txid(), vin.get_vout()))?;
+                        value_in = value_in.checked_add(value).ok_or_else(overflow)?;
                     }
+                    let fee = value_in.checked_sub(tx.value_out()?).ok_or_else(overflow)?;
+                    fees = fees.checked_add(fee).ok_or_else(overflow)?;
+                }
 
-                    match utxos.get_mut(&tx.get_id()) {
-                        Some(out) => {
-                            out.value += tx.get_outs()[index].get_value();
-                        }
-                        None => {
-                            utxos.insert(
-                                tx.get_id().clone(),
-                                TXOutput {
-                                    value: tx.get_outs()[index].get_value(),
-                                    pub_key_hash: tx.get_outs()[index].get_script_pub_key().clone().into(),
-                                }
-                            );
-                        }
-                    }
+                for (vout, out) in (0..).zip(tx.get_outs()) {
+                    values.insert((tx.get_id(), vout), out.get_value());
                 }
             }
+
+            issued = issued.checked_add(paid.checked_sub(fees).unwrap_or(Amount::ZERO)).ok_or_else(overflow)?;
         }
-        utxos
+
+        Ok(issued)
     }
 
-    pub fn find_spendable_outputs(
-        &self, 
-        address: &str,
-        amount: f32
-    ) -> (f32, HashMap<String, Vec<f32>>) {
-        let mut unspent_outputs = HashMap::<String, Vec<f32>>::new();
-        let mut accumulated = 0.0;
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
 
-        let unspent_txs = self.find_unspent_transactions(address);
+    pub fn get_block(&self, hash: &str) -> Result<Block> {
+        let encoded = self.db.get(hash)?
+            .ok_or_else(|| format_err!("Block {} not found", hash))?;
+        Ok(deserialize(&encoded)?)
+    }
+
+    pub fn get_header(&self, hash: &str) -> Result<BlockHeader> {
+        let encoded = self.headers_tree()?.get(hash)?
+            .ok_or_else(|| format_err!("Block header {} not found", hash))?;
+        Ok(deserialize(&encoded)?)
+    }
+
+    /// Cumulative work of the chain ending at block `hash`
+    pub fn get_chain_work(&self, hash: &str) -> Result<u128> {
+        let encoded = self.work_tree()?.get(hash)?
+            .ok_or_else(|| format_err!("Chain work of block {} not found", hash))?;
+        Ok(u128::from_be_bytes(encoded.as_ref().try_into()?))
+    }
+
+    pub fn get_tip(&self) -> String {
+        self.tip.clone()
+    }
+
+    pub fn get_best_height(&self) -> Result<usize> {
+        Ok(self.get_header(&self.tip)?.get_height())
+    }
+
+    /// Median time past of the tip, which the lock times of the next block
+    /// are checked against
+    pub fn get_median_time_past(&self) -> Result<u128> {
+        validation::median_time_past(self, &self.get_header(&self.tip)?)
+    }
+
+    /// Bits a block built on top of `prev` must carry. The target only moves
+    /// every `retarget_interval` blocks, based on how long the last window of
+    /// blocks took to mine according to their timestamps.
+    pub fn next_bits(&self, prev: &BlockHeader) -> Result<u32> {
+        let interval = self.params.retarget_interval;
+        if self.params.no_retargeting || !(prev.get_height() + 1).is_multiple_of(interval) {
+            return Ok(prev.get_bits());
+        }
+
+        let mut first = prev.clone();
+        for _ in 1..interval {
+            first = self.get_header(&first.get_prev_hash())?;
+        }
+
+        let timespan = prev.get_timestamp().saturating_sub(first.get_timestamp());
+        Ok(retarget(prev.get_bits(), timespan, self.params.target_timespan_ms(), self.params.pow_limit_bits))
+    }
+
+    /// Checks the proof of work of a block received on top of this chain,
+    /// including that its bits follow the retargeting rule
+    pub fn check_proof_of_work(&self, block: &Block) -> Result<()> {
+        if !block.validate()? {
+            return Err(format_err!("Block {} has an invalid merkle root or proof of work", block.get_hash()));
+        }
+
+        let prev = self.get_header(&block.get_prev_hash())?;
+        let expected = self.next_bits(&prev)?;
+        if block.get_bits() != expected {
+            return Err(format_err!(
+                "Block {} has bits {:#010x}, expected {:#010x}", block.get_hash(), block.get_bits(), expected));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn utxo_tree(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(UTXO_TREE)?)
+    }
+
+    fn headers_tree(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(HEADERS_TREE)?)
+    }
+
+    pub(crate) fn mempool_tree(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(MEMPOOL_TREE)?)
+    }
+
+    fn work_tree(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(WORK_TREE)?)
+    }
+
+    fn undo_tree(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(UNDO_TREE)?)
+    }
+
+    fn invalid_tree(&self) -> Result<sled::Tree> {
+        Ok(self.db.open_tree(INVALID_TREE)?)
+    }
+
+    pub fn iter(&self) -> BlockchainIterator<'_> {
+        BlockchainIterator {
+            curr_hash: self.tip.clone(),
+            bc: self
+        }
+    }
 
-        'outputs: for tx in unspent_txs {
-            let txid = tx.get_id();
+    pub fn iter_headers(&self) -> HeaderIterator<'_> {
+        HeaderIterator {
+            curr_hash: self.tip.clone(),
+            bc: self
+        }
+    }
 
-            for (out_idx, out) in tx.get_outs().iter().enumerate() {
-                if out.can_be_unlocked_with(address.to_owned()) && accumulated < amount {
-                    accumulated += out.get_value();
-                    unspent_outputs.entry(txid.clone()).or_insert(vec![]).push(out_idx as f32);
+    /// Walks the whole chain and collects every output that is not spent by
+    /// a later transaction. Only used to (re)build the UTXO index.
+    pub fn find_utxo(&self) -> HashMap<String, TXOutputs> {
+        let mut utxos: HashMap<String, TXOutputs> = HashMap::new();
+        let mut spent_txos: HashMap<String, Vec<u32>> = HashMap::new();
+
+        for block in self.iter() {
+            // Later transactions of a block may spend earlier ones
+            for tx in block.get_transactions().iter().rev() {
+                let txid = tx.get_id();
 
-                    if accumulated >= amount {
-                        break 'outputs;
+                for (out_idx, out) in (0..).zip(tx.get_outs()) {
+                    if let Some(ids) = spent_txos.get(&txid) {
+                        if ids.contains(&out_idx) {
+                            continue;
+                        }
+                    }
+
+                    let outs = utxos.entry(txid.clone()).or_insert_with(|| TXOutputs {
+                        outputs: HashMap::new(),
+                        height: block.get_height(),
+                        time: block.get_timestamp(),
+                        is_coinbase: tx.is_coinbase(),
+                    });
+                    outs.outputs.insert(out_idx, out);
+                }
+
+                if !tx.is_coinbase() {
+                    for input in tx.get_ins() {
+                        spent_txos.entry(input.get_txid()).or_default().push(input.get_vout());
                     }
                 }
             }
         }
 
-        (accumulated, unspent_outputs)
+        utxos
     }
 
     pub fn verify_transaction(&self, tx: &mut Transaction) -> Result<bool> {
@@ -188,15 +529,16 @@ impl Blockchain {
         tx.verify(prev_txs)
     }
 
-    pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<()> {
+    /// Signs the inputs of `tx` that `private_key` can unlock and returns
+    /// the number of signatures added
+    pub fn sign_transaction(&self, tx: &mut Transaction, private_key: &[u8]) -> Result<usize> {
         let prev_txs = self.get_prev_txs(tx)?;
-        tx.sign(private_key, prev_txs)?;
-        Ok(())
+        tx.sign(private_key, prev_txs)
     }
 
     fn get_prev_txs(&self, tx: &Transaction) -> Result<HashMap<String, Transaction>> {
         let mut prev_txs = HashMap::new();
-        for vin in &tx.get_ins() {
+        for vin in tx.get_ins() {
             let prev_tx = self.find_transaction(&vin.get_txid())?;
             prev_txs.insert(prev_tx.get_id(), prev_tx);
         }
@@ -204,7 +546,17 @@ impl Blockchain {
         Ok(prev_txs)
     }
 
-    fn find_transaction(&self, id: &str) -> Result<Transaction> {
+    /// Finds the block that contains the transaction `txid`
+    pub fn find_transaction_block(&self, txid: &str) -> Result<Block> {
+        for block in self.iter() {
+            if block.get_transactions().iter().any(|tx| tx.get_id() == txid) {
+                return Ok(block);
+            }
+        }
+        Err(format_err!("Transaction not found"))
+    }
+
+    pub fn find_transaction(&self, id: &str) -> Result<Transaction> {
         for block in self.iter() {
             for tx in block.get_transactions() {
                 if tx.get_id() == id {
@@ -214,8 +566,13 @@ impl Blockchain {
         }
         Err(format_err!("Transaction not found"))
     }
+}
 
-    
+fn tx_error(e: TransactionError<failure::Error>) -> failure::Error {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
 }
 
 impl<'a> Iterator for BlockchainIterator<'a> {
@@ -239,16 +596,51 @@ impl<'a> Iterator for BlockchainIterator<'a> {
     }
 }
 
+impl<'a> Iterator for HeaderIterator<'a> {
+    type Item = BlockHeader;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.bc.get_header(&self.curr_hash).ok()?;
+        self.curr_hash = header.get_prev_hash();
+        Some(header)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::script::Script;
+    use crate::testutil::{new_address, test_chain, test_chain_with_key, test_params};
+    use crate::tx::{TXInput, TXOutput};
+    use crate::wallet::decode_destination;
+
+    fn mine_on(bc: &Blockchain, parent: &str, txs: Vec<Transaction>) -> Block {
+        let prev = bc.get_header(parent).unwrap();
+        let min_timestamp = validation::median_time_past(bc, &prev).unwrap() + 1;
+        Block::new(txs, parent.to_string(), prev.get_height() + 1, prev.get_bits(), min_timestamp).unwrap()
+    }
+
+    fn coinbase(to: &str, height: usize, reward: Amount) -> Transaction {
+        Transaction::new_coinbase(to.to_string(), height, &[], reward, &ChainParams::regtest()).unwrap()
+    }
+
+    fn utxo_count(bc: &Blockchain, address: &str) -> usize {
+        UTXOSet::new(bc).find_utxo(&lock(address)).unwrap().len()
+    }
+
+    fn lock(address: &str) -> Script {
+        decode_destination(address, &ChainParams::regtest()).unwrap()
+    }
 
     #[test]
     fn test_blockchain() {
-        let mut bc = Blockchain::new().unwrap();
-        let mut tx = Transaction::new_coinbase("Alice".to_string(), "Bob".to_string()).unwrap();
+        let params = ChainParams::regtest();
+        let reward = params.subsidy(0);
+        let (alice, bob) = (new_address(), new_address());
+        let mut bc = test_chain(params, &alice);
+        let mut tx = Transaction::new_coinbase(alice.clone(), 1, b"Bob", reward, bc.params()).unwrap();
         bc.add_block(vec![tx]).unwrap();
-        tx = Transaction::new_coinbase("Bob".to_string(), "Alice".to_string()).unwrap();
+        tx = Transaction::new_coinbase(bob.clone(), 2, b"Alice", reward, bc.params()).unwrap();
         bc.add_block(vec![tx]).unwrap();
 
         // Check the blocks]
@@ -262,6 +654,96 @@ mod tests {
 
         txs = block.get_transactions();
         assert_eq!(txs.len(), 1);
-        dbg!(block);
+        bc.check_proof_of_work(&block).unwrap();
+
+        // Headers follow the same path as the blocks
+        let heights: Vec<usize> = bc.iter_headers().map(|h| h.get_height()).collect();
+        let block_heights: Vec<usize> = bc.iter().map(|b| b.get_height()).collect();
+        assert_eq!(heights, block_heights);
+        assert_eq!(heights.last(), Some(&0));
+
+        // The index must agree with a full walk of the chain
+        let utxo_set = UTXOSet::new(&bc);
+        let indexed = utxo_set.find_utxo(&lock(&alice)).unwrap();
+        let walked: usize = bc.find_utxo().values()
+            .flat_map(|outs| outs.outputs.values())
+            .filter(|out| out.get_script_pub_key() == &lock(&alice))
+            .count();
+        assert_eq!(indexed.len(), walked);
+        assert_eq!(utxo_count(&bc, &bob), 1);
+
+        // Fresh coinbases are not spendable yet
+        let (balance, immature) = utxo_set.get_balance(&lock(&alice)).unwrap();
+        assert_eq!((balance, immature), (Amount::ZERO, Amount::from_coins(200)));
+        assert!(utxo_set.find_spendable_outputs(&lock(&alice)).unwrap().is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_reorganization() {
+        let (mut bc, alice) = test_chain_with_key(test_params());
+        let reward = bc.params().subsidy(0);
+        let owner = alice.get_address(bc.params().address_version);
+        let genesis = bc.get_tip();
+        let genesis_tx = bc.get_block(&genesis).unwrap().get_transactions()[0].get_id();
+
+        // Branch a spends the genesis output to Bob
+        let (bob, miner_a, miner_b) = (new_address(), new_address(), new_address());
+        let vin = vec![TXInput::new(genesis_tx, 0, Script::new())];
+        let mut spend = Transaction::new(vin, vec![TXOutput::new(reward, bob.clone(), bc.params()).unwrap()]).unwrap();
+        bc.sign_transaction(&mut spend, &alice.private_key).unwrap();
+        let a1 = bc.add_block(vec![coinbase(&miner_a, 1, reward), spend.clone()]).unwrap();
+        assert_eq!(utxo_count(&bc, &owner), 0);
+        assert_eq!(utxo_count(&bc, &bob), 1);
+
+        // Branch b overtakes it once it has more work
+        let b1 = mine_on(&bc, &genesis, vec![coinbase(&miner_b, 1, reward)]);
+        bc.accept_block(&b1).unwrap();
+        assert_eq!(bc.get_tip(), a1.get_hash());
+
+        let b2 = mine_on(&bc, &b1.get_hash(), vec![coinbase(&new_address(), 2, reward)]);
+        bc.accept_block(&b2).unwrap();
+        assert_eq!(bc.get_tip(), b2.get_hash());
+        assert_eq!(bc.get_best_height().unwrap(), 2);
+        assert!(bc.get_chain_work(&b2.get_hash()).unwrap() > bc.get_chain_work(&a1.get_hash()).unwrap());
+
+        // The spend is undone and the genesis output is back, with the spend
+        // waiting in the mempool to be mined again
+        let mempool = Mempool::new(&bc).unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.get(&spend.get_id()).unwrap().is_some());
+        assert_eq!(utxo_count(&bc, &owner), 1);
+        assert_eq!(utxo_count(&bc, &bob), 0);
+        assert_eq!(utxo_count(&bc, &miner_a), 0);
+        assert_eq!(utxo_count(&bc, &miner_b), 1);
+        let walked: usize = bc.find_utxo().values().map(|outs| outs.outputs.len()).sum();
+        let indexed: usize = bc.utxo_tree().unwrap().iter()
+            .map(|entry| deserialize::<TXOutputs>(&entry.unwrap().1).unwrap().outputs.len())
+            .sum();
+        assert_eq!(walked, indexed);
+
+        // A heavier branch with an invalid block is dropped along with the
+        // blocks building on it, and b stays
+        let bad = mine_on(&bc, &a1.get_hash(), vec![coinbase(&miner_a, 2, reward), spend]);
+        bc.accept_block(&bad).unwrap();
+        assert_eq!(bc.get_tip(), b2.get_hash());
+        let child = mine_on(&bc, &bad.get_hash(), vec![coinbase(&miner_a, 3, reward)]);
+        assert!(bc.accept_block(&child).is_err());
+        assert_eq!(bc.get_tip(), b2.get_hash());
+        for hash in [bad.get_hash(), child.get_hash()] {
+            assert!(bc.get_block(&hash).is_err());
+            assert!(bc.get_header(&hash).is_err());
+            assert!(bc.get_chain_work(&hash).is_err());
+        }
+        assert_eq!(utxo_count(&bc, &owner), 1);
+        assert_eq!(utxo_count(&bc, &miner_a), 0);
+
+        // The branch stays invalid
+        assert!(bc.accept_block(&bad).is_err());
+        assert!(bc.accept_block(&child).is_err());
+        let grandchild = Block::new(vec![coinbase(&miner_a, 4, reward)], child.get_hash(), 4,
+            child.get_bits(), child.get_timestamp() + 1).unwrap();
+        let error = bc.accept_block(&grandchild).unwrap_err();
+        assert!(error.to_string().contains("builds on invalid block"), "{}", error);
+        assert_eq!(bc.verify_chain(None).unwrap(), 3);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::str::FromStr;
+
+use failure::{format_err, Error};
+use crate::amount::Amount;
+use crate::errors::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Main,
+    Test,
+    Regtest,
+}
+
+impl FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Network> {
+        match s {
+            "main" | "mainnet" => Ok(Network::Main),
+            "test" | "testnet" => Ok(Network::Test),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(format_err!("ERROR: Unknown network '{}', expected main, test or regtest", s)),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Main => write!(f, "main"),
+            Network::Test => write!(f, "test"),
+            Network::Regtest => write!(f, "regtest"),
+        }
+    }
+}
+
+/// Consensus parameters of a network. Two nodes only agree on a chain if
+/// they use the same parameters.
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub network: Network,
+    /// Message embedded in the genesis coinbase
+    pub genesis_message: &'static str,
+    /// Coins minted by the coinbase of the first blocks
+    pub initial_subsidy: Amount,
+    /// Number of blocks after which the subsidy is halved
+    pub halving_interval: usize,
+    /// Total number of coins that will ever be minted
+    pub max_supply: Amount,
+    /// Easiest target allowed, in compact form. Also the genesis target.
+    pub pow_limit_bits: u32,
+    /// Number of blocks between two difficulty adjustments
+    pub retarget_interval: usize,
+    /// Desired time between two blocks, in milliseconds
+    pub target_spacing_ms: u128,
+    /// Keep the genesis target forever
+    pub no_retargeting: bool,
+    /// First byte of the addresses of this network
+    pub address_version: u8,
+    /// First byte of the pay-to-script-hash addresses of this network
+    pub script_address_version: u8,
+    /// BIP44 coin type of the account HD wallets derive their keys under
+    pub bip44_coin_type: u32,
+    /// Largest serialized block accepted, in bytes
+    pub max_block_size: usize,
+    /// Confirmations a coinbase output needs before it can be spent
+    pub coinbase_maturity: usize,
+}
+
+impl ChainParams {
+    pub fn main() -> ChainParams {
+        ChainParams {
+            network: Network::Main,
+            genesis_message: "The Times 03/Jan/2009 Chancellor on brink of second bailout for banks",
+            initial_subsidy: Amount::from_coins(100),
+            halving_interval: 210_000,
+            max_supply: Amount::from_coins(42_000_000),
+            pow_limit_bits: 0x1f00ffff,
+            retarget_interval: 20,
+            target_spacing_ms: 10_000,
+            no_retargeting: false,
+            address_version: 0x00,
+            script_address_version: 0x05,
+            bip44_coin_type: 0,
+            max_block_size: 1_000_000,
+            coinbase_maturity: 100,
+        }
+    }
+
+    pub fn test() -> ChainParams {
+        ChainParams {
+            network: Network::Test,
+            genesis_message: "rustychain testnet genesis",
+            initial_subsidy: Amount::from_coins(100),
+            halving_interval: 210_000,
+            max_supply: Amount::from_coins(42_000_000),
+            pow_limit_bits: 0x1f00ffff,
+            retarget_interval: 20,
+            target_spacing_ms: 10_000,
+            no_retargeting: false,
+            address_version: 0x6f,
+            script_address_version: 0xc4,
+            bip44_coin_type: 1,
+            max_block_size: 1_000_000,
+            coinbase_maturity: 100,
+        }
+    }
+
+    /// Local testing network where blocks are mined instantly
+    pub fn regtest() -> ChainParams {
+        ChainParams {
+            network: Network::Regtest,
+            genesis_message: "rustychain regtest genesis",
+            initial_subsidy: Amount::from_coins(100),
+            halving_interval: 150,
+            max_supply: Amount::from_coins(30_000),
+            pow_limit_bits: 0x207fffff,
+            retarget_interval: 20,
+            target_spacing_ms: 10_000,
+            no_retargeting: true,
+            address_version: 0x7a,
+            script_address_version: 0x7c,
+            bip44_coin_type: 1,
+            max_block_size: 1_000_000,
+            coinbase_maturity: 100,
+        }
+    }
+
+    pub fn for_network(network: Network) -> ChainParams {
+        match network {
+            Network::Main => ChainParams::main(),
+            Network::Test => ChainParams::test(),
+            Network::Regtest => ChainParams::regtest(),
+        }
+    }
+
+    /// Whether an output created at `height` may be spent in a block at
+    /// `spend_height`. Coinbase outputs need `coinbase_maturity`
+    /// confirmations first.
+    pub fn is_mature(&self, is_coinbase: bool, height: usize, spend_height: usize) -> bool {
+        !is_coinbase || spend_height.saturating_sub(height) >= self.coinbase_maturity
+    }
+
+    /// Coins the coinbase of the block at `height` may mint: the initial
+    /// subsidy halved every `halving_interval` blocks, and never more than
+    /// what is left below `max_supply`
+    pub fn subsidy(&self, height: usize) -> Amount {
+        let scheduled = self.scheduled_subsidy(height);
+        match self.max_supply.checked_sub(self.issued_before(height)) {
+            Some(left) => scheduled.min(left),
+            None => Amount::ZERO,
+        }
+    }
+
+    /// Coins minted by the blocks below `height` if every coinbase claims
+    /// its full subsidy
+    pub fn issued_before(&self, height: usize) -> Amount {
+        let mut issued: u128 = 0;
+        let mut start = 0;
+        while start < height {
+            let subsidy = self.scheduled_subsidy(start);
+            if subsidy == Amount::ZERO {
+                break;
+            }
+            let end = (start + self.halving_interval).min(height);
+            issued += subsidy.base_units() as u128 * (end - start) as u128;
+            start = end;
+        }
+        Amount::from_base_units(issued.min(u64::MAX as u128) as u64)
+    }
+
+    fn scheduled_subsidy(&self, height: usize) -> Amount {
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return Amount::ZERO;
+        }
+        Amount::from_base_units(self.initial_subsidy.base_units() >> halvings)
+    }
+
+    /// Time a whole retarget window should take, in milliseconds
+    pub fn target_timespan_ms(&self) -> u128 {
+        self.target_spacing_ms * self.retarget_interval as u128
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subsidy() {
+        let params = ChainParams::regtest();
+        let interval = params.halving_interval;
+
+        assert_eq!(params.subsidy(0), Amount::from_coins(100));
+        assert_eq!(params.subsidy(interval - 1), Amount::from_coins(100));
+        assert_eq!(params.subsidy(interval), Amount::from_coins(50));
+        assert_eq!(params.subsidy(3 * interval), "12.5".parse::<Amount>().unwrap());
+        assert_eq!(params.subsidy(64 * interval), Amount::ZERO);
+
+        assert_eq!(params.issued_before(interval + 1), Amount::from_coins(15_050));
+        let total = params.issued_before(64 * interval);
+        assert!(total <= params.max_supply);
+        assert_eq!(params.issued_before(usize::MAX / 2), total);
+
+        // A cap below the schedule cuts the subsidy short
+        let capped = ChainParams { max_supply: Amount::from_coins(250), ..ChainParams::regtest() };
+        assert_eq!(capped.subsidy(1), Amount::from_coins(100));
+        assert_eq!(capped.subsidy(2), Amount::from_coins(50));
+        assert_eq!(capped.subsidy(3), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_maturity() {
+        let params = ChainParams::regtest();
+        let maturity = params.coinbase_maturity;
+
+        assert!(params.is_mature(false, 10, 10));
+        assert!(!params.is_mature(true, 10, 10 + maturity - 1));
+        assert!(params.is_mature(true, 10, 10 + maturity));
+        assert!(!params.is_mature(true, 10, 0));
+    }
+}
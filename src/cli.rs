@@ -1,9 +1,24 @@
+use std::collections::HashSet;
+use std::io;
+
 use clap::{arg, Command};
+use failure::format_err;
 
+use crate::amount::Amount;
+use crate::block::Block;
 use crate::blockchain::Blockchain;
+use crate::chainparams::Network;
+use crate::coinselect::{coin_selector, CoinSelector, DEFAULT_COIN_SELECTOR};
+use crate::config::Config;
 use crate::errors::Result;
-use crate::transaction::Transaction;
-use crate::wallet::Wallets;
+use crate::history::list_transactions;
+use crate::hd::generate_mnemonic;
+use crate::mempool::Mempool;
+use crate::psbt::Psbt;
+use crate::transaction::{Transaction, DEFAULT_FEE_RATE};
+use crate::utxoset::UTXOSet;
+use crate::script::Script;
+use crate::wallet::{decode_address, decode_destination, Wallets, GAP_LIMIT};
 
 pub struct Cli {}
 
@@ -17,34 +32,110 @@ impl Cli {
             .version("0.1")
             .author("jms.martinho@campus.fct.unl.pt")
             .about("a simple blockchain implementation in Rust")
-            .subcommand(Command::new("printchain").about("Prints the blockchain"))
-            .subcommand(Command::new("getbalance").about("Get the balance of an address")
-                .arg(arg!(<ADDRESS>).required(true).index(1)))
+            .arg(arg!(--datadir <DIR> "Directory holding the chain and wallet databases [env: RUSTYCHAIN_DATADIR]")
+                .global(true))
+            .arg(arg!(--network <NETWORK> "Network to use: main, test or regtest")
+                .default_value("main")
+                .global(true))
+            .subcommand(Command::new("printchain").about("Prints the blockchain")
+                .arg(arg!(--headers "Only print the block headers")))
+            .subcommand(Command::new("getbalance").about("Get the balance of an address, or of all the wallets")
+                .arg(arg!([ADDRESS]).index(1)))
             .subcommand(Command::new("create").about("Create a new blockchain")
                 .arg(arg!(<ADDRESS>).required(true).index(1)))
-            .subcommand(Command::new("send").about("Send an amount to an address")
+            .subcommand(Command::new("send").about("Send an amount to an address through the mempool")
+                .arg(arg!(<FROM>).required(true).index(1))
+                .arg(arg!(<TO>).required(true).index(2))
+                .arg(arg!(<AMOUNT>).required(true).index(3))
+                .arg(arg!(--"fee-rate" <RATE> "Fee in base units per byte [default: 10]")
+                    .value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--locktime <LOCKTIME> "Height, or Unix time in seconds, before which it cannot be mined")
+                    .value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--"coin-select" <STRATEGY> "Coin selection: largest-first, bnb or random-improve")
+                    .default_value(DEFAULT_COIN_SELECTOR)))
+            .subcommand(Command::new("mine").about("Mine a block with the mempool transactions")
+                .arg(arg!(<ADDRESS> "Address receiving the block reward").required(true).index(1)))
+            .subcommand(Command::new("createwallet")
+                .about("Create a new wallet, choosing the wallet passphrase the first time")
+                .arg(arg!(--mnemonic "Derive this and later wallets from a new mnemonic to back up")))
+            .subcommand(Command::new("restorewallet")
+                .about("Restore the wallets derived from a mnemonic and find the used ones in the chain")
+                .arg(arg!(<WORDS>).required(true).index(1).num_args(12..=24)))
+            .subcommand(Command::new("walletpassphrase")
+                .about("Unlock the wallet for the commands of the next TIMEOUT seconds, reading the passphrase \
+                    from the standard input")
+                .arg(arg!(<TIMEOUT>).required(true).index(1)
+                    .value_parser(clap::value_parser!(u64))))
+            .subcommand(Command::new("walletlock").about("Lock the wallet again before the timeout"))
+            .subcommand(Command::new("createmultisig")
+                .about("Create an address spendable with NREQUIRED signatures of the given keys")
+                .arg(arg!(<NREQUIRED>).required(true).index(1)
+                    .value_parser(clap::value_parser!(usize)))
+                .arg(arg!(<KEYS> "Wallet addresses or hex public keys").required(true).index(2).num_args(1..)))
+            .subcommand(Command::new("signrawtransaction")
+                .about("Add the signatures of the local wallets to a hex encoded transaction")
+                .arg(arg!(<HEX>).required(true).index(1)))
+            .subcommand(Command::new("sendrawtransaction")
+                .about("Add a fully signed hex encoded transaction to the mempool")
+                .arg(arg!(<HEX>).required(true).index(1)))
+            .subcommand(Command::new("createpsbt")
+                .about("Create a partially signed transaction for offline or cooperative signing")
                 .arg(arg!(<FROM>).required(true).index(1))
                 .arg(arg!(<TO>).required(true).index(2))
-                .arg(arg!(<AMOUNT>).required(true).index(3)))
-            .subcommand(Command::new("createwallet").about("Create a new wallet"))
-            .subcommand(Command::new("listaddresses").about("List all addresses"))
+                .arg(arg!(<AMOUNT>).required(true).index(3))
+                .arg(arg!(--"fee-rate" <RATE> "Fee in base units per byte [default: 10]")
+                    .value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--locktime <LOCKTIME> "Height, or Unix time in seconds, before which it cannot be mined")
+                    .value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--"coin-select" <STRATEGY> "Coin selection: largest-first, bnb or random-improve")
+                    .default_value(DEFAULT_COIN_SELECTOR)))
+            .subcommand(Command::new("signpsbt")
+                .about("Add the signatures of the local wallets to a partially signed transaction")
+                .arg(arg!(<PSBT>).required(true).index(1)))
+            .subcommand(Command::new("combinepsbt")
+                .about("Merge the signatures of copies of a partially signed transaction")
+                .arg(arg!(<PSBTS>).required(true).index(1).num_args(2..)))
+            .subcommand(Command::new("finalizepsbt")
+                .about("Turn a partially signed transaction with enough signatures into a raw transaction")
+                .arg(arg!(<PSBT>).required(true).index(1)))
+            .subcommand(Command::new("listaddresses").about("List all addresses")
+                .arg(arg!(--receive "Only list the addresses handed out to receive payments")
+                    .conflicts_with("change"))
+                .arg(arg!(--change "Only list the internal addresses change was sent to")))
+            .subcommand(Command::new("listtransactions")
+                .about("List the transactions of the chain that moved the coins of an address or of all the wallets")
+                .arg(arg!([ADDRESS]).index(1).required_unless_present("all"))
+                .arg(arg!(--all "List the transactions of all the wallets").conflicts_with("ADDRESS")))
+            .subcommand(Command::new("setlabel")
+                .about("Label an address or a transaction, or clear its label with an empty one")
+                .arg(arg!(<KEY> "Address or txid").required(true).index(1))
+                .arg(arg!(<LABEL>).required(true).index(2)))
             .subcommand(Command::new("getwallet").about("Get a wallet")
                 .arg(arg!(<ADDRESS>).required(true).index(1)))
             .subcommand(Command::new("listwallets").about("List all wallets"))
-
+            .subcommand(Command::new("reindexutxo").about("Rebuild the UTXO set from the blockchain"))
+            .subcommand(Command::new("proof").about("Print a merkle proof that a transaction is in a block")
+                .arg(arg!(<TXID>).required(true).index(1)))
+            .subcommand(Command::new("supply").about("Print the coins issued so far and the subsidy schedule"))
+            .subcommand(Command::new("verifychain").about("Re-run the consensus checks over the stored chain")
+                .arg(arg!(--depth <N> "Only check the last N blocks")
+                    .value_parser(clap::value_parser!(usize))))
             .get_matches();
 
+        let network = matches.get_one::<String>("network").unwrap().parse::<Network>()?;
+        let config = Config::new(matches.get_one::<String>("datadir").map(String::as_str), network);
+
         if let Some(matches) = matches.subcommand_matches("create") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
                 let address = String::from(address);
-                Cli::cmd_create_blockchain(&address)?;
+                Cli::cmd_create_blockchain(&config, &address)?;
             }
         }
 
         if let Some(matches) = matches.subcommand_matches("getbalance") {
-            if let Some(address) = matches.get_one::<String>("ADDRESS") {
-                let address = String::from(address);
-                Cli::cmd_get_balance(&address)?;
+            match matches.get_one::<String>("ADDRESS") {
+                Some(address) => Cli::cmd_get_balance(&config, address)?,
+                None => Cli::cmd_get_wallet_balance(&config)?,
             }
         }
 
@@ -54,30 +145,125 @@ impl Cli {
                     if let Some(amount) = matches.get_one::<String>("AMOUNT") {
                         let from = String::from(from);
                         let to = String::from(to);
-                        let amount = amount.parse::<f32>()?;
-                        Cli::cmd_send(&from, &to, amount)?;
+                        let amount = amount.parse::<Amount>()?;
+                        let fee_rate = matches.get_one::<u64>("fee-rate").copied().unwrap_or(DEFAULT_FEE_RATE);
+                        let lock_time = matches.get_one::<u64>("locktime").copied().unwrap_or(0);
+                        let selector = coin_selector(matches.get_one::<String>("coin-select").unwrap())?;
+                        Cli::cmd_send(&config, &from, &to, amount, fee_rate, lock_time, selector.as_ref())?;
+                    }
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("mine") {
+            if let Some(address) = matches.get_one::<String>("ADDRESS") {
+                Cli::cmd_mine(&config, address)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createwallet") {
+            Cli::cmd_create_wallet(&config, matches.get_flag("mnemonic"))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("restorewallet") {
+            let words: Vec<String> = matches.get_many::<String>("WORDS").unwrap_or_default().cloned().collect();
+            Cli::cmd_restore_wallet(&config, &words.join(" "))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("walletpassphrase") {
+            if let Some(timeout) = matches.get_one::<u64>("TIMEOUT") {
+                let passphrase = Cli::read_passphrase("Wallet passphrase")?;
+                Cli::cmd_wallet_passphrase(&config, &passphrase, *timeout)?;
+            }
+        }
+
+        if matches.subcommand_matches("walletlock").is_some() {
+            Cli::cmd_wallet_lock(&config)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createmultisig") {
+            if let Some(required) = matches.get_one::<usize>("NREQUIRED") {
+                let keys: Vec<String> = matches.get_many::<String>("KEYS").unwrap_or_default().cloned().collect();
+                Cli::cmd_create_multisig(&config, *required, &keys)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("signrawtransaction") {
+            if let Some(hex) = matches.get_one::<String>("HEX") {
+                Cli::cmd_sign_raw_transaction(&config, hex)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("sendrawtransaction") {
+            if let Some(hex) = matches.get_one::<String>("HEX") {
+                Cli::cmd_send_raw_transaction(&config, hex)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("createpsbt") {
+            if let Some(from) = matches.get_one::<String>("FROM") {
+                if let Some(to) = matches.get_one::<String>("TO") {
+                    if let Some(amount) = matches.get_one::<String>("AMOUNT") {
+                        let amount = amount.parse::<Amount>()?;
+                        let fee_rate = matches.get_one::<u64>("fee-rate").copied().unwrap_or(DEFAULT_FEE_RATE);
+                        let lock_time = matches.get_one::<u64>("locktime").copied().unwrap_or(0);
+                        let selector = coin_selector(matches.get_one::<String>("coin-select").unwrap())?;
+                        Cli::cmd_create_psbt(&config, from, to, amount, fee_rate, lock_time, selector.as_ref())?;
                     }
                 }
             }
         }
 
-        if matches.subcommand_matches("createwallet").is_some() {
-            let mut wallets = Wallets::new();
-            let address = wallets.create_wallet();
-            println!("Wallet created with address: {}", address);
+        if let Some(matches) = matches.subcommand_matches("signpsbt") {
+            if let Some(psbt) = matches.get_one::<String>("PSBT") {
+                Cli::cmd_sign_psbt(&config, psbt)?;
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("combinepsbt") {
+            let psbts: Vec<String> = matches.get_many::<String>("PSBTS").unwrap_or_default().cloned().collect();
+            Cli::cmd_combine_psbt(&psbts)?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("finalizepsbt") {
+            if let Some(psbt) = matches.get_one::<String>("PSBT") {
+                Cli::cmd_finalize_psbt(psbt)?;
+            }
         }
 
-        if matches.subcommand_matches("listaddresses").is_some() {
-            let wallets = Wallets::new();
+        if let Some(matches) = matches.subcommand_matches("listaddresses") {
+            let wallets = Cli::open_wallets(&config)?;
+            let (receive, change) = (matches.get_flag("receive"), matches.get_flag("change"));
             for address in wallets.get_addresses() {
-                println!("{}", address);
+                let is_change = wallets.is_change(&address)?;
+                if (!receive || !is_change) && (!change || is_change) {
+                    match wallets.get_label(&address)? {
+                        Some(label) => println!("{} {}", address, label),
+                        None => println!("{}", address),
+                    }
+                }
+            }
+        }
+
+        if let Some(matches) = matches.subcommand_matches("listtransactions") {
+            Cli::cmd_list_transactions(&config, matches.get_one::<String>("ADDRESS").map(String::as_str))?;
+        }
+
+        if let Some(matches) = matches.subcommand_matches("setlabel") {
+            if let (Some(key), Some(label)) = (matches.get_one::<String>("KEY"), matches.get_one::<String>("LABEL")) {
+                Cli::open_wallets(&config)?.set_label(key, label)?;
+                if label.is_empty() {
+                    println!("Label of {} cleared", key);
+                } else {
+                    println!("Labelled {} as '{}'", key, label);
+                }
             }
         }
 
         if let Some(matches) = matches.subcommand_matches("getwallet") {
             if let Some(address) = matches.get_one::<String>("ADDRESS") {
                 let address = String::from(address);
-                let wallets = Wallets::new();
+                let wallets = Cli::open_wallets(&config)?;
                 if let Some(wallet) = wallets.get_wallet(&address) {
                     println!("{:#?}", wallet);
                 } else {
@@ -87,58 +273,483 @@ impl Cli {
         }
 
         if matches.subcommand_matches("listwallets").is_some() {
-            let wallets = Wallets::new();
+            let wallets = Cli::open_wallets(&config)?;
             for wallet in wallets.get_wallets().values() {
                 println!("{:#?}", wallet);
             }
         }
 
-        if matches.subcommand_matches("printchain").is_some() {
-            Cli::cmd_print_chain()?;
+        if let Some(matches) = matches.subcommand_matches("printchain") {
+            Cli::cmd_print_chain(&config, matches.get_flag("headers"))?;
+        }
+
+        if matches.subcommand_matches("reindexutxo").is_some() {
+            Cli::cmd_reindex_utxo(&config)?;
         }
 
+        if let Some(matches) = matches.subcommand_matches("proof") {
+            if let Some(txid) = matches.get_one::<String>("TXID") {
+                Cli::cmd_proof(&config, txid)?;
+            }
+        }
+
+        if matches.subcommand_matches("supply").is_some() {
+            Cli::cmd_supply(&config)?;
+        }
 
+        if let Some(matches) = matches.subcommand_matches("verifychain") {
+            Cli::cmd_verify_chain(&config, matches.get_one::<usize>("depth").copied())?;
+        }
 
         Ok(())
     }
 
-    fn cmd_print_chain() -> Result<()> {
-        let bc = Blockchain::new()?;
-        for b in bc.iter() {
-            println!("{:#?}", b);
+    fn open_blockchain(config: &Config) -> Result<Blockchain> {
+        Blockchain::new(config.open_blocks_db()?, config.params())
+    }
+
+    /// Opens the wallets, unlocked if `walletpassphrase` unlocked them and
+    /// its timeout has not passed
+    fn open_wallets(config: &Config) -> Result<Wallets> {
+        let mut wallets = Wallets::new(config.open_wallets_db()?, &config.params())?;
+        wallets.resume_session(&config.wallet_session_path())?;
+        Ok(wallets)
+    }
+
+    /// Asks for the passphrase of a locked wallet, unlocking it for this
+    /// command only
+    fn unlock_wallets(wallets: &mut Wallets) -> Result<()> {
+        if wallets.is_locked() {
+            let passphrase = Cli::read_passphrase("Wallet passphrase")?;
+            wallets.unlock(&passphrase)?;
         }
         Ok(())
     }
 
-    fn cmd_get_balance(address: &str) -> Result<()> {
-        let bc = Blockchain::new()?;
-        let utxos = bc.find_utxo(address);
-        let mut balance = 0.0;
-        for out in utxos {
-            balance += out.1.get_value();
+    /// Unlocks the wallet, or asks for a passphrase to protect it with if it
+    /// has none yet
+    fn unlock_or_encrypt_wallets(wallets: &mut Wallets) -> Result<()> {
+        if wallets.is_encrypted() {
+            return Cli::unlock_wallets(wallets);
+        }
+
+        let passphrase = Cli::read_passphrase("Choose a wallet passphrase")?;
+        if Cli::read_passphrase("Repeat the passphrase")? != passphrase {
+            return Err(format_err!("ERROR: The passphrases do not match"));
+        }
+        wallets.set_passphrase(&passphrase)
+    }
+
+    /// Reads a passphrase from the standard input, so it can also be piped in
+    fn read_passphrase(prompt: &str) -> Result<String> {
+        eprint!("{}: ", prompt);
+        let mut line = String::new();
+        io::stdin().read_line(&mut line)?;
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+
+    fn cmd_print_chain(config: &Config, headers_only: bool) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        if headers_only {
+            for h in bc.iter_headers() {
+                println!("{:#?}", h);
+            }
+        } else {
+            for b in bc.iter() {
+                println!("{:#?}", b);
+            }
         }
+        Ok(())
+    }
+
+    fn cmd_get_balance(config: &Config, address: &str) -> Result<()> {
+        let script_pub_key = decode_destination(address, &config.params())?;
+        let bc = Cli::open_blockchain(config)?;
+        let (balance, immature) = UTXOSet::new(&bc).get_balance(&script_pub_key)?;
         println!("Balance of {}: {}", address, balance);
+        println!("Immature: {}", immature);
+
+        let pending = Mempool::new(&bc)?.pending_balance(&bc, &script_pub_key)?;
+        if pending.incoming > Amount::ZERO || pending.outgoing > Amount::ZERO {
+            let expected = balance.checked_add(pending.incoming)
+                .and_then(|total| total.checked_sub(pending.outgoing))
+                .ok_or_else(|| format_err!("ERROR: Amount overflow"))?;
+            println!("Pending: +{} -{} (fees {})", pending.incoming, pending.outgoing, pending.fees);
+            println!("Balance after pending: {}", expected);
+        }
+        Ok(())
+    }
+
+    fn cmd_get_wallet_balance(config: &Config) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        let wallets = Cli::open_wallets(config)?;
+        let (balance, immature) = wallets.get_balance(&UTXOSet::new(&bc))?;
+        println!("Balance of all wallets: {}", balance);
+        println!("Immature: {}", immature);
         Ok(())
     }
 
-    fn cmd_create_blockchain(address: &str) -> Result<()> {
-        Blockchain::create_blockchain(address.to_owned())?;
+    /// Lists the transactions of `address`, or of all the wallets if it is
+    /// `None`, along with the labels of the transactions and counterparties
+    fn cmd_list_transactions(config: &Config, address: Option<&str>) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        let wallets = Cli::open_wallets(config)?;
+        let owned = match address {
+            Some(address) => HashSet::from([decode_destination(address, &config.params())?]),
+            None => wallets.get_owned_scripts()?,
+        };
+
+        let label = |key: &str| -> Result<String> {
+            Ok(wallets.get_label(key)?.map(|label| format!(" ({})", label)).unwrap_or_default())
+        };
+        for tx in list_transactions(&bc, &owned)? {
+            let sign = if tx.is_outgoing() { "-" } else { "+" };
+            let counterparties = if tx.is_coinbase {
+                "coinbase".to_string()
+            } else {
+                tx.counterparties.iter()
+                    .map(|address| Ok(format!("{}{}", address, label(address)?)))
+                    .collect::<Result<Vec<String>>>()?
+                    .join(", ")
+            };
+            println!("{}{}", tx.txid, label(&tx.txid)?);
+            println!("    height {}, {} confirmations, {} {}{} {}",
+                tx.height, tx.confirmations, tx.direction, sign, tx.get_net_amount(), counterparties);
+        }
+        Ok(())
+    }
+
+    fn cmd_create_blockchain(config: &Config, address: &str) -> Result<()> {
+        decode_address(address, config.params().address_version)?;
+        Blockchain::create_blockchain(config.open_blocks_db()?, config.params(), address.to_owned())?;
         println!("Blockchain created");
         Ok(())
     }
 
-    fn cmd_send(from: &str, to: &str, amount: f32) -> Result<()> {
-        let bc = Blockchain::new()?;
-        let tx = Transaction::new_utxo(from, to, amount, &bc)?;
-        let mut cli = Cli {};
-        cli.cmd_add_block(vec![tx])?;
-        println!("Transaction sent");
+    fn cmd_create_wallet(config: &Config, with_mnemonic: bool) -> Result<()> {
+        let mut wallets = Cli::open_wallets(config)?;
+        Cli::unlock_or_encrypt_wallets(&mut wallets)?;
+        if with_mnemonic {
+            if wallets.has_seed()? {
+                return Err(format_err!("ERROR: The wallet already has a mnemonic"));
+            }
+            let mnemonic = generate_mnemonic()?;
+            wallets.set_mnemonic(&mnemonic)?;
+            println!("Write down this mnemonic, it restores the wallets with restorewallet:");
+            println!("{}", mnemonic);
+        }
+
+        let address = wallets.create_wallet()?;
+        println!("Wallet created with address: {}", address);
+        Ok(())
+    }
+
+    fn cmd_restore_wallet(config: &Config, mnemonic: &str) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        let mut wallets = Cli::open_wallets(config)?;
+        Cli::unlock_or_encrypt_wallets(&mut wallets)?;
+        wallets.set_mnemonic(mnemonic)?;
+
+        let mut used = HashSet::new();
+        for block in bc.iter() {
+            for tx in block.get_transactions() {
+                for out in tx.get_outs() {
+                    if let Some(pub_key_hash) = out.get_script_pub_key().get_pub_key_hash() {
+                        used.insert(pub_key_hash.to_vec());
+                    }
+                }
+            }
+        }
+
+        let found = wallets.rescan(GAP_LIMIT, |pub_key_hash| used.contains(pub_key_hash))?;
+        for address in &found {
+            println!("{}", address);
+        }
+        println!("Restored {} used addresses", found.len());
+        Ok(())
+    }
+
+    fn cmd_wallet_passphrase(config: &Config, passphrase: &str, timeout: u64) -> Result<()> {
+        let mut wallets = Cli::open_wallets(config)?;
+        wallets.unlock_for(passphrase, timeout, &config.wallet_session_path())?;
+        println!("Wallet unlocked for {} seconds", timeout);
+        Ok(())
+    }
+
+    fn cmd_wallet_lock(config: &Config) -> Result<()> {
+        Wallets::end_session(&config.wallet_session_path())?;
+        println!("Wallet locked");
+        Ok(())
+    }
+
+    fn cmd_send(
+        config: &Config,
+        from: &str,
+        to: &str,
+        amount: Amount,
+        fee_rate: u64,
+        lock_time: u64,
+        selector: &dyn CoinSelector
+    ) -> Result<()> {
+        decode_destination(from, &config.params())?;
+        decode_destination(to, &config.params())?;
+        let bc = Cli::open_blockchain(config)?;
+        let mut wallets = Cli::open_wallets(config)?;
+        Cli::unlock_wallets(&mut wallets)?;
+        let tx = Transaction::new_utxo(&mut wallets, from, to, amount, fee_rate, lock_time, selector,
+            &UTXOSet::new(&bc))?;
+        if tx.missing_signatures() > 0 {
+            println!("Transaction {} needs {} more signatures:", tx.get_id(), tx.missing_signatures());
+            println!("{}", tx.to_hex()?);
+            return Ok(());
+        }
+
+        Cli::add_to_mempool(&bc, tx)
+    }
+
+    fn add_to_mempool(bc: &Blockchain, tx: Transaction) -> Result<()> {
+        let txid = tx.get_id();
+        let mempool = Mempool::new(bc)?;
+        mempool.add(bc, tx.clone())?;
+        let fee = mempool.get(&txid)?.map(|entry| entry.get_fee()).unwrap_or(Amount::ZERO);
+        println!("Transaction {} added to the mempool with a fee of {}", txid, fee);
+        if !mempool.is_final(bc, &tx)? {
+            println!("It is held back until its lock times pass");
+        }
+        Ok(())
+    }
+
+    fn cmd_create_multisig(config: &Config, required: usize, keys: &[String]) -> Result<()> {
+        let wallets = Cli::open_wallets(config)?;
+        let keys = keys.iter()
+            .map(|key| match wallets.get_wallet(key) {
+                Some(wallet) => Ok(wallet.get_pub_key()),
+                None => hex::decode(key).map_err(|_| format_err!("ERROR: '{}' is neither a wallet nor a public key", key)),
+            })
+            .collect::<Result<Vec<Vec<u8>>>>()?;
+
+        let redeem_script = Script::multisig(required, &keys)?;
+        let address = wallets.add_multisig(&redeem_script, config.params().script_address_version)?;
+        println!("address:       {}", address);
+        println!("redeem script: {}", hex::encode(redeem_script.as_bytes()));
+        Ok(())
+    }
+
+    fn cmd_sign_raw_transaction(config: &Config, hex: &str) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        let mut wallets = Cli::open_wallets(config)?;
+        Cli::unlock_wallets(&mut wallets)?;
+        let mut tx = Transaction::from_hex(hex)?;
+
+        let mut involved = false;
+        let mut signed = 0;
+        let mut last_error = None;
+        for wallet in wallets.get_wallets().values().filter(|wallet| !wallet.private_key.is_empty()) {
+            match bc.sign_transaction(&mut tx, &wallet.private_key) {
+                Ok(count) => {
+                    involved = true;
+                    signed += count;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if !involved {
+            return Err(last_error.unwrap_or_else(|| format_err!("ERROR: The wallet holds no private key")));
+        }
+
+        println!("Added {} signatures, {} missing", signed, tx.missing_signatures());
+        println!("{}", tx.to_hex()?);
         Ok(())
     }
 
-    fn cmd_add_block(&mut self, txs: Vec<Transaction>) -> Result<()> {
-        let mut bc = Blockchain::new()?;
-        bc.add_block(txs)?;
+    fn cmd_send_raw_transaction(config: &Config, hex: &str) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        let tx = Transaction::from_hex(hex)?;
+        Cli::add_to_mempool(&bc, tx)
+    }
+
+    fn cmd_create_psbt(
+        config: &Config,
+        from: &str,
+        to: &str,
+        amount: Amount,
+        fee_rate: u64,
+        lock_time: u64,
+        selector: &dyn CoinSelector
+    ) -> Result<()> {
+        decode_destination(from, &config.params())?;
+        decode_destination(to, &config.params())?;
+        let bc = Cli::open_blockchain(config)?;
+        let mut wallets = Cli::open_wallets(config)?;
+        // Only the change of a key address may need a new key, and so the
+        // passphrase
+        if wallets.get_multisig(from)?.is_none() {
+            Cli::unlock_wallets(&mut wallets)?;
+        }
+        let utxo_set = UTXOSet::new(&bc);
+
+        let tx = Transaction::new_unsigned_utxo(&mut wallets, from, to, amount, fee_rate, lock_time, selector,
+            &utxo_set)?;
+        let psbt = Psbt::new(tx, &utxo_set)?;
+        println!("Transaction {} needs {} signatures:", psbt.get_transaction().get_id(), psbt.missing_signatures()?);
+        println!("{}", psbt.to_hex()?);
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Signs without opening the chain, so it works on an offline machine
+    fn cmd_sign_psbt(config: &Config, data: &str) -> Result<()> {
+        let mut wallets = Cli::open_wallets(config)?;
+        Cli::unlock_wallets(&mut wallets)?;
+        let mut psbt = Psbt::from_hex(data)?;
+
+        let mut involved = false;
+        let mut signed = 0;
+        let mut last_error = None;
+        for wallet in wallets.get_wallets().values().filter(|wallet| !wallet.private_key.is_empty()) {
+            match psbt.sign(&wallet.private_key) {
+                Ok(count) => {
+                    involved = true;
+                    signed += count;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if !involved {
+            return Err(last_error.unwrap_or_else(|| format_err!("ERROR: The wallet holds no private key")));
+        }
+
+        println!("Added {} signatures, {} missing", signed, psbt.missing_signatures()?);
+        println!("{}", psbt.to_hex()?);
+        Ok(())
+    }
+
+    fn cmd_combine_psbt(psbts: &[String]) -> Result<()> {
+        let mut combined = Psbt::from_hex(&psbts[0])?;
+        for data in &psbts[1..] {
+            combined.combine(&Psbt::from_hex(data)?)?;
+        }
+
+        println!("Combined {} copies, {} signatures missing", psbts.len(), combined.missing_signatures()?);
+        println!("{}", combined.to_hex()?);
+        Ok(())
+    }
+
+    fn cmd_finalize_psbt(data: &str) -> Result<()> {
+        let tx = Psbt::from_hex(data)?.finalize()?;
+        println!("Transaction {} is ready for sendrawtransaction:", tx.get_id());
+        println!("{}", tx.to_hex()?);
+        Ok(())
+    }
+
+    fn cmd_mine(config: &Config, address: &str) -> Result<()> {
+        decode_address(address, config.params().address_version)?;
+        let mut bc = Cli::open_blockchain(config)?;
+        let mempool = Mempool::new(&bc)?;
+        mempool.prune(&bc)?;
+
+        let height = bc.get_best_height()? + 1;
+        let params = config.params();
+
+        // Leave room for the header and the coinbase, whose size does not
+        // depend on the amount it pays
+        let placeholder = Transaction::new_coinbase(address.to_owned(), height, &[], Amount::ZERO, &params)?;
+        let reserved = Block::empty_size()? + bincode::serialized_size(&placeholder)? as usize;
+        let template = mempool.block_template(&bc, params.max_block_size.saturating_sub(reserved))?;
+
+        let fees = Amount::checked_sum(template.iter().map(|entry| entry.get_fee()))?;
+        let reward = params.subsidy(height).checked_add(fees)
+            .ok_or_else(|| format_err!("ERROR: Amount overflow"))?;
+        let mut txs = vec![Transaction::new_coinbase(address.to_owned(), height, &[], reward, &params)?];
+        txs.extend(template.into_iter().map(|entry| entry.get_transaction().clone()));
+
+        let block = bc.add_block(txs)?;
+        mempool.prune(&bc)?;
+        println!("Mined block {} at height {} with {} transactions",
+            block.get_hash(), block.get_height(), block.get_transactions().len());
+        Ok(())
+    }
+
+    fn cmd_reindex_utxo(config: &Config) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        let utxo_set = UTXOSet::new(&bc);
+        utxo_set.reindex()?;
+        println!("Done! There are {} transactions in the UTXO set.", utxo_set.count_transactions()?);
+        Ok(())
+    }
+
+    fn cmd_proof(config: &Config, txid: &str) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        let block = bc.find_transaction_block(txid)?;
+        let proof = block.merkle_proof(txid)?
+            .ok_or_else(|| format_err!("Transaction not found"))?;
+
+        println!("block:  {}", block.get_hash());
+        println!("height: {}", block.get_height());
+        println!("root:   {}", block.get_merkle_root());
+        println!("{}", proof);
+        println!("valid:  {}", proof.verify(&block.get_merkle_root())?);
+        Ok(())
+    }
+
+    fn cmd_verify_chain(config: &Config, depth: Option<usize>) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        let checked = bc.verify_chain(depth)?;
+        println!("Verified {} blocks up to height {}", checked, bc.get_best_height()?);
+        Ok(())
+    }
+
+    fn cmd_supply(config: &Config) -> Result<()> {
+        let bc = Cli::open_blockchain(config)?;
+        let params = bc.params();
+        let height = bc.get_best_height()?;
+        let next_halving = (height / params.halving_interval + 1) * params.halving_interval;
+
+        println!("height:       {}", height);
+        println!("issued:       {}", bc.issued_supply()?);
+        println!("scheduled:    {}", params.issued_before(height + 1));
+        println!("max supply:   {}", params.max_supply);
+        println!("subsidy:      {}", params.subsidy(height + 1));
+        println!("next halving: {}", next_halving);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coinselect::LargestFirst;
+    use crate::testutil::{new_address, temporary_dir};
+
+    #[test]
+    fn test_wallet_passphrase_session() {
+        let dir = temporary_dir();
+        let config = Config::new(dir.path().to_str(), Network::Regtest);
+        let params = config.params();
+        let mut wallets = Cli::open_wallets(&config).unwrap();
+        wallets.set_passphrase("secret").unwrap();
+        let from = wallets.create_wallet().unwrap();
+        drop(wallets);
+
+        // Bury the genesis coinbase paying `from` until it matures
+        let mut bc = Blockchain::create_blockchain(config.open_blocks_db().unwrap(), params.clone(), from.clone())
+            .unwrap();
+        for height in 1..=params.coinbase_maturity {
+            let coinbase = Transaction::new_coinbase(new_address(), height, &[], params.subsidy(height), &params)
+                .unwrap();
+            bc.add_block(vec![coinbase]).unwrap();
+        }
+        drop(bc);
+
+        // A later command spends without asking for the passphrase
+        Cli::cmd_wallet_passphrase(&config, "secret", 60).unwrap();
+        assert!(!Cli::open_wallets(&config).unwrap().is_locked());
+        Cli::cmd_send(&config, &from, &new_address(), Amount::from_coins(10), DEFAULT_FEE_RATE, 0, &LargestFirst)
+            .unwrap();
+        assert_eq!(Mempool::new(&Cli::open_blockchain(&config).unwrap()).unwrap().len(), 1);
+
+        Cli::cmd_wallet_lock(&config).unwrap();
+        assert!(Cli::open_wallets(&config).unwrap().is_locked());
+        assert!(Cli::cmd_wallet_passphrase(&config, "wrong", 60).is_err());
+        assert!(Cli::open_wallets(&config).unwrap().is_locked());
+    }
+}
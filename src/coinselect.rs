@@ -0,0 +1,341 @@
+use failure::format_err;
+use rand::seq::SliceRandom;
+
+use crate::amount::Amount;
+use crate::errors::Result;
+
+/// Smallest output a spend creates. Change below this, or below the fee it
+/// would take to spend it later, is left to the fee instead.
+pub const DUST_THRESHOLD: Amount = Amount::from_base_units(546);
+
+/// Branch and bound gives up after visiting this many selections
+pub const BNB_MAX_TRIES: usize = 100_000;
+
+/// Strategy `send` uses unless one is given
+pub const DEFAULT_COIN_SELECTOR: &str = "bnb";
+
+/// An unspent output that can fund a spend
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    pub txid: String,
+    pub vout: u32,
+    pub value: Amount,
+}
+
+/// What a spend has to pay for, with the sizes its fee depends on
+#[derive(Debug, Clone)]
+pub struct SelectionParams {
+    /// Amount paid to the recipient
+    pub target: Amount,
+    /// Fee in base units per byte
+    pub fee_rate: u64,
+    /// Size of the transaction without inputs or change
+    pub base_size: usize,
+    /// Size each signed input adds
+    pub input_size: usize,
+    /// Size the change output adds
+    pub change_size: usize,
+}
+
+impl SelectionParams {
+    /// Fee of a transaction with `inputs` inputs, and a change output if
+    /// `change` is set
+    pub fn fee(&self, inputs: usize, change: bool) -> u64 {
+        let size = self.base_size + inputs * self.input_size + if change { self.change_size } else { 0 };
+        self.fee_rate.saturating_mul(size as u64)
+    }
+
+    /// Value `utxo` adds once the fee of its input is paid
+    pub fn effective_value(&self, utxo: &Utxo) -> u64 {
+        utxo.value.base_units().saturating_sub(self.fee_rate.saturating_mul(self.input_size as u64))
+    }
+
+    /// Smallest change worth an output: `DUST_THRESHOLD`, or the fee of
+    /// spending it later if that is more
+    pub fn dust_threshold(&self) -> u64 {
+        DUST_THRESHOLD.base_units().max(self.fee_rate.saturating_mul(self.input_size as u64))
+    }
+}
+
+/// The coins a spend uses, the change it sends back and the fee it pays
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selection {
+    pub coins: Vec<Utxo>,
+    pub change: Amount,
+    pub fee: Amount,
+}
+
+impl Selection {
+    /// Selection of `coins` if they cover the target and the fee. The change
+    /// is left to the fee when it would be dust.
+    pub fn new(coins: &[Utxo], params: &SelectionParams) -> Option<Selection> {
+        let total: u64 = coins.iter().map(|utxo| utxo.value.base_units()).sum();
+        let target = params.target.base_units();
+        if total < target.checked_add(params.fee(coins.len(), false))? {
+            return None;
+        }
+
+        let change = total.saturating_sub(target.saturating_add(params.fee(coins.len(), true)));
+        let (change, fee) = if change >= params.dust_threshold() {
+            (change, params.fee(coins.len(), true))
+        } else {
+            (0, total - target)
+        };
+        Some(Selection {
+            coins: coins.to_vec(),
+            change: Amount::from_base_units(change),
+            fee: Amount::from_base_units(fee),
+        })
+    }
+}
+
+/// A strategy picking the coins that fund a spend
+pub trait CoinSelector {
+    /// Picks coins among `utxos` covering `params.target` and the fee
+    fn select(&self, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection>;
+}
+
+/// Spends the largest coins first, which keeps transactions small
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection> {
+        let mut sorted = worth_spending(utxos, params);
+        sorted.sort_by(|a, b| b.value.cmp(&a.value).then_with(|| (&a.txid, a.vout).cmp(&(&b.txid, b.vout))));
+
+        let mut chosen = Vec::new();
+        for utxo in sorted {
+            chosen.push(utxo.clone());
+            if let Some(selection) = Selection::new(&chosen, params) {
+                return Ok(selection);
+            }
+        }
+        Err(not_enough_funds())
+    }
+}
+
+/// Searches for coins matching the target closely enough that the spend
+/// needs no change, as Bitcoin Core does, and falls back to `LargestFirst`
+/// when there are none
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BranchAndBound;
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection> {
+        let mut sorted: Vec<(u64, &Utxo)> = worth_spending(utxos, params).into_iter()
+            .map(|utxo| (params.effective_value(utxo), utxo))
+            .collect();
+        sorted.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| (&a.1.txid, a.1.vout).cmp(&(&b.1.txid, b.1.vout))));
+
+        // Any excess below the cost of a change output and of spending it
+        // later goes to the fee
+        let lower = params.target.base_units().saturating_add(params.fee(0, false));
+        let window = params.fee_rate.saturating_mul(params.change_size as u64)
+            .saturating_add(params.dust_threshold()) - 1;
+        let mut search = Search {
+            coins: &sorted,
+            lower,
+            upper: lower.saturating_add(window),
+            tries: BNB_MAX_TRIES,
+            selected: Vec::new(),
+            best: None,
+        };
+        let remaining = sorted.iter().map(|(value, _)| value).sum();
+        search.run(0, 0, remaining);
+
+        match search.best {
+            Some((_, indexes)) => {
+                let coins: Vec<Utxo> = indexes.iter().map(|index| sorted[*index].1.clone()).collect();
+                Selection::new(&coins, params).ok_or_else(not_enough_funds)
+            }
+            None => LargestFirst.select(utxos, params),
+        }
+    }
+}
+
+/// Depth-first search of `BranchAndBound` over the coins sorted by
+/// decreasing effective value
+struct Search<'a> {
+    coins: &'a [(u64, &'a Utxo)],
+    lower: u64,
+    upper: u64,
+    tries: usize,
+    selected: Vec<usize>,
+    /// Smallest excess found and the coins giving it
+    best: Option<(u64, Vec<usize>)>,
+}
+
+impl Search<'_> {
+    fn run(&mut self, index: usize, current: u64, remaining: u64) {
+        if self.tries == 0 || current > self.upper {
+            return;
+        }
+        self.tries -= 1;
+
+        if current >= self.lower {
+            let excess = current - self.lower;
+            if self.best.as_ref().is_none_or(|(best, _)| excess < *best) {
+                self.best = Some((excess, self.selected.clone()));
+            }
+            return;
+        }
+        if index == self.coins.len() || current + remaining < self.lower {
+            return;
+        }
+
+        let value = self.coins[index].0;
+        self.selected.push(index);
+        self.run(index + 1, current + value, remaining - value);
+        self.selected.pop();
+        self.run(index + 1, current, remaining - value);
+    }
+}
+
+/// Picks random coins until the target is covered, then adds more while they
+/// bring the total closer to twice the target, up to three times it. The
+/// change then tends to be about as large as the payment, which keeps the
+/// coins of the wallet useful for later spends.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomImprove;
+
+impl CoinSelector for RandomImprove {
+    fn select(&self, utxos: &[Utxo], params: &SelectionParams) -> Result<Selection> {
+        let mut pool = worth_spending(utxos, params);
+        pool.shuffle(&mut rand::thread_rng());
+
+        let mut chosen = Vec::new();
+        let mut rest = pool.into_iter();
+        for utxo in rest.by_ref() {
+            chosen.push(utxo.clone());
+            if Selection::new(&chosen, params).is_some() {
+                break;
+            }
+        }
+        if Selection::new(&chosen, params).is_none() {
+            return Err(not_enough_funds());
+        }
+
+        let target = params.target.base_units();
+        let ideal = target.saturating_mul(2);
+        let mut total: u64 = chosen.iter().map(|utxo| utxo.value.base_units()).sum();
+        for utxo in rest {
+            let improved = total + utxo.value.base_units();
+            if improved <= target.saturating_mul(3) && improved.abs_diff(ideal) < total.abs_diff(ideal) {
+                chosen.push(utxo.clone());
+                total = improved;
+            }
+        }
+        Selection::new(&chosen, params).ok_or_else(not_enough_funds)
+    }
+}
+
+/// The strategy called `name`: `largest-first`, `bnb` or `random-improve`
+pub fn coin_selector(name: &str) -> Result<Box<dyn CoinSelector>> {
+    match name {
+        "largest-first" => Ok(Box::new(LargestFirst)),
+        "bnb" | "branch-and-bound" => Ok(Box::new(BranchAndBound)),
+        "random-improve" => Ok(Box::new(RandomImprove)),
+        _ => Err(format_err!(
+            "ERROR: Unknown coin selection '{}', expected largest-first, bnb or random-improve", name)),
+    }
+}
+
+/// Coins worth more than the fee of spending them
+fn worth_spending<'a>(utxos: &'a [Utxo], params: &SelectionParams) -> Vec<&'a Utxo> {
+    utxos.iter().filter(|utxo| params.effective_value(utxo) > 0).collect()
+}
+
+fn not_enough_funds() -> failure::Error {
+    format_err!("ERROR: Not enough funds")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utxos(values: &[u64]) -> Vec<Utxo> {
+        values.iter().enumerate()
+            .map(|(vout, value)| Utxo { txid: "tx".to_string(), vout: vout as u32, value: Amount::from_base_units(*value) })
+            .collect()
+    }
+
+    fn spend(target: u64, fee_rate: u64) -> SelectionParams {
+        SelectionParams {
+            target: Amount::from_base_units(target),
+            fee_rate,
+            base_size: 100,
+            input_size: 150,
+            change_size: 40,
+        }
+    }
+
+    fn values(selection: &Selection) -> Vec<u64> {
+        let mut values: Vec<u64> = selection.coins.iter().map(|utxo| utxo.value.base_units()).collect();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn test_selection() {
+        let params = spend(10_000, 1);
+        // 100 + 150 bytes without change, 290 with it
+        let selection = Selection::new(&utxos(&[20_000]), &params).unwrap();
+        assert_eq!((selection.change, selection.fee), (Amount::from_base_units(9_710), Amount::from_base_units(290)));
+
+        // Change below the dust threshold goes to the fee
+        let selection = Selection::new(&utxos(&[10_700]), &params).unwrap();
+        assert_eq!((selection.change, selection.fee), (Amount::ZERO, Amount::from_base_units(700)));
+        assert!(Selection::new(&utxos(&[10_249]), &params).is_none());
+        assert_eq!(params.dust_threshold(), 546);
+        assert_eq!(spend(0, 10).dust_threshold(), 1_500);
+    }
+
+    #[test]
+    fn test_largest_first() {
+        let coins = utxos(&[1_000, 50_000, 8_000, 30_000, 100]);
+        let selection = LargestFirst.select(&coins, &spend(60_000, 1)).unwrap();
+        assert_eq!(values(&selection), vec![30_000, 50_000]);
+        assert_eq!(selection.change.base_units(), 80_000 - 60_000 - 100 - 300 - 40);
+
+        // Coins worth less than their input are never spent
+        assert!(LargestFirst.select(&coins, &spend(89_000, 1)).is_err());
+        assert!(LargestFirst.select(&utxos(&[100]), &spend(1, 1)).is_err());
+        assert!(LargestFirst.select(&[], &spend(1, 0)).is_err());
+    }
+
+    #[test]
+    fn test_branch_and_bound() {
+        // 7_200 + 3_300 covers 10_000 and the 400 bytes of fee with less
+        // excess than a change output would cost, so no change is made
+        let coins = utxos(&[7_200, 5_000, 3_300, 2_000, 40_000]);
+        let selection = BranchAndBound.select(&coins, &spend(10_000, 1)).unwrap();
+        assert_eq!(values(&selection), vec![3_300, 7_200]);
+        assert_eq!(selection.change, Amount::ZERO);
+        assert_eq!(selection.fee, Amount::from_base_units(500));
+
+        // Without a close enough match it falls back to the largest coins
+        let selection = BranchAndBound.select(&coins, &spend(20_000, 1)).unwrap();
+        assert_eq!(values(&selection), vec![40_000]);
+        assert!(selection.change > Amount::ZERO);
+        assert!(BranchAndBound.select(&coins, &spend(100_000, 1)).is_err());
+    }
+
+    #[test]
+    fn test_random_improve() {
+        let coins = utxos(&[10_000; 10]);
+        for _ in 0..20 {
+            // The total moves towards twice the target, without passing three
+            // times it
+            let selection = RandomImprove.select(&coins, &spend(15_000, 1)).unwrap();
+            assert_eq!(values(&selection), vec![10_000; 3]);
+            assert!(selection.change > Amount::ZERO);
+        }
+        assert!(RandomImprove.select(&coins, &spend(100_000, 1)).is_err());
+
+        assert!(coin_selector("largest-first").is_ok());
+        assert!(coin_selector("bnb").is_ok());
+        assert!(coin_selector("random-improve").is_ok());
+        assert!(coin_selector("smallest-first").is_err());
+    }
+}
@@ -0,0 +1,98 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::chainparams::{ChainParams, Network};
+use crate::errors::Result;
+
+/// Environment variable overriding the default data directory
+pub const DATADIR_ENV: &str = "RUSTYCHAIN_DATADIR";
+
+pub const DEFAULT_DATADIR: &str = "data";
+
+/// Where a node keeps its databases and which network it runs. Every chain
+/// and wallet database lives under `datadir`, so nodes with different
+/// directories are fully isolated. Test networks get their own subdirectory.
+#[derive(Debug, Clone)]
+pub struct Config {
+    datadir: PathBuf,
+    network: Network,
+}
+
+impl Config {
+    /// Uses `datadir` if given, then `RUSTYCHAIN_DATADIR`, then `./data`
+    pub fn new(datadir: Option<&str>, network: Network) -> Config {
+        let datadir = match datadir {
+            Some(dir) => PathBuf::from(dir),
+            None => env::var_os(DATADIR_ENV)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_DATADIR)),
+        };
+
+        Config { datadir, network }
+    }
+
+    pub fn datadir(&self) -> &Path {
+        &self.datadir
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn params(&self) -> ChainParams {
+        ChainParams::for_network(self.network)
+    }
+
+    /// Directory holding the databases of the selected network
+    pub fn network_dir(&self) -> PathBuf {
+        match self.network {
+            Network::Main => self.datadir.clone(),
+            Network::Test => self.datadir.join("testnet"),
+            Network::Regtest => self.datadir.join("regtest"),
+        }
+    }
+
+    pub fn open_blocks_db(&self) -> Result<sled::Db> {
+        Ok(sled::open(self.network_dir().join("blocks"))?)
+    }
+
+    pub fn open_wallets_db(&self) -> Result<sled::Db> {
+        Ok(sled::open(self.network_dir().join("wallets"))?)
+    }
+
+    /// File keeping the wallet unlocked between commands after
+    /// `walletpassphrase`
+    pub fn wallet_session_path(&self) -> PathBuf {
+        self.network_dir().join("wallet.session")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_datadir_resolution() {
+        // The only test touching the variable, so parallel tests see no change
+        env::remove_var(DATADIR_ENV);
+        let config = Config::new(None, Network::Main);
+        assert_eq!(config.datadir(), Path::new(DEFAULT_DATADIR));
+        assert_eq!(config.network_dir(), PathBuf::from(DEFAULT_DATADIR));
+
+        // The variable overrides the default and the flag overrides both
+        env::set_var(DATADIR_ENV, "/tmp/from-env");
+        let from_env = Config::new(None, Network::Main);
+        let from_flag = Config::new(Some("/tmp/from-flag"), Network::Main);
+        env::remove_var(DATADIR_ENV);
+        assert_eq!(from_env.datadir(), Path::new("/tmp/from-env"));
+        assert_eq!(from_flag.datadir(), Path::new("/tmp/from-flag"));
+
+        // Test networks keep their databases in a subdirectory
+        let test = Config::new(Some("node"), Network::Test);
+        let regtest = Config::new(Some("node"), Network::Regtest);
+        assert_eq!(test.network_dir(), Path::new("node").join("testnet"));
+        assert_eq!(regtest.network_dir(), Path::new("node").join("regtest"));
+        assert_eq!(Config::new(Some("node"), Network::Main).network_dir(), Path::new("node"));
+        assert_eq!(regtest.params().address_version, ChainParams::regtest().address_version);
+    }
+}
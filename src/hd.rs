@@ -0,0 +1,225 @@
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::pbkdf2::pbkdf2;
+use crypto::sha2::Sha512;
+use failure::format_err;
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::errors::Result;
+
+/// The BIP39 English wordlist
+const WORDLIST: &str = include_str!("bip39_english.txt");
+
+/// Bits of entropy encoded by each mnemonic word
+const BITS_PER_WORD: usize = 11;
+
+/// Entropy of generated mnemonics, in bytes, which makes 12 words
+pub const MNEMONIC_ENTROPY_SIZE: usize = 16;
+
+/// Size of the seed a mnemonic stretches to
+pub const SEED_SIZE: usize = 64;
+
+/// Child indexes from this one up are hardened
+pub const HARDENED: u32 = 1 << 31;
+
+const PBKDF2_ROUNDS: u32 = 2048;
+
+fn words() -> Vec<&'static str> {
+    WORDLIST.lines().collect()
+}
+
+/// BIP39 mnemonic of `entropy`, 16 to 32 bytes in steps of 4, followed by a
+/// checksum taken from its hash
+pub fn entropy_to_mnemonic(entropy: &[u8]) -> Result<String> {
+    if entropy.len() < 16 || entropy.len() > 32 || !entropy.len().is_multiple_of(4) {
+        return Err(format_err!("ERROR: Mnemonic entropy must be 16 to 32 bytes in steps of 4"));
+    }
+
+    let checksum_bits = entropy.len() * 8 / 32;
+    let mut bits: Vec<bool> = entropy.iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+        .collect();
+    let hash = Sha256::digest(entropy);
+    bits.extend((0..checksum_bits).map(|i| hash[i / 8] >> (7 - i % 8) & 1 == 1));
+
+    let words = words();
+    let phrase: Vec<&str> = bits.chunks(BITS_PER_WORD)
+        .map(|chunk| words[chunk.iter().fold(0, |index, bit| index << 1 | *bit as usize)])
+        .collect();
+    Ok(phrase.join(" "))
+}
+
+/// A new mnemonic of 12 random words
+pub fn generate_mnemonic() -> Result<String> {
+    entropy_to_mnemonic(&rand::random::<[u8; MNEMONIC_ENTROPY_SIZE]>())
+}
+
+/// Entropy encoded by `mnemonic`, failing on unknown words, a wrong number
+/// of words or a wrong checksum
+pub fn mnemonic_to_entropy(mnemonic: &str) -> Result<Vec<u8>> {
+    let words = words();
+    let phrase: Vec<&str> = mnemonic.split_whitespace().collect();
+    if phrase.len() < 12 || phrase.len() > 24 || !phrase.len().is_multiple_of(3) {
+        return Err(format_err!("ERROR: A mnemonic has 12, 15, 18, 21 or 24 words"));
+    }
+
+    let mut bits = Vec::new();
+    for word in &phrase {
+        let index = words.binary_search(word)
+            .map_err(|_| format_err!("ERROR: '{}' is not a mnemonic word", word))?;
+        bits.extend((0..BITS_PER_WORD).rev().map(|i| index >> i & 1 == 1));
+    }
+
+    let checksum_bits = bits.len() / 33;
+    let entropy_bits = &bits[..bits.len() - checksum_bits];
+    let entropy: Vec<u8> = entropy_bits.chunks(8)
+        .map(|chunk| chunk.iter().fold(0, |byte, bit| byte << 1 | *bit as u8))
+        .collect();
+    if entropy_to_mnemonic(&entropy)? != phrase.join(" ") {
+        return Err(format_err!("ERROR: Invalid mnemonic checksum"));
+    }
+    Ok(entropy)
+}
+
+/// Seed of a valid `mnemonic` protected by the optional BIP39 `passphrase`.
+/// Both are NFKD normalized first, as BIP39 requires.
+pub fn mnemonic_to_seed(mnemonic: &str, passphrase: &str) -> Result<Vec<u8>> {
+    let mnemonic: String = mnemonic.nfkd().collect();
+    mnemonic_to_entropy(&mnemonic)?;
+    let phrase = mnemonic.split_whitespace().collect::<Vec<&str>>().join(" ");
+
+    let mut mac = Hmac::new(Sha512::new(), phrase.as_bytes());
+    let mut seed = vec![0; SEED_SIZE];
+    let salt: String = format!("mnemonic{}", passphrase).nfkd().collect();
+    pbkdf2(&mut mac, salt.as_bytes(), PBKDF2_ROUNDS, &mut seed);
+    Ok(seed)
+}
+
+/// A BIP32 extended private key: a private key and the chain code its
+/// children are derived with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedKey {
+    pub private_key: Vec<u8>,
+    pub chain_code: Vec<u8>,
+}
+
+impl ExtendedKey {
+    /// The master key of `seed`
+    pub fn from_seed(seed: &[u8]) -> Result<ExtendedKey> {
+        let (private_key, chain_code) = hmac_sha512(b"Bitcoin seed", seed);
+        secp256k1::SecretKey::from_slice(&private_key)
+            .map_err(|_| format_err!("ERROR: The seed does not make a valid master key"))?;
+        Ok(ExtendedKey { private_key, chain_code })
+    }
+
+    /// Child `index` of this key, hardened from `HARDENED` on
+    pub fn derive_child(&self, index: u32) -> Result<ExtendedKey> {
+        let secp = secp256k1::Secp256k1::signing_only();
+        let parent = secp256k1::SecretKey::from_slice(&self.private_key)
+            .map_err(|_| format_err!("ERROR: Invalid private key"))?;
+
+        let mut data = if index >= HARDENED {
+            let mut data = vec![0];
+            data.extend(&self.private_key);
+            data
+        } else {
+            secp256k1::PublicKey::from_secret_key(&secp, &parent).serialize().to_vec()
+        };
+        data.extend(index.to_be_bytes());
+
+        let (tweak, chain_code) = hmac_sha512(&self.chain_code, &data);
+        let tweak = secp256k1::Scalar::from_be_bytes(tweak.try_into().unwrap())
+            .map_err(|_| format_err!("ERROR: Child {} is not a valid key", index))?;
+        let private_key = parent.add_tweak(&tweak)
+            .map_err(|_| format_err!("ERROR: Child {} is not a valid key", index))?;
+        Ok(ExtendedKey { private_key: private_key.secret_bytes().to_vec(), chain_code })
+    }
+
+    /// Descendant of this key along `path`
+    pub fn derive_path(&self, path: &[u32]) -> Result<ExtendedKey> {
+        path.iter().try_fold(self.clone(), |key, index| key.derive_child(*index))
+    }
+}
+
+/// Parses a derivation path such as `m/44'/0'/0'/0/5`, where `'` or `h`
+/// marks hardened indexes
+pub fn parse_path(path: &str) -> Result<Vec<u32>> {
+    let invalid = || format_err!("ERROR: Invalid derivation path '{}'", path);
+    let mut parts = path.split('/');
+    if parts.next() != Some("m") {
+        return Err(invalid());
+    }
+
+    parts.map(|part| {
+        let (number, hardened) = match part.strip_suffix(['\'', 'h']) {
+            Some(number) => (number, HARDENED),
+            None => (part, 0),
+        };
+        let index: u32 = number.parse().map_err(|_| invalid())?;
+        if index >= HARDENED {
+            return Err(invalid());
+        }
+        Ok(index | hardened)
+    }).collect()
+}
+
+/// HMAC-SHA512 of `data` under `key`, split in its two halves
+fn hmac_sha512(key: &[u8], data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let mut mac = Hmac::new(Sha512::new(), key);
+    mac.input(data);
+    let code = mac.result().code().to_vec();
+    (code[..32].to_vec(), code[32..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mnemonic() {
+        assert_eq!(words().len(), 2048);
+        let mnemonic = entropy_to_mnemonic(&[0; 16]).unwrap();
+        assert_eq!(mnemonic, "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about");
+        assert_eq!(mnemonic_to_entropy(&mnemonic).unwrap(), vec![0; 16]);
+        assert_eq!(hex::encode(mnemonic_to_seed(&mnemonic, "TREZOR").unwrap()),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04");
+        assert_eq!(entropy_to_mnemonic(&[0xff; 32]).unwrap().split(' ').next_back(), Some("vote"));
+
+        // A wrong checksum, word count or word is rejected
+        assert!(mnemonic_to_entropy(&mnemonic.replace("about", "abandon")).is_err());
+        assert!(mnemonic_to_entropy("abandon abandon about").is_err());
+        assert!(mnemonic_to_entropy(&mnemonic.replace("about", "bitcoin")).is_err());
+
+        let random = generate_mnemonic().unwrap();
+        assert_eq!(random.split(' ').count(), 12);
+        assert!(mnemonic_to_seed(&random, "").is_ok());
+
+        // Composed and decomposed forms of a passphrase give the same seed
+        assert_eq!(mnemonic_to_seed(&mnemonic, "caf\u{e9}").unwrap(),
+            mnemonic_to_seed(&mnemonic, "cafe\u{301}").unwrap());
+        assert_ne!(mnemonic_to_seed(&mnemonic, "caf\u{e9}").unwrap(), mnemonic_to_seed(&mnemonic, "cafe").unwrap());
+    }
+
+    #[test]
+    fn test_derivation() {
+        // BIP32 test vector 1
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::from_seed(&seed).unwrap();
+        assert_eq!(hex::encode(&master.private_key), "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35");
+        assert_eq!(hex::encode(&master.chain_code), "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508");
+
+        let child = master.derive_path(&parse_path("m/0'").unwrap()).unwrap();
+        assert_eq!(hex::encode(&child.private_key), "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea");
+        assert_eq!(hex::encode(&child.chain_code), "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141");
+
+        let grandchild = master.derive_path(&parse_path("m/0h/1").unwrap()).unwrap();
+        assert_eq!(hex::encode(&grandchild.private_key), "3c6cb8d0f6a264c91ea8b5030fadaa8e538b020f0a387421a12de9319dc93368");
+        assert_eq!(grandchild, child.derive_child(1).unwrap());
+
+        assert_eq!(parse_path("m").unwrap(), Vec::<u32>::new());
+        assert!(parse_path("0/1").is_err());
+        assert!(parse_path("m/x").is_err());
+        assert!(parse_path("m/2147483648").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use failure::format_err;
+
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::script::Script;
+use crate::tx::TXOutput;
+use crate::wallet::encode_destination;
+
+/// How a transaction moved the coins of a wallet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Coins came in from elsewhere, or from mining
+    Receive,
+    /// Coins went out to someone else
+    Send,
+    /// Coins only moved between addresses of the wallet
+    SelfTransfer,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Receive => write!(f, "receive"),
+            Direction::Send => write!(f, "send"),
+            Direction::SelfTransfer => write!(f, "self"),
+        }
+    }
+}
+
+/// A transaction of the chain that moved coins of a wallet
+#[derive(Debug, Clone)]
+pub struct WalletTransaction {
+    pub txid: String,
+    pub height: usize,
+    pub confirmations: usize,
+    pub direction: Direction,
+    /// Addresses on the other side: those a send paid to, or those a receive
+    /// was paid from. Empty for a coinbase.
+    pub counterparties: Vec<String>,
+    /// Total of the outputs paying the wallet
+    pub received: Amount,
+    /// Total of the wallet outputs the inputs spent, fee included
+    pub sent: Amount,
+    pub is_coinbase: bool,
+}
+
+impl WalletTransaction {
+    /// Whether the wallet lost coins in the transaction
+    pub fn is_outgoing(&self) -> bool {
+        self.sent > self.received
+    }
+
+    /// How much the balance of the wallet moved, in the direction given by
+    /// `is_outgoing`
+    pub fn get_net_amount(&self) -> Amount {
+        self.sent.max(self.received).checked_sub(self.sent.min(self.received)).unwrap_or(Amount::ZERO)
+    }
+}
+
+/// Transactions of `bc` paying to or spending from one of the `owned`
+/// locking scripts, oldest first. The chain is walked from genesis so that
+/// each input can be matched with the output it spends.
+pub fn list_transactions(bc: &Blockchain, owned: &HashSet<Script>) -> Result<Vec<WalletTransaction>> {
+    let params = bc.params();
+    let best_height = bc.get_best_height()?;
+    let mut blocks: Vec<_> = bc.iter().collect();
+    blocks.reverse();
+
+    let mut unspent: HashMap<(String, u32), TXOutput> = HashMap::new();
+    let mut history = Vec::new();
+    for block in blocks {
+        for tx in block.get_transactions() {
+            let txid = tx.get_id();
+            let mut sent = Amount::ZERO;
+            let mut senders = Vec::new();
+            if !tx.is_coinbase() {
+                for vin in tx.get_ins() {
+                    let Some(prev) = unspent.remove(&(vin.get_txid(), vin.get_vout())) else {
+                        continue;
+                    };
+                    if owned.contains(prev.get_script_pub_key()) {
+                        sent = sent.checked_add(prev.get_value())
+                            .ok_or_else(|| format_err!("ERROR: Amount overflow"))?;
+                    } else if let Some(address) = encode_destination(prev.get_script_pub_key(), params) {
+                        senders.push(address);
+                    }
+                }
+            }
+
+            let mut received = Amount::ZERO;
+            let mut recipients = Vec::new();
+            for (vout, out) in tx.get_outs().into_iter().enumerate() {
+                if owned.contains(out.get_script_pub_key()) {
+                    received = received.checked_add(out.get_value())
+                        .ok_or_else(|| format_err!("ERROR: Amount overflow"))?;
+                } else if let Some(address) = encode_destination(out.get_script_pub_key(), params) {
+                    recipients.push(address);
+                }
+                unspent.insert((txid.clone(), vout as u32), out);
+            }
+
+            if sent == Amount::ZERO && received == Amount::ZERO {
+                continue;
+            }
+            let (direction, mut counterparties) = if sent == Amount::ZERO {
+                (Direction::Receive, senders)
+            } else if recipients.is_empty() {
+                (Direction::SelfTransfer, recipients)
+            } else {
+                (Direction::Send, recipients)
+            };
+            counterparties.sort();
+            counterparties.dedup();
+
+            history.push(WalletTransaction {
+                txid,
+                height: block.get_height(),
+                confirmations: best_height + 1 - block.get_height(),
+                direction,
+                counterparties,
+                received,
+                sent,
+                is_coinbase: tx.is_coinbase(),
+            });
+        }
+    }
+
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coinselect::LargestFirst;
+    use crate::testutil::{new_address, test_chain, test_params, test_wallets};
+    use crate::transaction::Transaction;
+    use crate::utxoset::UTXOSet;
+    use crate::wallet::decode_destination;
+
+    #[test]
+    fn test_list_transactions() {
+        let params = test_params();
+        let mut wallets = test_wallets(&params);
+        let alice = wallets.create_wallet().unwrap();
+        let bob = new_address();
+        let mut bc = test_chain(params.clone(), &alice);
+
+        let amount = Amount::from_coins(10);
+        let tx = Transaction::new_utxo(&mut wallets, &alice, &bob, amount, 10, 0, &LargestFirst,
+            &UTXOSet::new(&bc)).unwrap();
+        let payment = tx.get_id();
+        let fee = params.subsidy(0).checked_sub(tx.value_out().unwrap()).unwrap();
+        let coinbase = Transaction::new_coinbase(bob.clone(), 1, &[], params.subsidy(1), &params).unwrap();
+        bc.add_block(vec![coinbase, tx]).unwrap();
+
+        // The whole wallet sees the coinbase come in and the payment go out,
+        // the change staying in the wallet
+        let history = list_transactions(&bc, &wallets.get_owned_scripts().unwrap()).unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].direction, history[0].height, history[0].confirmations), (Direction::Receive, 0, 2));
+        assert!(history[0].is_coinbase && history[0].counterparties.is_empty());
+        assert_eq!(history[0].get_net_amount(), params.subsidy(0));
+        assert_eq!((&history[1].txid, history[1].direction), (&payment, Direction::Send));
+        assert_eq!(history[1].counterparties, vec![bob.clone()]);
+        assert!(history[1].is_outgoing());
+        assert_eq!(history[1].get_net_amount(), amount.checked_add(fee).unwrap());
+
+        // Seen from the address of Alice alone, the change leaves too
+        let owned = HashSet::from([decode_destination(&alice, &params).unwrap()]);
+        let history = list_transactions(&bc, &owned).unwrap();
+        assert_eq!(history[1].get_net_amount(), params.subsidy(0));
+        assert_eq!(history[1].counterparties.len(), 2);
+
+        // Bob mined a block and received the payment from Alice in it
+        let owned = HashSet::from([decode_destination(&bob, &params).unwrap()]);
+        let history = list_transactions(&bc, &owned).unwrap();
+        assert_eq!(history.len(), 2);
+        assert!(history[0].is_coinbase);
+        assert_eq!((history[1].direction, &history[1].counterparties), (Direction::Receive, &vec![alice]));
+        assert_eq!((history[1].get_net_amount(), history[1].confirmations), (amount, 1));
+        assert!(!history[1].is_outgoing());
+    }
+}
@@ -0,0 +1,114 @@
+use crypto::aead::{AeadDecryptor, AeadEncryptor};
+use crypto::chacha20poly1305::ChaCha20Poly1305;
+use crypto::scrypt::{scrypt, ScryptParams};
+use failure::format_err;
+use serde::{Serialize, Deserialize};
+
+use crate::errors::Result;
+
+/// Size of the master key and of the keys derived from passphrases
+pub const KEY_SIZE: usize = 32;
+
+/// scrypt cost parameters: 2^15 rounds over 8 blocks, which takes 32 MiB
+pub const SCRYPT_LOG_N: u8 = 15;
+pub const SCRYPT_R: u32 = 8;
+pub const SCRYPT_P: u32 = 1;
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 8;
+const TAG_SIZE: usize = 16;
+
+/// The random key the wallet private keys are encrypted under, itself
+/// encrypted under a key derived from the passphrase with scrypt. The scrypt
+/// parameters are stored along with it so they can be raised later.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MasterKey {
+    salt: Vec<u8>,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    encrypted_key: Vec<u8>,
+}
+
+impl MasterKey {
+    /// Generates a master key protected by `passphrase`. Returns it along
+    /// with the plaintext key.
+    pub fn new(passphrase: &str) -> Result<(MasterKey, Vec<u8>)> {
+        let key = rand::random::<[u8; KEY_SIZE]>().to_vec();
+        let mut master = MasterKey {
+            salt: rand::random::<[u8; SALT_SIZE]>().to_vec(),
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            encrypted_key: Vec::new(),
+        };
+        master.encrypted_key = encrypt(&master.derive_key(passphrase), &key, &[]);
+        Ok((master, key))
+    }
+
+    /// Decrypts the master key, failing if `passphrase` is wrong
+    pub fn unlock(&self, passphrase: &str) -> Result<Vec<u8>> {
+        decrypt(&self.derive_key(passphrase), &self.encrypted_key, &[])
+            .map_err(|_| format_err!("ERROR: Wrong wallet passphrase"))
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Vec<u8> {
+        let mut key = vec![0; KEY_SIZE];
+        scrypt(passphrase.as_bytes(), &self.salt, &ScryptParams::new(self.log_n, self.r, self.p), &mut key);
+        key
+    }
+}
+
+/// Encrypts `plaintext` under `key` with ChaCha20-Poly1305, authenticating
+/// `aad` along with it. The result is a random nonce, the ciphertext and the
+/// tag.
+pub fn encrypt(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+    let nonce = rand::random::<[u8; NONCE_SIZE]>();
+    let mut ciphertext = vec![0; plaintext.len()];
+    let mut tag = [0; TAG_SIZE];
+    ChaCha20Poly1305::new(key, &nonce, aad).encrypt(plaintext, &mut ciphertext, &mut tag);
+
+    let mut data = nonce.to_vec();
+    data.extend(ciphertext);
+    data.extend(tag);
+    data
+}
+
+/// Decrypts the output of `encrypt`, failing if it was not made with `key`
+/// and `aad` or was tampered with
+pub fn decrypt(key: &[u8], data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < NONCE_SIZE + TAG_SIZE {
+        return Err(format_err!("ERROR: Encrypted data is truncated"));
+    }
+    let (nonce, rest) = data.split_at(NONCE_SIZE);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_SIZE);
+
+    let mut plaintext = vec![0; ciphertext.len()];
+    if !ChaCha20Poly1305::new(key, nonce, aad).decrypt(ciphertext, &mut plaintext, tag) {
+        return Err(format_err!("ERROR: Encrypted data does not authenticate"));
+    }
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keystore() {
+        let (master, key) = MasterKey::new("correct horse").unwrap();
+        assert_eq!(master.unlock("correct horse").unwrap(), key);
+        assert!(master.unlock("battery staple").is_err());
+
+        let secret = [7u8; 32];
+        let data = encrypt(&key, &secret, b"public key");
+        assert_eq!(decrypt(&key, &data, b"public key").unwrap(), secret);
+        assert!(decrypt(&key, &data, b"other key").is_err());
+        assert!(decrypt(&[0; KEY_SIZE], &data, b"public key").is_err());
+
+        let mut tampered = data.clone();
+        tampered[NONCE_SIZE] ^= 1;
+        assert!(decrypt(&key, &tampered, b"public key").is_err());
+        assert!(decrypt(&key, &data[..NONCE_SIZE], b"public key").is_err());
+    }
+}
@@ -0,0 +1,24 @@
+pub mod amount;
+pub mod tx;
+pub mod cli;
+pub mod pow;
+pub mod block;
+pub mod chainparams;
+pub mod config;
+pub mod merkle;
+pub mod mempool;
+pub mod errors;
+pub mod wallet;
+pub mod keystore;
+pub mod hd;
+pub mod utxoset;
+pub mod blockchain;
+pub mod transaction;
+pub mod coinselect;
+pub mod script;
+pub mod psbt;
+pub mod history;
+pub mod validation;
+
+#[cfg(test)]
+mod testutil;
@@ -1,14 +1,4 @@
-use cli::Cli;
-
-mod tx;
-mod cli;
-mod block;
-mod errors;
-mod wallet;
-mod blockchain;
-mod transaction;
-
-
+use rustychain::cli::Cli;
 
 fn main() {
     let cli = Cli::new();
@@ -17,6 +7,4 @@ fn main() {
     } else {
         println!("Error creating CLI");
     }
-
-
 }
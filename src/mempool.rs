@@ -0,0 +1,330 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use bincode::{deserialize, serialize, serialized_size};
+use failure::format_err;
+use log::info;
+use serde::{Serialize, Deserialize};
+
+use crate::amount::Amount;
+use crate::blockchain::Blockchain;
+use crate::errors::Result;
+use crate::script::Script;
+use crate::transaction::Transaction;
+use crate::utxoset::UTXOSet;
+use crate::validation;
+
+pub const MEMPOOL_TREE: &str = "mempool";
+
+/// A validated transaction waiting to be mined
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MempoolEntry {
+    tx: Transaction,
+    fee: Amount,
+    size: usize,
+}
+
+impl MempoolEntry {
+    pub fn get_transaction(&self) -> &Transaction {
+        &self.tx
+    }
+
+    pub fn get_fee(&self) -> Amount {
+        self.fee
+    }
+
+    /// Serialized size of the transaction, in bytes
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    /// Orders entries by fee per byte, highest first. Rates are compared by
+    /// cross-multiplying so no precision is lost.
+    fn cmp_fee_rate(&self, other: &MempoolEntry) -> Ordering {
+        let lhs = self.fee.base_units() as u128 * other.size as u128;
+        let rhs = other.fee.base_units() as u128 * self.size as u128;
+        rhs.cmp(&lhs).then_with(|| self.tx.get_id().cmp(&other.tx.get_id()))
+    }
+}
+
+/// How the mempool would change the balance of an address once mined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PendingBalance {
+    /// Value of the pending outputs paying the address
+    pub incoming: Amount,
+    /// Value of the confirmed outputs of the address spent by pending transactions
+    pub outgoing: Amount,
+    /// Fees paid by pending transactions spending from the address
+    pub fees: Amount,
+}
+
+/// Unconfirmed transactions of a `Blockchain`.
+///
+/// Entries live in their own sled tree next to the blocks, so they survive
+/// restarts. Every entry spends confirmed unspent outputs only and no two
+/// entries spend the same output.
+pub struct Mempool {
+    tree: sled::Tree,
+}
+
+impl Mempool {
+    pub fn new(bc: &Blockchain) -> Result<Mempool> {
+        Ok(Mempool { tree: bc.mempool_tree()? })
+    }
+
+    /// Validates `tx` against the UTXO set and the other entries and adds it,
+    /// even if its lock times keep it out of the next block
+    pub fn add(&self, bc: &Blockchain, tx: Transaction) -> Result<()> {
+        let txid = tx.get_id();
+        if self.tree.contains_key(txid.as_bytes())? {
+            return Err(format_err!("ERROR: Transaction {} is already in the mempool", txid));
+        }
+
+        let spent = self.spent_outputs()?;
+        for vin in tx.get_ins() {
+            if spent.contains(&(vin.get_txid(), vin.get_vout())) {
+                return Err(format_err!(
+                    "ERROR: Transaction {} conflicts with the mempool on output {}:{}",
+                    txid, vin.get_txid(), vin.get_vout()));
+            }
+        }
+
+        let spend_height = bc.get_best_height()? + 1;
+        let fee = validation::check_transaction(&tx, &UTXOSet::new(bc), bc.params(), spend_height)
+            .map_err(|e| format_err!("ERROR: Rejected {}", e))?;
+        let size = serialized_size(&tx)? as usize;
+
+        self.tree.insert(txid.as_bytes(), serialize(&MempoolEntry { tx, fee, size })?)?;
+        self.tree.flush()?;
+        info!("Added transaction {} to the mempool", txid);
+        Ok(())
+    }
+
+    pub fn get(&self, txid: &str) -> Result<Option<MempoolEntry>> {
+        match self.tree.get(txid.as_bytes())? {
+            Some(encoded) => Ok(Some(deserialize(&encoded)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_entries(&self) -> Result<Vec<MempoolEntry>> {
+        let mut entries = Vec::new();
+        for entry in self.tree.iter() {
+            let (_, encoded) = entry?;
+            entries.push(deserialize(&encoded)?);
+        }
+        Ok(entries)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Picks the transactions of the next block, highest fee rate first,
+    /// skipping those whose lock times have not passed yet and those that
+    /// would take the total past `max_size` bytes
+    pub fn block_template(&self, bc: &Blockchain, max_size: usize) -> Result<Vec<MempoolEntry>> {
+        let mut entries = self.get_entries()?;
+        entries.sort_by(|a, b| a.cmp_fee_rate(b));
+
+        let mut size = 0;
+        let mut template = Vec::new();
+        for entry in entries {
+            if !self.is_final(bc, &entry.tx)? {
+                continue;
+            }
+            if size + entry.size <= max_size {
+                size += entry.size;
+                template.push(entry);
+            }
+        }
+
+        Ok(template)
+    }
+
+    /// Whether the lock times of `tx` allow it in the next block. Entries
+    /// that are not final yet stay in the mempool until they are.
+    pub fn is_final(&self, bc: &Blockchain, tx: &Transaction) -> Result<bool> {
+        let spend_height = bc.get_best_height()? + 1;
+        let median_time = bc.get_median_time_past()?;
+        Ok(validation::check_lock_times(tx, &UTXOSet::new(bc), spend_height, median_time).is_ok())
+    }
+
+    /// Drops the entries that are no longer valid on the current chain, for
+    /// instance because a block confirmed them or spent their inputs
+    pub fn prune(&self, bc: &Blockchain) -> Result<usize> {
+        let utxo_set = UTXOSet::new(bc);
+        let spend_height = bc.get_best_height()? + 1;
+        let mut removed = 0;
+
+        for entry in self.get_entries()? {
+            if validation::check_transaction(&entry.tx, &utxo_set, bc.params(), spend_height).is_err() {
+                self.tree.remove(entry.tx.get_id().as_bytes())?;
+                removed += 1;
+            }
+        }
+
+        self.tree.flush()?;
+        Ok(removed)
+    }
+
+    /// Sums what the entries pay to and spend from the outputs locked by
+    /// `script_pub_key`
+    pub fn pending_balance(&self, bc: &Blockchain, script_pub_key: &Script) -> Result<PendingBalance> {
+        let utxo_set = UTXOSet::new(bc);
+        let mut pending = PendingBalance::default();
+        let overflow = || format_err!("ERROR: Amount overflow");
+
+        for entry in self.get_entries()? {
+            let mut spends = false;
+            for vin in entry.tx.get_ins() {
+                if let Some(out) = utxo_set.get_output(&vin.get_txid(), vin.get_vout())? {
+                    if out.get_script_pub_key() == script_pub_key {
+                        pending.outgoing = pending.outgoing.checked_add(out.get_value()).ok_or_else(overflow)?;
+                        spends = true;
+                    }
+                }
+            }
+
+            for out in entry.tx.get_outs() {
+                if out.get_script_pub_key() == script_pub_key {
+                    pending.incoming = pending.incoming.checked_add(out.get_value()).ok_or_else(overflow)?;
+                }
+            }
+
+            if spends {
+                pending.fees = pending.fees.checked_add(entry.fee).ok_or_else(overflow)?;
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// Outputs spent by the entries
+    fn spent_outputs(&self) -> Result<HashSet<(String, u32)>> {
+        let mut spent = HashSet::new();
+        for entry in self.get_entries()? {
+            for vin in entry.tx.get_ins() {
+                spent.insert((vin.get_txid(), vin.get_vout()));
+            }
+        }
+        Ok(spent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::Script;
+    use crate::testutil::{new_address, test_chain_with_key, test_params};
+    use crate::tx::{TXInput, TXOutput};
+    use crate::wallet::decode_destination;
+
+    fn spend(bc: &Blockchain, txid: &str, values: &[Amount], private_key: &[u8], to: &str) -> Transaction {
+        let vin = vec![TXInput::new(txid.to_string(), 0, Script::new())];
+        let vout = values.iter().map(|value| TXOutput::new(*value, to.to_string(), bc.params()).unwrap()).collect();
+        let mut tx = Transaction::new(vin, vout).unwrap();
+        bc.sign_transaction(&mut tx, private_key).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_mempool() {
+        let (mut bc, alice) = test_chain_with_key(test_params());
+        let reward = bc.params().subsidy(0);
+        let address = alice.get_address(bc.params().address_version);
+        let (bob, carol) = (new_address(), new_address());
+        let private_key = alice.private_key.clone();
+
+        // Two coins to spend
+        let coinbase = Transaction::new_coinbase(address.clone(), 1, &[], reward, bc.params()).unwrap();
+        bc.add_block(vec![coinbase.clone()]).unwrap();
+        let genesis_tx = bc.iter().last().unwrap().get_transactions()[0].get_id();
+
+        let mempool = Mempool::new(&bc).unwrap();
+        let cheap = spend(&bc, &genesis_tx, &[Amount::from_coins(99)], &private_key, &bob);
+        mempool.add(&bc, cheap.clone()).unwrap();
+        assert!(mempool.add(&bc, cheap.clone()).is_err());
+        assert_eq!(mempool.get(&cheap.get_id()).unwrap().unwrap().get_fee(), Amount::from_coins(1));
+
+        let pending = mempool.pending_balance(&bc, &decode_destination(&address, bc.params()).unwrap()).unwrap();
+        assert_eq!(pending, PendingBalance {
+            incoming: Amount::ZERO,
+            outgoing: Amount::from_coins(100),
+            fees: Amount::from_coins(1),
+        });
+        assert_eq!(mempool.pending_balance(&bc, &decode_destination(&bob, bc.params()).unwrap()).unwrap().incoming, Amount::from_coins(99));
+
+        // Spending the same output again conflicts
+        let conflict = spend(&bc, &genesis_tx, &[Amount::from_coins(50)], &private_key, &bob);
+        assert!(mempool.add(&bc, conflict).is_err());
+
+        // Overspending is rejected
+        let greedy = spend(&bc, &coinbase.get_id(), &[Amount::from_coins(101)], &private_key, &bob);
+        assert!(mempool.add(&bc, greedy).is_err());
+
+        // The higher fee rate goes first, and a tight limit keeps only it
+        let generous = spend(&bc, &coinbase.get_id(), &[Amount::from_coins(90)], &private_key, &bob);
+        mempool.add(&bc, generous.clone()).unwrap();
+        let template = mempool.block_template(&bc, usize::MAX).unwrap();
+        let ids: Vec<String> = template.iter().map(|entry| entry.get_transaction().get_id()).collect();
+        assert_eq!(ids, vec![generous.get_id(), cheap.get_id()]);
+        let size = mempool.get(&generous.get_id()).unwrap().unwrap().get_size();
+        assert_eq!(mempool.block_template(&bc, size).unwrap().len(), 1);
+
+        // Mining confirms the entries and pruning removes them. The coinbase
+        // may claim the fees but no more.
+        let fees = Amount::checked_sum(template.iter().map(|entry| entry.get_fee())).unwrap();
+        assert_eq!(fees, Amount::from_coins(11));
+        let mut txs: Vec<Transaction> = template.into_iter().map(|entry| entry.get_transaction().clone()).collect();
+        let greedy = Transaction::new_coinbase(
+            carol.clone(), 2, &[], reward.checked_add(Amount::from_coins(12)).unwrap(), bc.params()).unwrap();
+        let mut block = vec![greedy];
+        block.extend(txs.clone());
+        assert!(bc.add_block(block).is_err());
+        txs.insert(0, Transaction::new_coinbase(
+            carol.clone(), 2, &[], reward.checked_add(fees).unwrap(), bc.params()).unwrap());
+        bc.add_block(txs).unwrap();
+        assert_eq!(mempool.prune(&bc).unwrap(), 2);
+        assert!(mempool.is_empty());
+
+        // Collected fees are not newly issued coins
+        assert_eq!(bc.issued_supply().unwrap(), Amount::from_coins(300));
+    }
+
+    #[test]
+    fn test_mempool_lock_time() {
+        let (mut bc, alice) = test_chain_with_key(test_params());
+        let address = alice.get_address(bc.params().address_version);
+        let bob = new_address();
+        let genesis_tx = bc.iter().last().unwrap().get_transactions()[0].get_id();
+
+        // Held until the block after height 2
+        let mut vin = TXInput::new(genesis_tx, 0, Script::new());
+        vin.set_sequence(0);
+        let vout = vec![TXOutput::new(Amount::from_coins(99), bob, bc.params()).unwrap()];
+        let mut tx = Transaction::with_lock_time(vec![vin], vout, 2).unwrap();
+        bc.sign_transaction(&mut tx, &alice.private_key).unwrap();
+
+        let mempool = Mempool::new(&bc).unwrap();
+        mempool.add(&bc, tx.clone()).unwrap();
+        for height in 1..=2 {
+            assert!(!mempool.is_final(&bc, &tx).unwrap());
+            assert!(mempool.block_template(&bc, usize::MAX).unwrap().is_empty());
+            let coinbase = Transaction::new_coinbase(address.clone(), height, &[], Amount::ZERO, bc.params()).unwrap();
+            bc.add_block(vec![coinbase]).unwrap();
+            assert_eq!(mempool.prune(&bc).unwrap(), 0);
+        }
+
+        assert!(mempool.is_final(&bc, &tx).unwrap());
+        let template = mempool.block_template(&bc, usize::MAX).unwrap();
+        assert_eq!(template[0].get_transaction().get_id(), tx.get_id());
+        let coinbase = Transaction::new_coinbase(address, 3, &[], Amount::ZERO, bc.params()).unwrap();
+        bc.add_block(vec![coinbase, tx]).unwrap();
+        assert_eq!(mempool.prune(&bc).unwrap(), 1);
+    }
+}
@@ -0,0 +1,155 @@
+use std::fmt;
+
+use failure::format_err;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::errors::Result;
+
+/// Proof that a transaction is part of a block: the sibling hashes on the
+/// path from the transaction's leaf up to the merkle root of the block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub txid: String,
+    pub index: usize,
+    pub branch: Vec<String>,
+}
+
+impl MerkleProof {
+    /// Recomputes the root from the txid and the branch and compares it to
+    /// the merkle root found in a block header
+    pub fn verify(&self, merkle_root: &str) -> Result<bool> {
+        let mut hash = hex::decode(&self.txid)?;
+        let mut index = self.index;
+
+        for sibling in &self.branch {
+            let sibling = hex::decode(sibling)?;
+            hash = if index.is_multiple_of(2) {
+                hash_nodes(&hash, &sibling)
+            } else {
+                hash_nodes(&sibling, &hash)
+            };
+            index /= 2;
+        }
+
+        Ok(hex::encode(hash) == merkle_root)
+    }
+}
+
+impl fmt::Display for MerkleProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "txid:   {}", self.txid)?;
+        writeln!(f, "index:  {}", self.index)?;
+        write!(f, "branch:")?;
+        for hash in &self.branch {
+            write!(f, "\n  {}", hash)?;
+        }
+        Ok(())
+    }
+}
+
+/// Merkle root over a list of transaction ids. Levels with an odd number of
+/// nodes pair their last node with itself.
+pub fn merkle_root(txids: &[String]) -> Result<String> {
+    if txids.is_empty() {
+        return Err(format_err!("Cannot build a merkle tree without transactions"));
+    }
+
+    let mut level = leaves(txids)?;
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+
+    Ok(hex::encode(&level[0]))
+}
+
+/// Builds the inclusion proof for the transaction at `index`
+pub fn merkle_proof(txids: &[String], index: usize) -> Result<MerkleProof> {
+    if index >= txids.len() {
+        return Err(format_err!("Transaction index {} out of range", index));
+    }
+
+    let mut level = leaves(txids)?;
+    let mut branch = Vec::new();
+    let mut pos = index;
+
+    while level.len() > 1 {
+        let sibling = if pos.is_multiple_of(2) {
+            level.get(pos + 1).unwrap_or(&level[pos])
+        } else {
+            &level[pos - 1]
+        };
+        branch.push(hex::encode(sibling));
+        level = next_level(&level);
+        pos /= 2;
+    }
+
+    Ok(MerkleProof {
+        txid: txids[index].clone(),
+        index,
+        branch,
+    })
+}
+
+fn leaves(txids: &[String]) -> Result<Vec<Vec<u8>>> {
+    txids.iter()
+        .map(|txid| Ok(hex::decode(txid)?))
+        .collect()
+}
+
+fn next_level(level: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    level.chunks(2)
+        .map(|pair| hash_nodes(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+        .collect()
+}
+
+fn hash_nodes(left: &[u8], right: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txids(n: u8) -> Vec<String> {
+        (0..n).map(|i| hex::encode(Sha256::digest([i]))).collect()
+    }
+
+    #[test]
+    fn test_single_transaction_root() {
+        let ids = txids(1);
+        assert_eq!(merkle_root(&ids).unwrap(), ids[0]);
+        assert!(merkle_proof(&ids, 0).unwrap().branch.is_empty());
+    }
+
+    #[test]
+    fn test_proofs_verify_against_root() {
+        for n in 1..=9 {
+            let ids = txids(n);
+            let root = merkle_root(&ids).unwrap();
+
+            for index in 0..ids.len() {
+                let proof = merkle_proof(&ids, index).unwrap();
+                assert!(proof.verify(&root).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampered_proof_fails() {
+        let ids = txids(5);
+        let root = merkle_root(&ids).unwrap();
+
+        let mut proof = merkle_proof(&ids, 3).unwrap();
+        proof.index = 2;
+        assert!(!proof.verify(&root).unwrap());
+
+        let mut proof = merkle_proof(&ids, 3).unwrap();
+        proof.txid = ids[4].clone();
+        assert!(!proof.verify(&root).unwrap());
+
+        assert!(merkle_proof(&ids, 5).is_err());
+    }
+}
@@ -0,0 +1,246 @@
+use std::fmt;
+
+use serde::{Serialize, Deserialize};
+
+/// A 256-bit proof of work target, stored big-endian so that the derived
+/// ordering is the numeric one. A block hash is valid if it is not above it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Target([u8; 32]);
+
+impl Target {
+    pub const MAX: Target = Target([0xff; 32]);
+
+    /// Expands the compact "bits" representation used in block headers:
+    /// the high byte is a base-256 exponent and the low 3 bytes a mantissa.
+    pub fn from_compact(bits: u32) -> Target {
+        let exponent = (bits >> 24) as usize;
+        let mantissa = bits & 0x007fffff;
+        let mut target = [0u8; 32];
+
+        if exponent <= 3 {
+            let mantissa = mantissa >> (8 * (3 - exponent));
+            target[29..].copy_from_slice(&mantissa.to_be_bytes()[1..]);
+        } else {
+            for (i, byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+                // Bytes shifted past the top of the target are dropped
+                if let Some(pos) = (32 + i).checked_sub(exponent) {
+                    target[pos] = *byte;
+                }
+            }
+        }
+
+        Target(target)
+    }
+
+    /// Packs the target back into its compact form, losing the low bits
+    pub fn to_compact(&self) -> u32 {
+        let first = match self.0.iter().position(|b| *b != 0) {
+            Some(first) => first,
+            None => return 0,
+        };
+
+        let mut size = 32 - first;
+        let mut mantissa = self.0[first..].iter().take(3)
+            .fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        if size < 3 {
+            mantissa <<= 8 * (3 - size);
+        }
+
+        // The mantissa is signed in the encoding, keep its top bit clear
+        if mantissa & 0x00800000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+
+        ((size as u32) << 24) | mantissa
+    }
+
+    /// Whether a big-endian hash satisfies this target
+    pub fn is_met_by(&self, hash: &[u8]) -> bool {
+        hash.len() == 32 && hash <= &self.0[..]
+    }
+
+    /// Scales the target by `num / den`, saturating at `Target::MAX`
+    pub fn mul_div(&self, num: u64, den: u64) -> Target {
+        assert!(den != 0, "Division by zero");
+
+        // Little-endian 32-bit limbs with one extra limb for the overflow
+        let mut limbs = [0u64; 9];
+        for (i, limb) in limbs.iter_mut().take(8).enumerate() {
+            let pos = 32 - 4 * (i + 1);
+            *limb = u32::from_be_bytes(self.0[pos..pos + 4].try_into().unwrap()) as u64;
+        }
+
+        let mut carry: u128 = 0;
+        for limb in limbs.iter_mut() {
+            let product = *limb as u128 * num as u128 + carry;
+            *limb = (product & 0xffff_ffff) as u64;
+            carry = product >> 32;
+        }
+        if carry != 0 {
+            return Target::MAX;
+        }
+
+        let mut rem: u128 = 0;
+        for limb in limbs.iter_mut().rev() {
+            let cur = (rem << 32) | *limb as u128;
+            *limb = (cur / den as u128) as u64;
+            rem = cur % den as u128;
+        }
+        if limbs[8] != 0 {
+            return Target::MAX;
+        }
+
+        let mut target = [0u8; 32];
+        for (i, limb) in limbs.iter().take(8).enumerate() {
+            let pos = 32 - 4 * (i + 1);
+            target[pos..pos + 4].copy_from_slice(&(*limb as u32).to_be_bytes());
+        }
+        Target(target)
+    }
+
+    /// Expected number of hashes needed to meet this target, which is
+    /// 2^256 / (target + 1), saturating at `u128::MAX`
+    pub fn work(&self) -> u128 {
+        if *self == Target::MAX {
+            return 1;
+        }
+
+        // 2^256 does not fit, but ~target / (target + 1) + 1 is the same value
+        let target = to_limbs(&self.0);
+        let not_target = target.map(|limb| !limb);
+        let mut den = target;
+        for limb in den.iter_mut() {
+            let (sum, overflow) = limb.overflowing_add(1);
+            *limb = sum;
+            if !overflow {
+                break;
+            }
+        }
+
+        let quotient = div_limbs(&not_target, &den);
+        if quotient[2] != 0 || quotient[3] != 0 {
+            return u128::MAX;
+        }
+        (((quotient[1] as u128) << 64) | quotient[0] as u128).saturating_add(1)
+    }
+}
+
+/// Little-endian 64-bit limbs of a big-endian 256-bit number
+fn to_limbs(bytes: &[u8; 32]) -> [u64; 4] {
+    let mut limbs = [0u64; 4];
+    for (i, limb) in limbs.iter_mut().enumerate() {
+        let pos = 32 - 8 * (i + 1);
+        *limb = u64::from_be_bytes(bytes[pos..pos + 8].try_into().unwrap());
+    }
+    limbs
+}
+
+/// Bitwise long division of two 256-bit numbers
+fn div_limbs(num: &[u64; 4], den: &[u64; 4]) -> [u64; 4] {
+    let mut quotient = [0u64; 4];
+    let mut rem = [0u64; 4];
+
+    for bit in (0..256).rev() {
+        // rem = rem << 1 | next bit of num, remembering the bit shifted out
+        let carry = rem[3] >> 63;
+        for i in (1..4).rev() {
+            rem[i] = (rem[i] << 1) | (rem[i - 1] >> 63);
+        }
+        rem[0] = (rem[0] << 1) | ((num[bit / 64] >> (bit % 64)) & 1);
+
+        if carry == 1 || rem.iter().rev().cmp(den.iter().rev()) != std::cmp::Ordering::Less {
+            let mut borrow = false;
+            for i in 0..4 {
+                let (diff, b1) = rem[i].overflowing_sub(den[i]);
+                let (diff, b2) = diff.overflowing_sub(borrow as u64);
+                rem[i] = diff;
+                borrow = b1 || b2;
+            }
+            quotient[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    quotient
+}
+
+impl fmt::Debug for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in self.0.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Computes the bits for the next retarget window. `actual_timespan` is the
+/// time the last window took to mine and `target_timespan` the time it should
+/// have taken; the adjustment is clamped to a factor of four either way and
+/// never gets easier than `limit_bits`.
+pub fn retarget(bits: u32, actual_timespan: u128, target_timespan: u128, limit_bits: u32) -> u32 {
+    let timespan = actual_timespan.clamp(target_timespan / 4, target_timespan * 4);
+
+    let target = Target::from_compact(bits).mul_div(timespan as u64, target_timespan as u64);
+    let limit = Target::from_compact(limit_bits);
+
+    if target > limit {
+        limit_bits
+    } else {
+        target.to_compact()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POW_LIMIT_BITS: u32 = 0x1f00ffff;
+
+    #[test]
+    fn test_compact_round_trip() {
+        for bits in [POW_LIMIT_BITS, 0x1d00ffff, 0x207fffff, 0x1b0404cb, 0x03123456] {
+            assert_eq!(Target::from_compact(bits).to_compact(), bits);
+        }
+
+        let target = Target::from_compact(0x1d00ffff);
+        assert_eq!(format!("{:?}", target), format!("00000000ffff{}", "0".repeat(52)));
+    }
+
+    #[test]
+    fn test_is_met_by() {
+        let target = Target::from_compact(POW_LIMIT_BITS);
+        let mut hash = [0u8; 32];
+        hash[2] = 0xff;
+        assert!(target.is_met_by(&hash));
+        hash[1] = 0x01;
+        assert!(!target.is_met_by(&hash));
+    }
+
+    #[test]
+    fn test_work() {
+        assert_eq!(Target::MAX.work(), 1);
+        // A target just under 2^255 is met by about half of the hashes
+        assert_eq!(Target::from_compact(0x207fffff).work(), 2);
+        assert_eq!(Target::from_compact(0x1d00ffff).work(), 0x0100010001);
+        assert!(Target::from_compact(0x1c7fff80).work() > Target::from_compact(0x1d00ffff).work());
+        assert_eq!(Target::from_compact(0x03000001).work(), u128::MAX);
+    }
+
+    #[test]
+    fn test_retarget() {
+        let timespan = 200_000;
+
+        // On schedule keeps the difficulty
+        assert_eq!(retarget(0x1d00ffff, timespan, timespan, POW_LIMIT_BITS), 0x1d00ffff);
+
+        // Twice as fast halves the target
+        let harder = retarget(0x1d00ffff, timespan / 2, timespan, POW_LIMIT_BITS);
+        assert_eq!(harder, 0x1c7fff80);
+
+        // Way too fast is clamped to a factor of four
+        assert_eq!(retarget(0x1d00ffff, 1, timespan, POW_LIMIT_BITS), 0x1c3fffc0);
+
+        // Never easier than the limit
+        assert_eq!(retarget(POW_LIMIT_BITS, timespan * 10, timespan, POW_LIMIT_BITS), POW_LIMIT_BITS);
+    }
+}
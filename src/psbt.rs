@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+
+use failure::format_err;
+use serde::{Serialize, Deserialize};
+
+use crate::errors::Result;
+use crate::script::Script;
+use crate::transaction::{hash_pub_key, split_multisig_sig, Transaction};
+use crate::tx::TXOutput;
+use crate::utxoset::UTXOSet;
+use crate::wallet::{derive_pub_key, sign_digest, Wallet};
+
+/// What a signer needs to know about one input of a `Psbt`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PsbtInput {
+    /// The output the input spends
+    pub prev_out: TXOutput,
+    /// Redeem script of a multisig output, which its script hash commits to
+    pub redeem_script: Option<Script>,
+    /// Signatures collected so far, keyed by public key
+    pub signatures: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+/// A partially signed transaction.
+///
+/// It holds the unsigned transaction along with the outputs its inputs spend,
+/// so it can be signed on a machine without the chain, and the signatures
+/// collected from each signer. Copies signed separately are merged with
+/// `combine`, and `finalize` turns a copy with enough signatures into a
+/// transaction ready for the mempool.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Psbt {
+    tx: Transaction,
+    inputs: Vec<PsbtInput>,
+}
+
+impl Psbt {
+    /// Wraps `tx` along with the outputs it spends, looked up in `utxo_set`.
+    /// Multisig inputs must reveal their redeem script; the signatures
+    /// already in `tx` are dropped.
+    pub fn new(mut tx: Transaction, utxo_set: &UTXOSet) -> Result<Psbt> {
+        let mut inputs = Vec::new();
+        for (index, vin) in tx.get_ins().iter().enumerate() {
+            let prev_out = utxo_set.get_output(&vin.get_txid(), vin.get_vout())?
+                .ok_or_else(|| format_err!(
+                    "ERROR: Output {}:{} is missing or spent", vin.get_txid(), vin.get_vout()))?;
+            let redeem_script = split_multisig_sig(vin.get_script_sig()).map(|(redeem_script, _)| redeem_script);
+            if prev_out.get_script_pub_key().get_script_hash().is_some() && redeem_script.is_none() {
+                return Err(format_err!("ERROR: Input {} does not reveal its redeem script", index));
+            }
+
+            tx.set_script_sig(index, Script::new())?;
+            inputs.push(PsbtInput { prev_out, redeem_script, signatures: BTreeMap::new() });
+        }
+
+        Ok(Psbt { tx, inputs })
+    }
+
+    /// The unsigned transaction
+    pub fn get_transaction(&self) -> &Transaction {
+        &self.tx
+    }
+
+    pub fn get_inputs(&self) -> &[PsbtInput] {
+        &self.inputs
+    }
+
+    /// Adds the signature of `private_key` to the inputs it can unlock: those
+    /// spending a pay-to-pubkey-hash output of its key and the multisig
+    /// inputs listing its key. Returns the number of signatures added, and
+    /// fails if no input involves the key.
+    pub fn sign(&mut self, private_key: &[u8]) -> Result<usize> {
+        let pub_key = derive_pub_key(private_key)?;
+        let mut pub_key_hash = pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        let mut involved = false;
+        let mut signed = 0;
+        for (index, input) in self.inputs.iter_mut().enumerate() {
+            let listed = input.multisig().is_some_and(|(_, keys)| keys.contains(&pub_key));
+            if !listed && !input.prev_out.is_locked_with_key(&pub_key_hash) {
+                continue;
+            }
+            involved = true;
+
+            if !input.signatures.contains_key(&pub_key) {
+                let digest = self.tx.signature_hash(index, &input.prev_out)?;
+                input.signatures.insert(pub_key.clone(), sign_digest(private_key, &digest)?);
+                signed += 1;
+            }
+        }
+
+        if !involved {
+            return Err(format_err!("ERROR: No input spends an output of this key"));
+        }
+        Ok(signed)
+    }
+
+    /// Merges the signatures of `other`, a copy of the same transaction
+    /// signed separately
+    pub fn combine(&mut self, other: &Psbt) -> Result<()> {
+        if self.tx.get_id() != other.tx.get_id() || self.inputs.len() != other.inputs.len() {
+            return Err(format_err!("ERROR: The partially signed transactions spend different transactions"));
+        }
+
+        for (input, other) in self.inputs.iter_mut().zip(&other.inputs) {
+            for (pub_key, signature) in &other.signatures {
+                input.signatures.entry(pub_key.clone()).or_insert_with(|| signature.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of valid signatures the inputs still lack
+    pub fn missing_signatures(&self) -> Result<usize> {
+        let mut missing = 0;
+        for index in 0..self.inputs.len() {
+            missing += self.unlocking_script(index)?.1;
+        }
+        Ok(missing)
+    }
+
+    /// Builds the unlocking script of every input from the signatures and
+    /// returns the signed transaction, failing if an input lacks signatures
+    /// or does not verify
+    pub fn finalize(&self) -> Result<Transaction> {
+        let mut tx = self.tx.clone();
+        for (index, input) in self.inputs.iter().enumerate() {
+            let (script_sig, missing) = self.unlocking_script(index)?;
+            if missing > 0 {
+                return Err(format_err!("ERROR: Input {} needs {} more signatures", index, missing));
+            }
+            tx.set_script_sig(index, script_sig)?;
+            if !tx.verify_input(index, &input.prev_out)? {
+                return Err(format_err!("ERROR: Input {} does not verify", index));
+            }
+        }
+        Ok(tx)
+    }
+
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(bincode::serialize(self)?))
+    }
+
+    /// Decodes a partially signed transaction, which must describe every
+    /// input of its transaction and nothing more
+    pub fn from_hex(data: &str) -> Result<Psbt> {
+        let psbt: Psbt = bincode::deserialize(&hex::decode(data.trim())?)?;
+        if psbt.inputs.len() != psbt.tx.get_ins().len() {
+            return Err(format_err!("ERROR: The partially signed transaction describes {} inputs of {}",
+                psbt.inputs.len(), psbt.tx.get_ins().len()));
+        }
+        Ok(psbt)
+    }
+
+    /// Unlocking script of input `index` made of its valid signatures, and
+    /// the number of signatures it still needs
+    fn unlocking_script(&self, index: usize) -> Result<(Script, usize)> {
+        let input = &self.inputs[index];
+        let digest = self.tx.signature_hash(index, &input.prev_out)?;
+        let valid = |pub_key: &Vec<u8>| input.signatures.get(pub_key)
+            .filter(|signature| Wallet::verify(pub_key, &digest, signature));
+
+        if let Some(pub_key_hash) = input.prev_out.get_script_pub_key().get_pub_key_hash() {
+            for pub_key in input.signatures.keys() {
+                let mut hash = pub_key.clone();
+                hash_pub_key(&mut hash);
+                if let Some(signature) = valid(pub_key).filter(|_| hash == pub_key_hash) {
+                    return Ok((Script::p2pkh_sig(signature, pub_key), 0));
+                }
+            }
+            return Ok((Script::new(), 1));
+        }
+
+        let (m, keys) = input.multisig()
+            .ok_or_else(|| format_err!("ERROR: Input {} spends an output of an unknown kind", index))?;
+        let signatures: Vec<&Vec<u8>> = keys.iter().filter_map(valid).take(m).collect();
+        let script_sig = signatures.iter()
+            .fold(Script::new(), |script, signature| script.push_data(signature))
+            .push_data(input.redeem_script.as_ref().unwrap().as_bytes());
+        Ok((script_sig, m - signatures.len()))
+    }
+}
+
+impl PsbtInput {
+    /// Required signatures and keys of the redeem script, if it is a
+    /// multisig script matching the script hash of the spent output
+    fn multisig(&self) -> Option<(usize, Vec<Vec<u8>>)> {
+        let redeem_script = self.redeem_script.as_ref()?;
+        let mut hash = redeem_script.as_bytes().to_vec();
+        hash_pub_key(&mut hash);
+        if self.prev_out.get_script_pub_key().get_script_hash() != Some(&hash[..]) {
+            return None;
+        }
+        redeem_script.parse_multisig()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coinselect::LargestFirst;
+    use crate::amount::Amount;
+    use crate::mempool::Mempool;
+    use crate::testutil::{new_address, test_chain_with_key, test_params};
+    use crate::wallet::{decode_destination, new_wallet};
+
+    #[test]
+    fn test_psbt() {
+        let params = test_params();
+        let (mut bc, alice) = test_chain_with_key(params.clone());
+        let owner = decode_destination(&alice.get_address(params.address_version), &params).unwrap();
+
+        let holders: Vec<Wallet> = (0..3).map(|_| new_wallet()).collect();
+        let keys: Vec<Vec<u8>> = holders.iter().map(|holder| holder.public_key.clone()).collect();
+        let redeem_script = Script::multisig(2, &keys).unwrap();
+        let mut hash = redeem_script.as_bytes().to_vec();
+        hash_pub_key(&mut hash);
+        let multisig = Script::p2sh(&hash);
+
+        // A single key signs a copy that went through its hex encoding
+        let tx = Transaction::new_unsigned(&owner, None, &multisig, &owner, Amount::from_coins(60), 10, 0,
+            &LargestFirst, &UTXOSet::new(&bc)).unwrap();
+        let psbt = Psbt::new(tx, &UTXOSet::new(&bc)).unwrap();
+        assert_eq!(psbt.missing_signatures().unwrap(), 1);
+        assert!(psbt.finalize().is_err());
+        let mut psbt = Psbt::from_hex(&psbt.to_hex().unwrap()).unwrap();
+        assert!(psbt.sign(&holders[0].private_key).is_err());
+        assert_eq!(psbt.sign(&alice.private_key).unwrap(), 1);
+        assert_eq!(psbt.sign(&alice.private_key).unwrap(), 0);
+        let coinbase = Transaction::new_coinbase(new_address(), 1, &[], params.subsidy(1), &params).unwrap();
+        bc.add_block(vec![coinbase, psbt.finalize().unwrap()]).unwrap();
+
+        // Multisig inputs need their redeem script
+        let utxo_set = UTXOSet::new(&bc);
+        let tx = Transaction::new_unsigned(&multisig, None, &owner, &multisig, Amount::from_coins(20), 10, 0,
+            &LargestFirst, &utxo_set).unwrap();
+        assert!(Psbt::new(tx, &utxo_set).is_err());
+
+        // Two holders sign their own copies, which combine into a complete
+        // one. The same signature twice does not count.
+        let tx = Transaction::new_unsigned(&multisig, Some(&redeem_script), &owner, &multisig,
+            Amount::from_coins(20), 10, 0, &LargestFirst, &utxo_set).unwrap();
+        let psbt = Psbt::new(tx, &utxo_set).unwrap();
+        assert_eq!(psbt.missing_signatures().unwrap(), 2);
+        let (mut first, mut second) = (psbt.clone(), psbt);
+        assert_eq!(first.sign(&holders[0].private_key).unwrap(), 1);
+        assert_eq!(second.sign(&holders[2].private_key).unwrap(), 1);
+        assert!(first.finalize().is_err());
+        let mut twice = first.clone();
+        twice.combine(&first).unwrap();
+        assert_eq!(twice.missing_signatures().unwrap(), 1);
+
+        first.combine(&second).unwrap();
+        assert_eq!(first.missing_signatures().unwrap(), 0);
+        let tx = first.finalize().unwrap();
+        assert!(bc.verify_transaction(&mut tx.clone()).unwrap());
+        Mempool::new(&bc).unwrap().add(&bc, tx).unwrap();
+
+        // Copies of another transaction do not combine
+        let tx = Transaction::new_unsigned(&multisig, Some(&redeem_script), &owner, &multisig,
+            Amount::from_coins(10), 10, 0, &LargestFirst, &utxo_set).unwrap();
+        assert!(first.combine(&Psbt::new(tx, &utxo_set).unwrap()).is_err());
+
+        // Containers describing more or fewer inputs than the transaction
+        // has are rejected rather than signed
+        let mut extra = first.clone();
+        extra.inputs.push(extra.inputs[0].clone());
+        assert!(Psbt::from_hex(&extra.to_hex().unwrap()).is_err());
+        assert!(extra.sign(&holders[1].private_key).is_err());
+        assert!(extra.missing_signatures().is_err());
+        let mut missing = first.clone();
+        missing.inputs.clear();
+        assert!(Psbt::from_hex(&missing.to_hex().unwrap()).is_err());
+        assert!(first.get_transaction().signature_hash(1, &first.inputs[0].prev_out).is_err());
+    }
+}
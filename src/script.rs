@@ -0,0 +1,651 @@
+use std::fmt;
+
+use failure::format_err;
+use serde::{Serialize, Deserialize};
+use crate::errors::Result;
+use crate::transaction::hash_pub_key;
+
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_HASH160: u8 = 0xa9;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+
+/// Longest script that may be executed, in bytes
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+
+/// Longest value that may be pushed on the stack
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+
+/// Most non-push opcodes a script may execute. The keys of a
+/// `OP_CHECKMULTISIG` count as well.
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+
+/// Most values the stack may hold at once
+pub const MAX_STACK_SIZE: usize = 1000;
+
+/// Most keys a `OP_CHECKMULTISIG` may check against
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+/// Longest encoding of a number read from the stack
+const MAX_NUM_SIZE: usize = 8;
+
+/// A script locking an output (`scriptPubKey`) or unlocking an input
+/// (`scriptSig`), as its serialized opcodes
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Script(Vec<u8>);
+
+/// A single step of a script
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction<'a> {
+    Push(&'a [u8]),
+    Op(u8),
+}
+
+/// What the signature opcodes check against: the transaction spending the
+/// output, seen from the input being verified
+pub trait SignatureChecker {
+    /// Whether `signature` signs the spending transaction for `pub_key`
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8]) -> bool;
+
+    /// Whether the spending transaction may only be mined past `lock_time`
+    fn check_lock_time(&self, lock_time: i64) -> bool;
+}
+
+impl Script {
+    pub fn new() -> Script {
+        Script(Vec::new())
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Script {
+        Script(bytes)
+    }
+
+    /// Locks an output to the public key hashing to `pub_key_hash`:
+    /// `OP_DUP OP_HASH160 <hash> OP_EQUALVERIFY OP_CHECKSIG`
+    pub fn p2pkh(pub_key_hash: &[u8]) -> Script {
+        Script::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_HASH160)
+            .push_data(pub_key_hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    /// Unlocks a `p2pkh` output
+    pub fn p2pkh_sig(signature: &[u8], pub_key: &[u8]) -> Script {
+        Script::new().push_data(signature).push_data(pub_key)
+    }
+
+    /// Locks an output to the script hashing to `script_hash`, which the
+    /// spender reveals as the last push of its unlocking script:
+    /// `OP_HASH160 <hash> OP_EQUAL`
+    pub fn p2sh(script_hash: &[u8]) -> Script {
+        Script::new()
+            .push_opcode(OP_HASH160)
+            .push_data(script_hash)
+            .push_opcode(OP_EQUAL)
+    }
+
+    /// Requires `m` signatures by distinct `keys`:
+    /// `<m> <key>... <n> OP_CHECKMULTISIG`
+    pub fn multisig(m: usize, keys: &[Vec<u8>]) -> Result<Script> {
+        if m == 0 || m > keys.len() || keys.len() > MAX_PUBKEYS_PER_MULTISIG {
+            return Err(format_err!(
+                "ERROR: Multisig needs 1 <= m <= n <= {} but got {} of {}", MAX_PUBKEYS_PER_MULTISIG, m, keys.len()));
+        }
+
+        let script = keys.iter().fold(Script::new().push_int(m as i64), |script, key| script.push_data(key));
+        Ok(script.push_int(keys.len() as i64).push_opcode(OP_CHECKMULTISIG))
+    }
+
+    /// An output nobody can spend, carrying `data`
+    pub fn op_return(data: &[u8]) -> Script {
+        Script::new().push_opcode(OP_RETURN).push_data(data)
+    }
+
+    pub fn push_opcode(mut self, op: u8) -> Script {
+        self.0.push(op);
+        self
+    }
+
+    /// Appends the shortest push of `data`
+    pub fn push_data(mut self, data: &[u8]) -> Script {
+        match data.len() {
+            0 => self.0.push(OP_0),
+            len if len < OP_PUSHDATA1 as usize => self.0.push(len as u8),
+            len if len <= u8::MAX as usize => self.0.extend([OP_PUSHDATA1, len as u8]),
+            len => {
+                self.0.push(OP_PUSHDATA2);
+                self.0.extend((len as u16).to_le_bytes());
+            }
+        }
+        self.0.extend(data);
+        self
+    }
+
+    /// Appends the shortest push of the number `n`
+    pub fn push_int(self, n: i64) -> Script {
+        match n {
+            1..=16 => self.push_opcode(OP_1 + n as u8 - 1),
+            n => self.push_data(&encode_num(n)),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The hash this script locks to if it is a `p2pkh` script
+    pub fn get_pub_key_hash(&self) -> Option<&[u8]> {
+        match self.instructions().ok()?.as_slice() {
+            [Instruction::Op(OP_DUP), Instruction::Op(OP_HASH160), Instruction::Push(hash),
+                Instruction::Op(OP_EQUALVERIFY), Instruction::Op(OP_CHECKSIG)] if hash.len() == 20 => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// The hash this script locks to if it is a `p2sh` script
+    pub fn get_script_hash(&self) -> Option<&[u8]> {
+        match self.instructions().ok()?.as_slice() {
+            [Instruction::Op(OP_HASH160), Instruction::Push(hash), Instruction::Op(OP_EQUAL)]
+                if hash.len() == 20 => Some(hash),
+            _ => None,
+        }
+    }
+
+    /// The threshold and the keys of a `multisig` script
+    pub fn parse_multisig(&self) -> Option<(usize, Vec<Vec<u8>>)> {
+        let instructions = self.instructions().ok()?;
+        let (last, rest) = instructions.split_last()?;
+        if *last != Instruction::Op(OP_CHECKMULTISIG) || rest.len() < 3 {
+            return None;
+        }
+
+        let m = instruction_num(&rest[0])?;
+        let n = instruction_num(&rest[rest.len() - 1])?;
+        let keys: Vec<Vec<u8>> = rest[1..rest.len() - 1].iter()
+            .map(|instruction| match instruction {
+                Instruction::Push(key) if !key.is_empty() => Some(key.to_vec()),
+                _ => None,
+            })
+            .collect::<Option<_>>()?;
+
+        if m == 0 || m > n || n != keys.len() || n > MAX_PUBKEYS_PER_MULTISIG {
+            return None;
+        }
+        Some((m, keys))
+    }
+
+    /// Whether the script fails as soon as it runs, whatever unlocks it
+    pub fn is_unspendable(&self) -> bool {
+        self.0.first() == Some(&OP_RETURN) || self.0.len() > MAX_SCRIPT_SIZE
+    }
+
+    /// Whether the script only pushes values, as unlocking scripts must
+    pub fn is_push_only(&self) -> bool {
+        match self.instructions() {
+            Ok(instructions) => instructions.iter().all(|instruction| match instruction {
+                Instruction::Push(_) => true,
+                Instruction::Op(op) => (OP_1..=OP_16).contains(op),
+            }),
+            Err(_) => false,
+        }
+    }
+
+    /// Splits the script into its steps, failing if a push runs past its end
+    pub fn instructions(&self) -> Result<Vec<Instruction<'_>>> {
+        let mut instructions = Vec::new();
+        let mut rest = &self.0[..];
+
+        while let Some((&op, tail)) = rest.split_first() {
+            let (len, tail) = match op {
+                0x01..=0x4b => (op as usize, tail),
+                OP_0 => (0, tail),
+                OP_PUSHDATA1 => match tail.split_first() {
+                    Some((&len, tail)) => (len as usize, tail),
+                    None => return Err(format_err!("ERROR: Truncated push in script")),
+                },
+                OP_PUSHDATA2 if tail.len() >= 2 => (u16::from_le_bytes([tail[0], tail[1]]) as usize, &tail[2..]),
+                OP_PUSHDATA2 => return Err(format_err!("ERROR: Truncated push in script")),
+                _ => {
+                    instructions.push(Instruction::Op(op));
+                    rest = tail;
+                    continue;
+                }
+            };
+
+            if tail.len() < len {
+                return Err(format_err!("ERROR: Truncated push in script"));
+            }
+            instructions.push(Instruction::Push(&tail[..len]));
+            rest = &tail[len..];
+        }
+
+        Ok(instructions)
+    }
+}
+
+impl fmt::Display for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let instructions = match self.instructions() {
+            Ok(instructions) => instructions,
+            Err(_) => return write!(f, "[invalid] {}", hex::encode(&self.0)),
+        };
+
+        for (i, instruction) in instructions.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            match instruction {
+                Instruction::Push([]) => write!(f, "0")?,
+                Instruction::Push(data) => write!(f, "{}", hex::encode(data))?,
+                Instruction::Op(op) => write!(f, "{}", op_name(*op))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Script({})", self)
+    }
+}
+
+/// Runs `script_sig` then `script_pub_key` on the same stack and succeeds if
+/// a single true value is left. `script_sig` may only push values. When
+/// `script_pub_key` is a `p2sh` script, the script revealed by the last push
+/// of `script_sig` must then succeed as well on the values pushed before it.
+pub fn verify_script(script_sig: &Script, script_pub_key: &Script, checker: &dyn SignatureChecker) -> Result<()> {
+    if !script_sig.is_push_only() {
+        return Err(format_err!("ERROR: Unlocking script must only push values"));
+    }
+
+    let mut stack = Vec::new();
+    eval_script(&mut stack, script_sig, checker)?;
+    let mut redeem_stack = stack.clone();
+    eval_script(&mut stack, script_pub_key, checker)?;
+
+    if script_pub_key.get_script_hash().is_none() {
+        return check_result(&stack);
+    }
+
+    if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+        return Err(format_err!("ERROR: Script hash does not match"));
+    }
+    let redeem_script = Script::from_bytes(pop(&mut redeem_stack)?);
+    eval_script(&mut redeem_stack, &redeem_script, checker)?;
+    check_result(&redeem_stack)
+}
+
+fn check_result(stack: &[Vec<u8>]) -> Result<()> {
+    match stack {
+        [top] if cast_to_bool(top) => Ok(()),
+        [_] | [] => Err(format_err!("ERROR: Script evaluated to false")),
+        _ => Err(format_err!("ERROR: Script left {} values on the stack", stack.len())),
+    }
+}
+
+/// Executes `script` on `stack`, failing on the first opcode that fails or
+/// breaks one of the limits
+pub fn eval_script(stack: &mut Vec<Vec<u8>>, script: &Script, checker: &dyn SignatureChecker) -> Result<()> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(format_err!("ERROR: Script is longer than {} bytes", MAX_SCRIPT_SIZE));
+    }
+
+    let mut ops = 0;
+    for instruction in script.instructions()? {
+        let op = match instruction {
+            Instruction::Push(data) => {
+                if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                    return Err(format_err!("ERROR: Script pushes more than {} bytes", MAX_SCRIPT_ELEMENT_SIZE));
+                }
+                stack.push(data.to_vec());
+                check_stack_size(stack)?;
+                continue;
+            }
+            Instruction::Op(op) => op,
+        };
+
+        if op > OP_16 {
+            ops += 1;
+            check_op_count(ops)?;
+        }
+
+        match op {
+            OP_1..=OP_16 => stack.push(encode_num((op - OP_1 + 1) as i64)),
+            OP_VERIFY => {
+                if !cast_to_bool(&pop(stack)?) {
+                    return Err(format_err!("ERROR: OP_VERIFY failed"));
+                }
+            }
+            OP_RETURN => return Err(format_err!("ERROR: OP_RETURN executed")),
+            OP_DROP => {
+                pop(stack)?;
+            }
+            OP_DUP => {
+                let top = stack.last().cloned().ok_or_else(stack_underflow)?;
+                stack.push(top);
+            }
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let equal = pop(stack)? == pop(stack)?;
+                if op == OP_EQUALVERIFY {
+                    if !equal {
+                        return Err(format_err!("ERROR: OP_EQUALVERIFY failed"));
+                    }
+                } else {
+                    stack.push(encode_bool(equal));
+                }
+            }
+            OP_HASH160 => {
+                let mut top = pop(stack)?;
+                hash_pub_key(&mut top);
+                stack.push(top);
+            }
+            OP_CHECKSIG => {
+                let pub_key = pop(stack)?;
+                let signature = pop(stack)?;
+                stack.push(encode_bool(checker.check_sig(&signature, &pub_key)));
+            }
+            OP_CHECKMULTISIG => {
+                let keys_count = pop_count(stack, MAX_PUBKEYS_PER_MULTISIG)?;
+                ops += keys_count;
+                check_op_count(ops)?;
+                let keys = pop_many(stack, keys_count)?;
+                let sigs_count = pop_count(stack, keys_count)?;
+                let sigs = pop_many(stack, sigs_count)?;
+                stack.push(encode_bool(check_multisig(&sigs, &keys, checker)));
+            }
+            OP_CHECKLOCKTIMEVERIFY => {
+                let lock_time = decode_num(stack.last().ok_or_else(stack_underflow)?)?;
+                if lock_time < 0 || !checker.check_lock_time(lock_time) {
+                    return Err(format_err!("ERROR: OP_CHECKLOCKTIMEVERIFY failed for {}", lock_time));
+                }
+            }
+            op => return Err(format_err!("ERROR: Unknown opcode 0x{:02x}", op)),
+        }
+        check_stack_size(stack)?;
+    }
+
+    Ok(())
+}
+
+/// Matches the signatures against the keys in order, so every signature
+/// needs a distinct key and they must come in the order of their keys
+fn check_multisig(sigs: &[Vec<u8>], keys: &[Vec<u8>], checker: &dyn SignatureChecker) -> bool {
+    let mut keys = keys.iter();
+    for (i, signature) in sigs.iter().enumerate() {
+        loop {
+            if keys.len() < sigs.len() - i {
+                return false;
+            }
+            match keys.next() {
+                Some(key) if checker.check_sig(signature, key) => break,
+                Some(_) => continue,
+                None => return false,
+            }
+        }
+    }
+    true
+}
+
+/// Encodes `n` as little-endian magnitude bytes with the sign in the top
+/// bit of the last byte. Zero is the empty value.
+pub fn encode_num(n: i64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut magnitude = n.unsigned_abs();
+    while magnitude > 0 {
+        bytes.push(magnitude as u8);
+        magnitude >>= 8;
+    }
+
+    if let Some(&last) = bytes.last() {
+        if last & 0x80 != 0 {
+            bytes.push(if n < 0 { 0x80 } else { 0 });
+        } else if n < 0 {
+            *bytes.last_mut().unwrap() |= 0x80;
+        }
+    }
+    bytes
+}
+
+/// Reads a value written by `encode_num`
+pub fn decode_num(bytes: &[u8]) -> Result<i64> {
+    if bytes.len() > MAX_NUM_SIZE {
+        return Err(format_err!("ERROR: Number is longer than {} bytes", MAX_NUM_SIZE));
+    }
+
+    let mut magnitude = 0u64;
+    for (i, byte) in bytes.iter().enumerate() {
+        let byte = if i == bytes.len() - 1 { byte & 0x7f } else { *byte };
+        magnitude |= (byte as u64) << (8 * i);
+    }
+
+    let n = i64::try_from(magnitude).map_err(|_| format_err!("ERROR: Number out of range"))?;
+    match bytes.last() {
+        Some(last) if last & 0x80 != 0 => Ok(-n),
+        _ => Ok(n),
+    }
+}
+
+/// Any value other than zero, negative zero included, is true
+pub fn cast_to_bool(value: &[u8]) -> bool {
+    value.iter().enumerate().any(|(i, &byte)| byte != 0 && !(i == value.len() - 1 && byte == 0x80))
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { Vec::new() }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>> {
+    stack.pop().ok_or_else(stack_underflow)
+}
+
+/// Pops the number of keys or signatures of a `OP_CHECKMULTISIG`
+fn pop_count(stack: &mut Vec<Vec<u8>>, max: usize) -> Result<usize> {
+    let count = decode_num(&pop(stack)?)?;
+    match usize::try_from(count) {
+        Ok(count) if count <= max => Ok(count),
+        _ => Err(format_err!("ERROR: Invalid OP_CHECKMULTISIG count {}", count)),
+    }
+}
+
+/// Pops the top `count` values, in the order they were pushed
+fn pop_many(stack: &mut Vec<Vec<u8>>, count: usize) -> Result<Vec<Vec<u8>>> {
+    if stack.len() < count {
+        return Err(stack_underflow());
+    }
+    Ok(stack.split_off(stack.len() - count))
+}
+
+fn check_stack_size(stack: &[Vec<u8>]) -> Result<()> {
+    if stack.len() > MAX_STACK_SIZE {
+        return Err(format_err!("ERROR: Stack holds more than {} values", MAX_STACK_SIZE));
+    }
+    Ok(())
+}
+
+fn check_op_count(ops: usize) -> Result<()> {
+    if ops > MAX_OPS_PER_SCRIPT {
+        return Err(format_err!("ERROR: Script runs more than {} opcodes", MAX_OPS_PER_SCRIPT));
+    }
+    Ok(())
+}
+
+fn stack_underflow() -> failure::Error {
+    format_err!("ERROR: Script reads past the bottom of the stack")
+}
+
+/// Value of an instruction pushing a small number
+fn instruction_num(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Op(op) if (OP_1..=OP_16).contains(op) => Some((op - OP_1 + 1) as usize),
+        Instruction::Push(data) => usize::try_from(decode_num(data).ok()?).ok(),
+        _ => None,
+    }
+}
+
+fn op_name(op: u8) -> String {
+    match op {
+        OP_1..=OP_16 => format!("{}", op - OP_1 + 1),
+        OP_VERIFY => "OP_VERIFY".to_string(),
+        OP_RETURN => "OP_RETURN".to_string(),
+        OP_DROP => "OP_DROP".to_string(),
+        OP_DUP => "OP_DUP".to_string(),
+        OP_EQUAL => "OP_EQUAL".to_string(),
+        OP_EQUALVERIFY => "OP_EQUALVERIFY".to_string(),
+        OP_HASH160 => "OP_HASH160".to_string(),
+        OP_CHECKSIG => "OP_CHECKSIG".to_string(),
+        OP_CHECKMULTISIG => "OP_CHECKMULTISIG".to_string(),
+        OP_CHECKLOCKTIMEVERIFY => "OP_CHECKLOCKTIMEVERIFY".to_string(),
+        op => format!("OP_UNKNOWN(0x{:02x})", op),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accepts the signatures equal to their key and lock times up to 100
+    struct TestChecker;
+
+    impl SignatureChecker for TestChecker {
+        fn check_sig(&self, signature: &[u8], pub_key: &[u8]) -> bool {
+            signature == pub_key
+        }
+
+        fn check_lock_time(&self, lock_time: i64) -> bool {
+            lock_time <= 100
+        }
+    }
+
+    fn run(script_sig: &Script, script_pub_key: &Script) -> bool {
+        verify_script(script_sig, script_pub_key, &TestChecker).is_ok()
+    }
+
+    #[test]
+    fn test_num() {
+        for n in [0, 1, -1, 127, 128, -128, 255, 256, 0x7fff_ffff, 1_700_000_000_000, i64::MAX, -i64::MAX] {
+            assert_eq!(decode_num(&encode_num(n)).unwrap(), n);
+        }
+        assert_eq!(encode_num(0), Vec::<u8>::new());
+        assert_eq!(encode_num(-1), vec![0x81]);
+        assert_eq!(encode_num(128), vec![0x80, 0x00]);
+        assert!(decode_num(&[1; 9]).is_err());
+        assert!(!cast_to_bool(&[0, 0x80]));
+        assert!(cast_to_bool(&[0x80, 0]));
+    }
+
+    #[test]
+    fn test_p2pkh() {
+        let pub_key = vec![2u8; 33];
+        let mut hash = pub_key.clone();
+        hash_pub_key(&mut hash);
+        let script_pub_key = Script::p2pkh(&hash);
+        assert_eq!(script_pub_key.get_pub_key_hash(), Some(&hash[..]));
+        assert_eq!(Script::from_bytes(script_pub_key.as_bytes().to_vec()), script_pub_key);
+        assert!(format!("{}", script_pub_key).starts_with("OP_DUP OP_HASH160 "));
+
+        assert!(run(&Script::p2pkh_sig(&pub_key, &pub_key), &script_pub_key));
+        // Bad signature, other key, nothing at all
+        assert!(!run(&Script::p2pkh_sig(&[1; 64], &pub_key), &script_pub_key));
+        assert!(!run(&Script::p2pkh_sig(&[3; 33], &[3; 33]), &script_pub_key));
+        assert!(!run(&Script::new(), &script_pub_key));
+
+        // The unlocking script may not run opcodes of its own
+        let sneaky = Script::new().push_opcode(OP_DUP);
+        assert!(!run(&sneaky, &Script::new().push_int(1)));
+    }
+
+    #[test]
+    fn test_multisig() {
+        let keys: Vec<Vec<u8>> = (1..=3).map(|i| vec![i; 33]).collect();
+        let mut script_pub_key = Script::new().push_int(2);
+        for key in &keys {
+            script_pub_key = script_pub_key.push_data(key);
+        }
+        let script_pub_key = script_pub_key.push_int(3).push_opcode(OP_CHECKMULTISIG);
+
+        let sigs = |sigs: &[&Vec<u8>]| sigs.iter().fold(Script::new(), |script, sig| script.push_data(sig));
+        assert!(run(&sigs(&[&keys[0], &keys[2]]), &script_pub_key));
+        assert!(run(&sigs(&[&keys[1], &keys[2]]), &script_pub_key));
+        // Too few, out of order, the same key twice, a bad signature
+        assert!(!run(&sigs(&[&keys[0]]), &script_pub_key));
+        assert!(!run(&sigs(&[&keys[2], &keys[0]]), &script_pub_key));
+        assert!(!run(&sigs(&[&keys[1], &keys[1]]), &script_pub_key));
+        assert!(!run(&sigs(&[&keys[0], &vec![9; 33]]), &script_pub_key));
+    }
+
+    #[test]
+    fn test_p2sh() {
+        let keys: Vec<Vec<u8>> = (1..=3).map(|i| vec![i; 33]).collect();
+        let redeem_script = Script::multisig(2, &keys).unwrap();
+        assert_eq!(redeem_script.parse_multisig(), Some((2, keys.clone())));
+        assert!(Script::multisig(0, &keys).is_err());
+        assert!(Script::multisig(4, &keys).is_err());
+
+        let mut hash = redeem_script.as_bytes().to_vec();
+        hash_pub_key(&mut hash);
+        let script_pub_key = Script::p2sh(&hash);
+        assert_eq!(script_pub_key.get_script_hash(), Some(&hash[..]));
+        assert_eq!(script_pub_key.get_pub_key_hash(), None);
+
+        let unlock = |sigs: &[&Vec<u8>], redeem: &Script| sigs.iter()
+            .fold(Script::new(), |script, sig| script.push_data(sig))
+            .push_data(redeem.as_bytes());
+        assert!(run(&unlock(&[&keys[0], &keys[1]], &redeem_script), &script_pub_key));
+        // The redeem script must run and succeed, and match the hash
+        assert!(!run(&unlock(&[&keys[0]], &redeem_script), &script_pub_key));
+        let other = Script::multisig(1, &keys).unwrap();
+        assert!(!run(&unlock(&[&keys[0]], &other), &script_pub_key));
+        // Nothing may be left over
+        assert!(!run(&unlock(&[&keys[0], &keys[0], &keys[1]], &redeem_script), &script_pub_key));
+    }
+
+    #[test]
+    fn test_lock_time_and_limits() {
+        let locked = |lock_time| Script::new()
+            .push_int(lock_time)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP)
+            .push_int(1);
+        assert!(run(&Script::new(), &locked(100)));
+        assert!(!run(&Script::new(), &locked(101)));
+        assert!(!run(&Script::new(), &locked(-1)));
+
+        let burn = Script::op_return(b"hello");
+        assert!(burn.is_unspendable());
+        assert!(!run(&Script::new().push_int(1), &burn));
+
+        // Too many opcodes, too large a push, too deep a stack
+        let busy = (0..=MAX_OPS_PER_SCRIPT).fold(Script::new().push_int(1), |script, _| script.push_opcode(OP_DUP));
+        assert!(!run(&Script::new(), &busy));
+        let big = vec![1; MAX_SCRIPT_ELEMENT_SIZE + 1];
+        assert!(!run(&Script::new().push_data(&big), &Script::new().push_opcode(OP_DROP).push_int(1)));
+        let deep = (0..=MAX_STACK_SIZE).fold(Script::new(), |script, _| script.push_int(1));
+        assert!(!run(&deep, &Script::new()));
+
+        // Pushes running past the end of the script are rejected
+        assert!(Script::from_bytes(vec![5, 1, 2]).instructions().is_err());
+        assert!(!run(&Script::new(), &Script::from_bytes(vec![OP_PUSHDATA1])));
+    }
+}
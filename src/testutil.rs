@@ -0,0 +1,67 @@
+//! Fixtures shared by the tests of the crate
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::Blockchain;
+use crate::chainparams::ChainParams;
+use crate::wallet::{new_wallet, Wallet, Wallets};
+
+/// Regtest parameters with coinbase outputs spendable right away
+pub fn test_params() -> ChainParams {
+    ChainParams { coinbase_maturity: 0, ..ChainParams::regtest() }
+}
+
+/// A database removed once the test drops it
+pub fn temporary_db() -> sled::Db {
+    sled::Config::new().temporary(true).open().unwrap()
+}
+
+/// A directory removed once the test drops it
+pub struct TemporaryDir(PathBuf);
+
+impl TemporaryDir {
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TemporaryDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// A new empty directory under the system temporary directory
+pub fn temporary_dir() -> TemporaryDir {
+    let path = env::temp_dir().join(format!("rustychain-{}", hex::encode(rand::random::<[u8; 8]>())));
+    fs::create_dir_all(&path).unwrap();
+    TemporaryDir(path)
+}
+
+/// Regtest address of a new key the test does not keep
+pub fn new_address() -> String {
+    new_wallet().get_address(ChainParams::regtest().address_version)
+}
+
+/// A chain with `params` on a temporary database, whose genesis coinbase
+/// pays `owner`
+pub fn test_chain(params: ChainParams, owner: &str) -> Blockchain {
+    Blockchain::create_blockchain(temporary_db(), params, owner.to_string()).unwrap()
+}
+
+/// Like `test_chain`, paying a new key returned along with the chain
+pub fn test_chain_with_key(params: ChainParams) -> (Blockchain, Wallet) {
+    let owner = new_wallet();
+    let bc = test_chain(params.clone(), &owner.get_address(params.address_version));
+    (bc, owner)
+}
+
+/// Unlocked wallets of the network of `params` on a temporary database,
+/// protected by the passphrase "secret"
+pub fn test_wallets(params: &ChainParams) -> Wallets {
+    let mut wallets = Wallets::new(temporary_db(), params).unwrap();
+    wallets.set_passphrase("secret").unwrap();
+    wallets
+}
@@ -1,100 +1,279 @@
 use std::collections::HashMap;
-use std::fmt::Display;
 
 use crypto::digest::Digest;
-use crypto::{ed25519, ripemd160};
-use failure::{format_err, Error};
+use crypto::ripemd160;
+use failure::format_err;
 use serde::{Serialize, Deserialize};
-use sha2::{Digest, Sha256};
+use sha2::{Digest as Sha256Digest, Sha256};
+use crate::amount::Amount;
+use crate::chainparams::ChainParams;
+use crate::coinselect::{CoinSelector, SelectionParams, Utxo, DUST_THRESHOLD};
 use crate::errors::{Result};
-use crate::blockchain::Blockchain;
+use crate::script::{self, Instruction, Script, SignatureChecker};
 use crate::tx::{TXInput, TXOutput};
-use crate::wallet::Wallets;
+use crate::utxoset::UTXOSet;
+use crate::wallet::{decode_destination, derive_pub_key, sign_digest, Wallet, Wallets};
+
+/// Output index used by the input of a coinbase transaction
+pub const COINBASE_VOUT: u32 = u32::MAX;
+
+/// Bytes of the block height at the start of the coinbase input
+pub const COINBASE_HEIGHT_SIZE: usize = 8;
+
+/// Longest data a coinbase may carry after its height
+pub const MAX_COINBASE_DATA_SIZE: usize = 100;
+
+/// Fee rate used by `send` unless one is given, in base units per byte
+pub const DEFAULT_FEE_RATE: u64 = 10;
+
+/// Longest signature an input may carry
+pub const MAX_SIGNATURE_SIZE: usize = 72;
+
+/// Size of a compressed secp256k1 public key
+pub const PUBLIC_KEY_SIZE: usize = 33;
+
+/// Room reserved for the unlocking script of an unsigned input when
+/// estimating sizes: a signature and a public key with their push opcodes
+pub const MAX_SCRIPT_SIG_SIZE: usize = 1 + MAX_SIGNATURE_SIZE + 1 + PUBLIC_KEY_SIZE;
+
+/// Lock times below this are block heights, the others Unix timestamps in
+/// seconds
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+
+/// Sequence of an input that opts out of the lock time and of relative locks
+pub const SEQUENCE_FINAL: u32 = u32::MAX;
+
+/// Set in the sequence of an input that has no relative lock
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+
+/// Set in the sequence of an input whose relative lock is a time rather than
+/// a number of blocks
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+
+/// Bits of the sequence holding the relative lock
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0xffff;
+
+/// Relative time locks count in units of 2^9 = 512 seconds
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+/// How long after its output was created an input may be mined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelativeLock {
+    /// Confirmations the spent output needs
+    Blocks(usize),
+    /// Milliseconds the median time past must be past the timestamp of the
+    /// block that created the spent output
+    Time(u128),
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Transaction {
     id: String,
     vin: Vec<TXInput>,
-    vout: Vec<TXOutput>
+    vout: Vec<TXOutput>,
+    lock_time: u64,
 }
 
 impl Transaction {
-    
-    pub fn new_utxo(from: &str, to: &str, amount: f32, bc: &Blockchain) -> Result<Transaction> {
-        let mut vin = Vec::new();
+    /// Builds an unsigned transaction from its inputs and outputs
+    pub fn new(vin: Vec<TXInput>, vout: Vec<TXOutput>) -> Result<Transaction> {
+        Transaction::with_lock_time(vin, vout, 0)
+    }
 
-        let wallets = Wallets::new();
-        let wallet = match wallets.get_wallet(from) {
-            Some(wallet) => wallet,
+    /// Like `new`, for a transaction that may not be mined before
+    /// `lock_time`, a block height below `LOCKTIME_THRESHOLD` or a Unix
+    /// timestamp in seconds. The lock time only applies if some input has
+    /// a sequence other than `SEQUENCE_FINAL`.
+    pub fn with_lock_time(vin: Vec<TXInput>, vout: Vec<TXOutput>, lock_time: u64) -> Result<Transaction> {
+        let mut tx = Transaction {
+            id: String::new(),
+            vin,
+            vout,
+            lock_time
+        };
+
+        tx.set_id()?;
+        Ok(tx)
+    }
+
+    /// Builds a transaction paying `amount` from `from` to `to` and signs it
+    /// with the keys we hold for `from`. `from` is one of our wallets or a
+    /// multisig address added to them, in which case the transaction may
+    /// still need the signatures of other holders. The change is handled as
+    /// in `new_unsigned_utxo`. The inputs also cover a fee of `fee_rate` base
+    /// units per byte of the fully signed transaction. Both addresses must
+    /// be valid on the network of the chain, but `to` need not be ours. A
+    /// non-zero `lock_time` keeps the transaction out of blocks until then,
+    /// and `selector` picks the coins it spends.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_utxo(
+        wallets: &mut Wallets,
+        from: &str,
+        to: &str,
+        amount: Amount,
+        fee_rate: u64,
+        lock_time: u64,
+        selector: &dyn CoinSelector,
+        utxo_set: &UTXOSet
+    ) -> Result<Transaction> {
+        // Multisig inputs are signed by every listed key we hold
+        let signers = match wallets.get_multisig(from)? {
+            Some(redeem_script) => {
+                let keys = redeem_script.parse_multisig().map(|(_, keys)| keys).unwrap_or_default();
+                wallets.get_signers(&keys)
+            }
             None => {
-                return Err(format_err!("ERROR: Wallet not found"))
+                let wallet = match wallets.get_wallet(from) {
+                    Some(wallet) => wallet,
+                    None => {
+                        return Err(format_err!("ERROR: Wallet not found"))
+                    }
+                };
+                if wallet.private_key.is_empty() {
+                    return Err(format_err!("ERROR: Wallet '{}' has no private key", from));
+                }
+                vec![wallet]
             }
         };
-
-        if let None = wallets.get_wallet(&to) {
-            return Err(format_err!("ERROR: Wallet not found"));
+        if signers.is_empty() {
+            return Err(format_err!("ERROR: None of the keys of '{}' is in the wallet", from));
         }
 
-        let mut pub_key_hash = wallet.get_pub_key();
-        hash_pub_key(&mut pub_key_hash);
-
+        let mut tx = Transaction::new_unsigned_utxo(wallets, from, to, amount, fee_rate, lock_time, selector,
+            utxo_set)?;
+        for signer in &signers {
+            utxo_set.blockchain().sign_transaction(&mut tx, &signer.private_key)?;
+        }
+        Ok(tx)
+    }
 
-        let acc_v = bc.find_spendable_outputs(from, amount);
+    /// Like `new_utxo`, but leaves the transaction unsigned. If the selected
+    /// coins leave change, the change of a wallet with a passphrase goes to a
+    /// fresh internal address so that payments are not linked to each other,
+    /// which needs the wallet to be unlocked. Wallets without a passphrase
+    /// cannot store new keys, so their change goes back to `from`, as does
+    /// the change of a multisig address.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_unsigned_utxo(
+        wallets: &mut Wallets,
+        from: &str,
+        to: &str,
+        amount: Amount,
+        fee_rate: u64,
+        lock_time: u64,
+        selector: &dyn CoinSelector,
+        utxo_set: &UTXOSet
+    ) -> Result<Transaction> {
+        let params = utxo_set.blockchain().params();
+        let from_script = decode_destination(from, params)?;
+        let to_script = decode_destination(to, params)?;
+
+        // Multisig inputs reveal the redeem script
+        let redeem_script = wallets.get_multisig(from)?;
+        let mut tx = Transaction::new_unsigned(&from_script, redeem_script.as_ref(), &to_script, &from_script,
+            amount, fee_rate, lock_time, selector, utxo_set)?;
+
+        // The change output follows the payment. A fresh key hash is as long
+        // as the one of `from`, so the size and the fee stay the same.
+        let fresh_change = redeem_script.is_none() && wallets.is_encrypted()
+            && from_script.get_pub_key_hash().is_some();
+        if fresh_change && tx.vout.len() > 1 {
+            let change_key = wallets.next_change_key()?;
+            tx.vout[1] = TXOutput::with_script(tx.vout[1].get_value(), change_key.get_script_pub_key());
+            tx.set_id()?;
+            wallets.add_change_key(change_key)?;
+        }
+        Ok(tx)
+    }
 
-        if acc_v.0 < amount {
-            return Err(format_err!("ERROR: Not enough funds"));
+    /// Builds an unsigned transaction paying `amount` to `to_script` from the
+    /// outputs locked by `from_script` that `selector` picks, with the change
+    /// going to `change_script`. Inputs spending a multisig `redeem_script`
+    /// already reveal it. The inputs also cover a fee of `fee_rate` base
+    /// units per byte of the fully signed transaction, and change too small
+    /// to be worth an output is added to the fee.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new_unsigned(
+        from_script: &Script,
+        redeem_script: Option<&Script>,
+        to_script: &Script,
+        change_script: &Script,
+        amount: Amount,
+        fee_rate: u64,
+        lock_time: u64,
+        selector: &dyn CoinSelector,
+        utxo_set: &UTXOSet
+    ) -> Result<Transaction> {
+        if amount < DUST_THRESHOLD {
+            return Err(format_err!("ERROR: Cannot send less than {}", DUST_THRESHOLD));
         }
 
-        let mut acc_txs = acc_v.1;
-        for (txid, outs) in acc_txs.iter() {
-            let txid = txid.clone();
-            for out in outs {
-                vin.push(
-                    TXInput::new(
-                        txid.clone(),
-                        out.clone(),
-                        vec![],
-                        wallet.get_pub_key().clone()
-                    ));   
+        let build = |coins: &[Utxo], change: Amount| -> Result<Transaction> {
+            let vin = coins.iter()
+                .map(|utxo| {
+                    let mut input = TXInput::new(utxo.txid.clone(), utxo.vout, Script::new());
+                    // Final inputs would disable the lock time
+                    if lock_time > 0 {
+                        input.set_sequence(SEQUENCE_FINAL - 1);
+                    }
+                    input
+                })
+                .collect();
+            let mut vout = vec![TXOutput::with_script(amount, to_script.clone())];
+            if change > Amount::ZERO {
+                vout.push(TXOutput::with_script(change, change_script.clone()));
             }
-        } 
 
-        let mut vout = vec![TXOutput::new(
-            amount,
-            to.to_string()
-        )];
-        
-        if acc_v.0 > amount {
-            vout.push(TXOutput::new(
-                acc_v.0 - amount,
-                from.to_string()
-            ));
-        }
-
-        let mut tx = Transaction {
-            id: String::new(),
-            vin,
-            vout
+            let mut tx = Transaction::with_lock_time(vin, vout, lock_time)?;
+            if let Some(redeem_script) = redeem_script {
+                for vin in tx.vin.iter_mut() {
+                    vin.set_script_sig(Script::new().push_data(redeem_script.as_bytes()));
+                }
+            }
+            Ok(tx)
         };
 
-        tx.set_id()?;
+        // Measure what an input and the change output add to the size on
+        // stand-in transactions, so the selector knows the fee of each choice
+        let base = build(&[], Amount::ZERO)?;
+        let base_size = base.estimated_size()?;
+        let placeholder = Utxo { txid: base.get_id(), vout: 0, value: Amount::ZERO };
+        let params = SelectionParams {
+            target: amount,
+            fee_rate,
+            base_size,
+            input_size: build(&[placeholder], Amount::ZERO)?.estimated_size()? - base_size,
+            change_size: build(&[], Amount::MAX)?.estimated_size()? - base_size,
+        };
 
+        let selection = selector.select(&utxo_set.find_spendable_outputs(from_script)?, &params)?;
+        let tx = build(&selection.coins, selection.change)?;
+        if fee_for_size(fee_rate, tx.estimated_size()?)? > selection.fee {
+            return Err(format_err!("ERROR: The selected coins do not cover the fee"));
+        }
         Ok(tx)
     }
 
-    pub fn new_coinbase(to: String, mut data: String) -> Result<Transaction> {
-        if data == String::new() {
-            data += &format!("Reward to '{}'", to);
+    /// Builds the coinbase of the block at `height`, minting `reward` to
+    /// `to`. Its input carries the height, so coinbases of different blocks
+    /// never share a txid, followed by free-form `data` such as an extra
+    /// nonce or the genesis headline.
+    pub fn new_coinbase(to: String, height: usize, data: &[u8], reward: Amount, params: &ChainParams) -> Result<Transaction> {
+        let mut script = (height as u64).to_le_bytes().to_vec();
+        if data.is_empty() {
+            script.extend(format!("Reward to '{}'", to).as_bytes());
+        } else {
+            script.extend(data);
         }
 
-        let mut tx = Transaction {
-            id: String::new(),
-            vin: vec![],
-            vout: vec![TXOutput::new(100.0, to)]
-        };
+        if script.len() - COINBASE_HEIGHT_SIZE > MAX_COINBASE_DATA_SIZE {
+            return Err(format_err!("ERROR: Coinbase data is longer than {} bytes", MAX_COINBASE_DATA_SIZE));
+        }
 
-        tx.set_id()?;
-        Ok(tx)
+        Transaction::new(
+            vec![TXInput::new(String::new(), COINBASE_VOUT, Script::from_bytes(script))],
+            vec![TXOutput::new(reward, to, params)?]
+        )
     }
 
     pub fn get_id(&self) -> String {
@@ -110,32 +289,113 @@ impl Transaction {
     }
 
     pub fn is_coinbase(&self) -> bool {
-        self.vin.len() == 1 && self.get_ins()[0].get_txid() == String::new() && self.get_ins()[0].get_vout() == -1.0
+        self.vin.len() == 1 && self.vin[0].get_txid().is_empty() && self.vin[0].get_vout() == COINBASE_VOUT
+    }
+
+    /// Height committed to by a coinbase
+    pub fn coinbase_height(&self) -> Option<usize> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        let script = self.vin[0].script_sig.as_bytes();
+        let height = script.get(..COINBASE_HEIGHT_SIZE)?;
+        usize::try_from(u64::from_le_bytes(height.try_into().ok()?)).ok()
+    }
+
+    pub fn get_lock_time(&self) -> u64 {
+        self.lock_time
+    }
+
+    /// Whether the lock time allows the transaction in the block at `height`
+    /// whose previous blocks have the median time past `median_time`, in
+    /// milliseconds. Transactions whose inputs are all final ignore it.
+    pub fn is_final(&self, height: usize, median_time: u128) -> bool {
+        if self.lock_time == 0 || self.vin.iter().all(|vin| vin.get_sequence() == SEQUENCE_FINAL) {
+            return true;
+        }
+        if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time < height as u64
+        } else {
+            (self.lock_time as u128) * 1000 < median_time
+        }
+    }
+
+    /// Free-form data carried by a coinbase after its height
+    pub fn coinbase_data(&self) -> Option<&[u8]> {
+        if !self.is_coinbase() {
+            return None;
+        }
+        self.vin[0].script_sig.as_bytes().get(COINBASE_HEIGHT_SIZE..)
     }
 
+    /// Serialized size once every input carries all its signatures, the
+    /// size the fee of a transaction that is not fully signed is based on
+    pub fn estimated_size(&self) -> Result<usize> {
+        let missing: usize = self.vin.iter()
+            .map(|vin| match split_multisig_sig(vin.get_script_sig()) {
+                Some(_) => missing_signatures(vin.get_script_sig()) * (1 + MAX_SIGNATURE_SIZE),
+                None if vin.get_script_sig().is_empty() => MAX_SCRIPT_SIG_SIZE,
+                None => 0,
+            })
+            .sum();
+        Ok(bincode::serialized_size(self)? as usize + missing)
+    }
+
+    /// Number of signatures the inputs still lack: one for an unsigned
+    /// input, and what a multisig input needs to reach its threshold
+    pub fn missing_signatures(&self) -> usize {
+        if self.is_coinbase() {
+            return 0;
+        }
+        self.vin.iter().map(|vin| missing_signatures(vin.get_script_sig())).sum()
+    }
+
+    /// Hex encoding of the serialized transaction, to hand it over to
+    /// another signer
+    pub fn to_hex(&self) -> Result<String> {
+        Ok(hex::encode(bincode::serialize(self)?))
+    }
+
+    pub fn from_hex(data: &str) -> Result<Transaction> {
+        Ok(bincode::deserialize(&hex::decode(data.trim())?)?)
+    }
+
+    /// Total value of the outputs, failing if the sum overflows
+    pub fn value_out(&self) -> Result<Amount> {
+        Amount::checked_sum(self.vout.iter().map(|out| out.get_value()))
+    }
+
+    /// Sets the unlocking script of input `index`. Unlocking scripts are not
+    /// part of the txid.
+    pub fn set_script_sig(&mut self, index: usize, script_sig: Script) -> Result<()> {
+        let vin = self.vin.get_mut(index)
+            .ok_or_else(|| format_err!("ERROR: Transaction has no input {}", index))?;
+        vin.set_script_sig(script_sig);
+        Ok(())
+    }
+
+    /// Checks that the unlocking script of every input satisfies the locking
+    /// script of the output it spends. `prev_txs` must hold the transactions
+    /// being spent.
     pub fn verify(&mut self, prev_txs: HashMap<String, Transaction>) -> Result<bool> {
         if self.is_coinbase() {
             return Ok(true);
         }
 
         for vin in &self.get_ins() {
-            if prev_txs.get(&vin.get_txid()).is_none() {
+            if !prev_txs.contains_key(&vin.get_txid()) {
                 return Err(format_err!("ERROR: Previous transaction is not correct"));
-            
             }
         }
 
-        let mut tx_copy = self.trim_copy();
+        for (id, vin) in self.vin.iter().enumerate() {
+            let prev_tx = prev_txs.get(&vin.get_txid()).unwrap();
+            let prev_out = match prev_tx.vout.get(vin.get_vout() as usize) {
+                Some(out) => out,
+                None => return Ok(false),
+            };
 
-        for id in 0..self.get_ins().len() {
-            let prev_tx = prev_txs.get(&self.get_ins()[id].get_txid()).unwrap();
-            tx_copy.get_ins()[id].set_signature(vec![]);
-            tx_copy.get_ins()[id].set_pub_key(prev_tx.get_outs()[self.get_ins()[id].get_vout() as usize].get_script_pub_key().clone().into());
-
-            tx_copy.set_id()?;
-            tx_copy.get_ins()[id].set_pub_key(vec![]);
-
-            if !ed25519::verify(&tx_copy.hash(), &self.get_ins()[id].get_signature(), &self.get_ins()[id].get_pub_key()) {
+            if !self.verify_input(id, prev_out)? {
                 return Ok(false);
             }
         }
@@ -143,41 +403,124 @@ impl Transaction {
         Ok(true)
     }
 
+    /// Checks that the unlocking script of input `index` satisfies the
+    /// locking script of `prev_out`, the output it spends
+    pub fn verify_input(&self, index: usize, prev_out: &TXOutput) -> Result<bool> {
+        let vin = self.vin.get(index)
+            .ok_or_else(|| format_err!("ERROR: Transaction has no input {}", index))?;
+        let checker = InputChecker {
+            digest: self.signature_hash(index, prev_out)?,
+            lock_time: self.lock_time,
+            sequence: vin.get_sequence(),
+        };
+        Ok(script::verify_script(&vin.script_sig, &prev_out.script_pub_key, &checker).is_ok())
+    }
+
     pub fn set_id(&mut self) -> Result<()> {
-        let hash = self.hash();
-        self.id = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        self.id = self.compute_id();
         Ok(())
     }
 
+    /// Txid the contents of this transaction hash to, whatever its stored id
+    /// says. Unlocking scripts are left out as signing fills them in later,
+    /// except for a coinbase, whose unlocking script carries its height.
+    pub fn compute_id(&self) -> String {
+        let mut tx = if self.is_coinbase() { self.clone() } else { self.trim_copy() };
+        tx.id = String::new();
+        hex::encode(tx.hash())
+    }
+
+    /// Adds the signature of `private_key` to the inputs it can unlock: the
+    /// inputs spending a pay-to-pubkey-hash output of its key, and the
+    /// multisig inputs whose revealed redeem script lists its key and still
+    /// lacks signatures. Multisig signatures are kept in the order of their
+    /// keys. Returns the number of signatures added, and fails if no input
+    /// involves the key. `prev_txs` must hold the transactions being spent.
     pub fn sign(
         &mut self,
         private_key: &[u8],
         prev_txs: HashMap<String, Transaction>
-    ) -> Result<()> {
+    ) -> Result<usize> {
         if self.is_coinbase() {
-            return Ok(());
+            return Ok(0);
         }
 
         for vin in &self.get_ins() {
-            if prev_txs.get(&vin.get_txid()).is_none() {
+            if !prev_txs.contains_key(&vin.get_txid()) {
                 return Err(format_err!("ERROR: Previous transaction is not correct"));
             }
         }
 
-        let mut tx_copy = self.trim_copy();
+        let pub_key = derive_pub_key(private_key)?;
+        let mut pub_key_hash = pub_key.clone();
+        hash_pub_key(&mut pub_key_hash);
+
+        let mut involved = false;
+        let mut signed = 0;
+        for id in 0..self.vin.len() {
+            let prev_tx = prev_txs.get(&self.vin[id].get_txid()).unwrap();
+            let prev_out = prev_tx.vout.get(self.vin[id].get_vout() as usize)
+                .ok_or_else(|| format_err!("ERROR: Previous output does not exist"))?;
+            let digest = self.signature_hash(id, prev_out)?;
+
+            if prev_out.is_locked_with_key(&pub_key_hash) {
+                involved = true;
+                let signature = sign_digest(private_key, &digest)?;
+                self.vin[id].set_script_sig(Script::p2pkh_sig(&signature, &pub_key));
+                signed += 1;
+                continue;
+            }
 
-        for id in 0..tx_copy.get_ins().len() {
-            let prev_tx = prev_txs.get(&tx_copy.get_ins()[id].get_txid()).unwrap();
-            tx_copy.get_ins()[id].set_signature(vec![]);
-            tx_copy.get_ins()[id].set_pub_key(prev_tx.get_outs()[tx_copy.get_ins()[id].get_vout() as usize].get_script_pub_key().clone().into());
-            tx_copy.set_id()?;
-            tx_copy.get_ins()[id].set_pub_key(vec![]);
-            let signature = 
-                ed25519::signature(&tx_copy.hash(), private_key).to_vec();
-            self.get_ins()[id].set_signature(signature);
+            let script_hash = match prev_out.script_pub_key.get_script_hash() {
+                Some(script_hash) => script_hash,
+                None => continue,
+            };
+            let (redeem_script, sigs) = match split_multisig_sig(&self.vin[id].script_sig) {
+                Some(partial) => partial,
+                None => continue,
+            };
+            let mut redeem_hash = redeem_script.as_bytes().to_vec();
+            hash_pub_key(&mut redeem_hash);
+            let (m, keys) = redeem_script.parse_multisig().unwrap();
+            let key_index = match keys.iter().position(|key| *key == pub_key) {
+                Some(key_index) if redeem_hash == script_hash => key_index,
+                _ => continue,
+            };
+            involved = true;
+
+            // Keep the valid signatures, sorted by the index of their key
+            let mut by_key: Vec<(usize, Vec<u8>)> = sigs.into_iter()
+                .filter_map(|sig| keys.iter()
+                    .position(|key| Wallet::verify(key, &digest, &sig))
+                    .map(|index| (index, sig)))
+                .collect();
+            if by_key.len() >= m || by_key.iter().any(|(index, _)| *index == key_index) {
+                continue;
+            }
+            by_key.push((key_index, sign_digest(private_key, &digest)?));
+            by_key.sort_by_key(|(index, _)| *index);
+
+            let script_sig = by_key.iter()
+                .fold(Script::new(), |script, (_, sig)| script.push_data(sig))
+                .push_data(redeem_script.as_bytes());
+            self.vin[id].set_script_sig(script_sig);
+            signed += 1;
         }
 
-        Ok(())
+        if !involved {
+            return Err(format_err!("ERROR: No input spends an output of this key"));
+        }
+        Ok(signed)
+    }
+
+    /// Digest signed by input `index`: the transaction without unlocking
+    /// scripts, with the locking script of the spent output `prev_out` in
+    /// place of the unlocking script of that input
+    pub fn signature_hash(&self, index: usize, prev_out: &TXOutput) -> Result<Vec<u8>> {
+        let mut tx_copy = self.trim_copy();
+        tx_copy.set_script_sig(index, prev_out.script_pub_key.clone())?;
+        tx_copy.id = hex::encode(tx_copy.hash());
+        Ok(tx_copy.hash())
     }
 
     fn hash(&self) -> Vec<u8> {
@@ -189,38 +532,283 @@ impl Transaction {
 
     fn trim_copy(&self) -> Transaction {
         let mut ins = Vec::new();
-        let mut outs = Vec::new();
 
         for vin in &self.get_ins() {
-            ins.push(TXInput::new(
-                vin.get_txid().clone(),
-                vin.get_vout().clone(),
-                Vec::new(),
-                Vec::new())
-            );
-        }
-
-        for vout in &self.get_outs() {
-            outs.push(TXOutput::new(
-                vout.get_value(),
-                vout.get_script_pub_key().clone())
-            )
+            let mut input = vin.clone();
+            input.set_script_sig(Script::new());
+            ins.push(input);
         }
 
         Transaction {
             id: self.get_id(),
             vin: ins,
-            vout: outs
+            vout: self.vout.clone(),
+            lock_time: self.lock_time
+        }
+    }
+}
+
+/// Checks the signatures of one input against the digest it signs, and
+/// script lock times against the lock time of its transaction
+struct InputChecker {
+    digest: Vec<u8>,
+    lock_time: u64,
+    sequence: u32,
+}
+
+impl SignatureChecker for InputChecker {
+    fn check_sig(&self, signature: &[u8], pub_key: &[u8]) -> bool {
+        Wallet::verify(pub_key, &self.digest, signature)
+    }
+
+    /// The transaction lock time must be of the same kind and at least
+    /// `lock_time`, and enforced, which a final input would prevent
+    fn check_lock_time(&self, lock_time: i64) -> bool {
+        let lock_time = match u64::try_from(lock_time) {
+            Ok(lock_time) => lock_time,
+            Err(_) => return false,
+        };
+        (lock_time < LOCKTIME_THRESHOLD) == (self.lock_time < LOCKTIME_THRESHOLD)
+            && lock_time <= self.lock_time
+            && self.sequence != SEQUENCE_FINAL
+    }
+}
+
+/// Splits the unlocking script of a multisig input into the redeem script
+/// it reveals and the signatures pushed before it
+pub(crate) fn split_multisig_sig(script_sig: &Script) -> Option<(Script, Vec<Vec<u8>>)> {
+    let mut pushes = script_sig.instructions().ok()?.into_iter()
+        .map(|instruction| match instruction {
+            Instruction::Push(data) => Some(data.to_vec()),
+            Instruction::Op(_) => None,
+        })
+        .collect::<Option<Vec<Vec<u8>>>>()?;
+
+    let redeem_script = Script::from_bytes(pushes.pop()?);
+    redeem_script.parse_multisig()?;
+    Some((redeem_script, pushes))
+}
+
+fn missing_signatures(script_sig: &Script) -> usize {
+    match split_multisig_sig(script_sig) {
+        Some((redeem_script, sigs)) => {
+            let (m, _) = redeem_script.parse_multisig().unwrap();
+            m.saturating_sub(sigs.len())
         }
+        None if script_sig.is_empty() => 1,
+        None => 0,
     }
 }
 
+/// Fee paid by a transaction of `size` bytes at `fee_rate` base units per byte
+pub fn fee_for_size(fee_rate: u64, size: usize) -> Result<Amount> {
+    fee_rate.checked_mul(size as u64)
+        .map(Amount::from_base_units)
+        .ok_or_else(|| format_err!("ERROR: Fee overflow"))
+}
+
 pub fn hash_pub_key(pub_key: &mut Vec<u8>) {
     let mut hasher = Sha256::new();
-    hasher.update(pub_key);
-    *pub_key = hasher.finalize().to_vec();
+    hasher.update(&pub_key[..]);
+    let sha = hasher.finalize();
     let mut hasher_ripemd = ripemd160::Ripemd160::new();
-    hasher_ripemd.input(pub_key);
+    hasher_ripemd.input(&sha);
     pub_key.resize(20, 0);
-    hasher_ripemd.result(&mut pub_key);
-}
\ No newline at end of file
+    hasher_ripemd.result(pub_key);
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coinselect::LargestFirst;
+    use crate::testutil::{new_address, temporary_db, test_chain, test_params, test_wallets};
+    use crate::wallet::{decode_address, decode_destination, new_wallet};
+
+    #[test]
+    fn test_coinbase() {
+        let params = ChainParams::regtest();
+        let reward = Amount::from_coins(100);
+        let alice = new_address();
+        let first = Transaction::new_coinbase(alice.clone(), 1, b"hello", reward, &params).unwrap();
+        let second = Transaction::new_coinbase(alice.clone(), 2, b"hello", reward, &params).unwrap();
+
+        assert!(first.is_coinbase());
+        assert_ne!(first.get_id(), second.get_id());
+        assert_eq!(first.coinbase_height(), Some(1));
+        assert_eq!(first.coinbase_data(), Some(&b"hello"[..]));
+        assert!(first.clone().verify(HashMap::new()).unwrap());
+
+        let default = Transaction::new_coinbase(alice.clone(), 3, &[], reward, &params).unwrap();
+        assert_eq!(default.coinbase_data(), Some(format!("Reward to '{}'", alice).as_bytes()));
+        assert!(Transaction::new_coinbase(alice.clone(), 3, &[0; 101], reward, &params).is_err());
+
+        let spend = Transaction::new(
+            vec![TXInput::new(first.get_id(), 0, Script::new())],
+            vec![TXOutput::new(reward, alice.clone(), &params).unwrap()]).unwrap();
+        assert!(!spend.is_coinbase());
+        assert_eq!(spend.coinbase_height(), None);
+    }
+
+    #[test]
+    fn test_new_utxo_fee() {
+        let params = test_params();
+        let mut wallets = test_wallets(&params);
+        let from = wallets.create_wallet().unwrap();
+        let recipient = new_wallet();
+        let to = recipient.get_address(params.address_version);
+        let reward = params.subsidy(0);
+        let mut bc = test_chain(params, &from);
+        let utxo_set = UTXOSet::new(&bc);
+
+        let amount = Amount::from_coins(10);
+        let spend = |wallets: &mut Wallets, to: &str, amount: Amount, fee_rate: u64| {
+            Transaction::new_utxo(wallets, &from, to, amount, fee_rate, 0, &LargestFirst, &utxo_set)
+        };
+        let tx = spend(&mut wallets, &to, amount, 10).unwrap();
+        let fee = reward.checked_sub(tx.value_out().unwrap()).unwrap();
+        // The fee was set before signing, with room for a signature of the
+        // largest size, so it covers the signed transaction
+        let size = tx.estimated_size().unwrap();
+        assert!(fee >= fee_for_size(10, size).unwrap());
+        assert!(fee <= fee_for_size(10, size + MAX_SCRIPT_SIG_SIZE).unwrap());
+        assert!(bc.verify_transaction(&mut tx.clone()).unwrap());
+        assert_eq!(tx.get_outs()[0].get_value(), amount);
+
+        // Outputs are locked to the hash of the recipient key, which need
+        // not be one of our wallets
+        let to_hash = decode_address(&to, bc.params().address_version).unwrap();
+        assert!(tx.get_outs()[0].is_locked_with_key(&to_hash));
+        assert_eq!(tx.get_outs()[0].get_script_pub_key(), &Script::p2pkh(&to_hash));
+
+        // Malformed addresses and addresses of another network are rejected
+        let mainnet = recipient.get_address(ChainParams::main().address_version);
+        assert!(spend(&mut wallets, &mainnet, amount, 10).is_err());
+        assert!(spend(&mut wallets, "Bob", amount, 10).is_err());
+        assert!(TXOutput::new(amount, "Bob".to_string(), bc.params()).is_err());
+        assert!(TXOutput::new(amount, mainnet.clone(), bc.params()).is_err());
+
+        // Payments below the dust threshold are refused
+        assert!(spend(&mut wallets, &to, Amount::from_base_units(545), 10).is_err());
+
+        // The fee has to fit in the inputs as well
+        assert!(spend(&mut wallets, &to, reward, 10).is_err());
+        assert!(spend(&mut wallets, &to, reward, 0).is_ok());
+
+        // The change went to a fresh internal address, and only the spend
+        // that had change added one
+        let change: Vec<String> = wallets.get_addresses().into_iter()
+            .filter(|address| wallets.is_change(address).unwrap())
+            .collect();
+        assert_eq!(change.len(), 1);
+        assert_eq!(wallets.get_addresses().len(), 2);
+        let change_hash = decode_address(&change[0], bc.params().address_version).unwrap();
+        assert_eq!(tx.get_outs()[1].get_script_pub_key(), &Script::p2pkh(&change_hash));
+        assert!(!wallets.is_change(&from).unwrap());
+
+        // The wallet balance counts the change along with the other addresses
+        let change_value = tx.get_outs()[1].get_value();
+        let coinbase = Transaction::new_coinbase(to, 1, &[], bc.params().subsidy(1), bc.params()).unwrap();
+        bc.add_block(vec![coinbase, tx]).unwrap();
+        let (balance, immature) = wallets.get_balance(&UTXOSet::new(&bc)).unwrap();
+        assert_eq!(balance, change_value);
+        assert_eq!(immature, Amount::ZERO);
+    }
+
+    #[test]
+    fn test_unsigned_utxo_change() {
+        let params = test_params();
+        let db = temporary_db();
+        let legacy = new_wallet();
+        let from = legacy.get_address(params.address_version);
+        db.insert(from.as_bytes(), bincode::serialize(&legacy).unwrap()).unwrap();
+        let mut wallets = Wallets::new(db, &params).unwrap();
+        let to = new_address();
+        let bc = test_chain(params, &from);
+        let utxo_set = UTXOSet::new(&bc);
+        let amount = Amount::from_coins(10);
+
+        // Wallets without a passphrase cannot store a change key, so their
+        // change goes back to `from`
+        let from_script = decode_destination(&from, bc.params()).unwrap();
+        let tx = Transaction::new_utxo(&mut wallets, &from, &to, amount, 10, 0, &LargestFirst, &utxo_set).unwrap();
+        assert_eq!(tx.get_outs()[1].get_script_pub_key(), &from_script);
+        assert!(bc.verify_transaction(&mut tx.clone()).unwrap());
+        assert_eq!(wallets.get_addresses(), vec![from.clone()]);
+
+        // A locked wallet only needs its keys once the spend has change
+        wallets.set_passphrase("secret").unwrap();
+        wallets.lock().unwrap();
+        let reward = bc.params().subsidy(0);
+        let exact = Transaction::new_unsigned_utxo(&mut wallets, &from, &to, reward, 0, 0, &LargestFirst, &utxo_set)
+            .unwrap();
+        assert_eq!(exact.get_outs().len(), 1);
+        assert!(Transaction::new_unsigned_utxo(&mut wallets, &from, &to, amount, 10, 0, &LargestFirst, &utxo_set)
+            .is_err());
+        assert_eq!(wallets.get_addresses(), vec![from.clone()]);
+
+        // Unsigned spends, as `createpsbt` makes, get a fresh change address
+        // with the fee of the signed transaction
+        wallets.unlock("secret").unwrap();
+        let tx = Transaction::new_unsigned_utxo(&mut wallets, &from, &to, amount, 10, 0, &LargestFirst, &utxo_set)
+            .unwrap();
+        assert_eq!(tx.missing_signatures(), 1);
+        assert_eq!(tx.get_id(), tx.compute_id());
+        let fee = reward.checked_sub(tx.value_out().unwrap()).unwrap();
+        assert!(fee >= fee_for_size(10, tx.estimated_size().unwrap()).unwrap());
+        let change = wallets.get_addresses().into_iter().find(|address| *address != from).unwrap();
+        assert!(wallets.is_change(&change).unwrap());
+        assert_eq!(tx.get_outs()[1].get_script_pub_key(), &decode_destination(&change, bc.params()).unwrap());
+    }
+
+    #[test]
+    fn test_multisig() {
+        let params = test_params();
+        let [mut funder, mut first, mut second] = [(); 3].map(|_| test_wallets(&params));
+        let from = funder.create_wallet().unwrap();
+        let first_address = first.create_wallet().unwrap();
+        let first_key = first.get_wallet(&first_address).unwrap();
+        let second_address = second.create_wallet().unwrap();
+        let second_key = second.get_wallet(&second_address).unwrap();
+        let third_key = new_wallet();
+        let mut bc = test_chain(params.clone(), &from);
+
+        // A 2-of-3 address known to the first holder only
+        let keys = vec![first_key.public_key.clone(), second_key.public_key.clone(), third_key.public_key.clone()];
+        let redeem_script = Script::multisig(2, &keys).unwrap();
+        let multisig = first.add_multisig(&redeem_script, params.script_address_version).unwrap();
+        assert_eq!(first.get_multisig(&multisig).unwrap(), Some(redeem_script));
+        assert!(decode_address(&multisig, params.address_version).is_err());
+
+        let funding = Transaction::new_utxo(&mut funder, &from, &multisig, Amount::from_coins(50), 10, 0,
+            &LargestFirst, &UTXOSet::new(&bc)).unwrap();
+        bc.add_block(vec![Transaction::new_coinbase(from.clone(), 1, &[], params.subsidy(1), &params).unwrap(), funding])
+            .unwrap();
+        let locked = decode_destination(&multisig, &params).unwrap();
+        assert!(locked.get_script_hash().is_some());
+        assert_eq!(TXOutput::new(Amount::from_coins(1), multisig.clone(), &params).unwrap().get_script_pub_key(),
+            &locked);
+        assert_eq!(UTXOSet::new(&bc).get_balance(&locked).unwrap().0, Amount::from_coins(50));
+
+        // One signature is not enough
+        let to = third_key.get_address(params.address_version);
+        let mut tx = Transaction::new_utxo(&mut first, &multisig, &to, Amount::from_coins(20), 10, 0,
+            &LargestFirst, &UTXOSet::new(&bc)).unwrap();
+        assert_eq!(tx.missing_signatures(), 1);
+        assert!(!bc.verify_transaction(&mut tx.clone()).unwrap());
+        assert_eq!(bc.sign_transaction(&mut tx, &first_key.private_key).unwrap(), 0);
+        assert!(bc.sign_transaction(&mut tx, &new_wallet().private_key).is_err());
+
+        // The second holder completes it, after which nobody else adds one
+        assert_eq!(bc.sign_transaction(&mut tx, &second_key.private_key).unwrap(), 1);
+        assert_eq!(tx.missing_signatures(), 0);
+        assert_eq!(bc.sign_transaction(&mut tx, &third_key.private_key).unwrap(), 0);
+        let size = tx.estimated_size().unwrap();
+        let fee = Amount::from_coins(50).checked_sub(tx.value_out().unwrap()).unwrap();
+        assert!(fee >= fee_for_size(10, size).unwrap());
+
+        let tx = Transaction::from_hex(&tx.to_hex().unwrap()).unwrap();
+        assert!(bc.verify_transaction(&mut tx.clone()).unwrap());
+        bc.add_block(vec![Transaction::new_coinbase(from, 2, &[], params.subsidy(2), &params).unwrap(), tx]).unwrap();
+        let change = UTXOSet::new(&bc).get_balance(&locked).unwrap().0;
+        assert_eq!(change, Amount::from_coins(30).checked_sub(fee).unwrap());
+    }
+}
@@ -1,78 +1,124 @@
 use std::collections::HashMap;
-use crate::errors::Result;
 use serde::{Serialize, Deserialize};
+use crate::amount::Amount;
+use crate::errors::Result;
+use crate::script::Script;
+use crate::transaction::{
+    RelativeLock, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_GRANULARITY,
+    SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG
+};
+use crate::chainparams::ChainParams;
+use crate::wallet::decode_destination;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXInput {
     txid: String,
-    vout: f32,
-    pub signature: Vec<u8>,
-    pub pub_key: Vec<u8>,
+    vout: u32,
+    pub script_sig: Script,
+    sequence: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TXOutput {
-    pub value: f32,
-    pub pub_key_hash: Vec<u8>,
+    pub value: Amount,
+    pub script_pub_key: Script,
+}
+
+// Unspent outputs of a single transaction, keyed by their index in `vout`,
+// with the height and timestamp of the block that created them
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TXOutputs {
+    pub outputs: HashMap<u32, TXOutput>,
+    pub height: usize,
+    pub time: u128,
+    pub is_coinbase: bool,
 }
 
 impl TXInput {
-    pub fn new(txid: String, vout: f32, signature: Vec<u8>, pub_key: Vec<u8>) -> TXInput {
+    /// Input spending output `vout` of `txid`, with a final sequence
+    pub fn new(txid: String, vout: u32, script_sig: Script) -> TXInput {
         TXInput {
             txid,
             vout,
-            signature,
-            pub_key
+            script_sig,
+            sequence: SEQUENCE_FINAL
         }
     }
 
-    pub fn can_be_unlocked_with(&self, unlocking_data: String) -> bool {
-        // Compare the 
-        self.pub_key == unlocking_data.as_bytes()
-    }
-
     pub fn get_txid(&self) -> String {
         self.txid.clone()
     }
 
-    pub fn get_vout(&self) -> f32 {
+    pub fn get_vout(&self) -> u32 {
         self.vout
     }
 
-    pub fn get_signature(&self) -> Vec<u8> {
-        self.signature.clone()
+    pub fn get_script_sig(&self) -> &Script {
+        &self.script_sig
     }
 
-    pub fn get_pub_key(&self) -> Vec<u8> {
-        self.pub_key.clone()
+    pub fn set_script_sig(&mut self, script_sig: Script) {
+        self.script_sig = script_sig;
     }
 
-    pub fn set_signature(&mut self, signature: Vec<u8>) {
-        self.signature = signature;
+    pub fn get_sequence(&self) -> u32 {
+        self.sequence
     }
 
-    pub fn set_pub_key(&mut self, pub_key: Vec<u8>) {
-        self.pub_key = pub_key;
+    pub fn set_sequence(&mut self, sequence: u32) {
+        self.sequence = sequence;
+    }
+
+    /// Relative lock encoded in the sequence, if it has one
+    pub fn relative_lock(&self) -> Option<RelativeLock> {
+        if self.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = self.sequence & SEQUENCE_LOCKTIME_MASK;
+        if self.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            Some(RelativeLock::Time(((value as u128) << SEQUENCE_LOCKTIME_GRANULARITY) * 1000))
+        } else {
+            Some(RelativeLock::Blocks(value as usize))
+        }
     }
 }
 
 impl TXOutput {
-    pub fn new(value: f32, pub_key_hash: String) -> TXOutput {
+    /// Output of `value` paying to `address`, a key or script address of the
+    /// network of `params`
+    pub fn new(value: Amount, address: String, params: &ChainParams) -> Result<TXOutput> {
+        let mut txo = TXOutput {
+            value,
+            script_pub_key: Script::new()
+        };
+        txo.lock(&address, params)?;
+        Ok(txo)
+    }
+
+    /// Output of `value` locked by an arbitrary script
+    pub fn with_script(value: Amount, script_pub_key: Script) -> TXOutput {
         TXOutput {
             value,
-            pub_key_hash: pub_key_hash.as_bytes().to_vec()
+            script_pub_key
         }
     }
 
-    pub fn can_be_unlocked_with(&self, unlocking_data: String) -> bool {
-        self.pub_key_hash == unlocking_data.as_bytes()
+    fn lock(&mut self, address: &str, params: &ChainParams) -> Result<()> {
+        self.script_pub_key = decode_destination(address, params)?;
+        Ok(())
+    }
+
+    /// Whether the output is a pay-to-pubkey-hash output paying to the
+    /// public key hashing to `pub_key_hash`
+    pub fn is_locked_with_key(&self, pub_key_hash: &[u8]) -> bool {
+        self.script_pub_key.get_pub_key_hash() == Some(pub_key_hash)
     }
 
-    pub fn get_value(&self) -> f32 {
+    pub fn get_value(&self) -> Amount {
         self.value
     }
 
-    pub fn get_script_pub_key(&self) -> String {
-        String::from_utf8(self.pub_key_hash.clone()).unwrap()
+    pub fn get_script_pub_key(&self) -> &Script {
+        &self.script_pub_key
     }
 }
@@ -0,0 +1,261 @@
+use std::collections::{HashMap, HashSet};
+
+use bincode::{deserialize, serialize};
+use failure::{format_err, Error};
+use log::info;
+use serde::{Serialize, Deserialize};
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree};
+
+use crate::amount::Amount;
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::coinselect::Utxo;
+use crate::errors::Result;
+use crate::script::Script;
+use crate::tx::{TXOutput, TXOutputs};
+
+pub const UTXO_TREE: &str = "utxos";
+
+/// An output spent by a block, kept so the block can be disconnected again
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SpentOutput {
+    pub txid: String,
+    pub vout: u32,
+    pub output: TXOutput,
+    pub height: usize,
+    pub time: u128,
+    pub is_coinbase: bool,
+}
+
+/// An unspent output along with where it comes from
+#[derive(Debug, Clone)]
+pub struct Coin {
+    pub output: TXOutput,
+    /// Height of the block that created the output
+    pub height: usize,
+    /// Timestamp of the block that created the output
+    pub time: u128,
+    pub is_coinbase: bool,
+}
+
+/// Index of the unspent transaction outputs of a `Blockchain`.
+///
+/// The index lives in its own sled tree next to the blocks and maps a txid to
+/// the outputs of that transaction that are still unspent, so balance lookups
+/// and coin selection don't have to walk the chain back to genesis.
+pub struct UTXOSet<'a> {
+    bc: &'a Blockchain,
+}
+
+impl<'a> UTXOSet<'a> {
+    pub fn new(bc: &'a Blockchain) -> UTXOSet<'a> {
+        UTXOSet { bc }
+    }
+
+    /// Rebuilds the index from scratch by walking the whole chain
+    pub fn reindex(&self) -> Result<()> {
+        info!("Reindexing the UTXO set");
+        let tree = self.bc.utxo_tree()?;
+        tree.clear()?;
+
+        for (txid, outs) in self.bc.find_utxo() {
+            tree.insert(txid.as_bytes(), serialize(&outs)?)?;
+        }
+
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Outputs locked by `script_pub_key` that can be spent in the next
+    /// block, which leaves out coinbase outputs that are not mature yet.
+    /// They are ordered by txid and index so coin selection is repeatable.
+    pub fn find_spendable_outputs(&self, script_pub_key: &Script) -> Result<Vec<Utxo>> {
+        let mut utxos = Vec::new();
+        let spend_height = self.bc.get_best_height()? + 1;
+
+        for entry in self.bc.utxo_tree()?.iter() {
+            let (txid, outs) = entry?;
+            let txid = String::from_utf8(txid.to_vec())?;
+            let outs: TXOutputs = deserialize(&outs)?;
+            if !self.bc.params().is_mature(outs.is_coinbase, outs.height, spend_height) {
+                continue;
+            }
+
+            for (vout, out) in outs.outputs {
+                if out.get_script_pub_key() == script_pub_key {
+                    utxos.push(Utxo { txid: txid.clone(), vout, value: out.get_value() });
+                }
+            }
+        }
+
+        utxos.sort_by(|a, b| (&a.txid, a.vout).cmp(&(&b.txid, b.vout)));
+        Ok(utxos)
+    }
+
+    /// Returns every unspent output locked by `script_pub_key`
+    pub fn find_utxo(&self, script_pub_key: &Script) -> Result<Vec<TXOutput>> {
+        let mut utxos = Vec::new();
+
+        for entry in self.bc.utxo_tree()?.iter() {
+            let (_, outs) = entry?;
+            let outs: TXOutputs = deserialize(&outs)?;
+
+            for out in outs.outputs.into_values() {
+                if out.get_script_pub_key() == script_pub_key {
+                    utxos.push(out);
+                }
+            }
+        }
+
+        Ok(utxos)
+    }
+
+    /// Balance of the outputs locked by `script_pub_key`, as the spendable
+    /// part and the part locked in coinbase outputs that are not mature yet
+    pub fn get_balance(&self, script_pub_key: &Script) -> Result<(Amount, Amount)> {
+        let spend_height = self.bc.get_best_height()? + 1;
+        let mut mature = Amount::ZERO;
+        let mut immature = Amount::ZERO;
+
+        for entry in self.bc.utxo_tree()?.iter() {
+            let (_, outs) = entry?;
+            let outs: TXOutputs = deserialize(&outs)?;
+            let is_mature = self.bc.params().is_mature(outs.is_coinbase, outs.height, spend_height);
+
+            for out in outs.outputs.into_values() {
+                if out.get_script_pub_key() == script_pub_key {
+                    let total = if is_mature { &mut mature } else { &mut immature };
+                    *total = total.checked_add(out.get_value())
+                        .ok_or_else(|| format_err!("ERROR: Amount overflow"))?;
+                }
+            }
+        }
+
+        Ok((mature, immature))
+    }
+
+    /// Output `vout` of transaction `txid`, if it is still unspent
+    pub fn get_output(&self, txid: &str, vout: u32) -> Result<Option<TXOutput>> {
+        Ok(self.get_coin(txid, vout)?.map(|coin| coin.output))
+    }
+
+    /// Like `get_output`, along with the height, time and kind of the
+    /// transaction that created the output
+    pub fn get_coin(&self, txid: &str, vout: u32) -> Result<Option<Coin>> {
+        match self.bc.utxo_tree()?.get(txid.as_bytes())? {
+            Some(outs) => {
+                let mut outs: TXOutputs = deserialize(&outs)?;
+                Ok(outs.outputs.remove(&vout).map(|output| Coin {
+                    output,
+                    height: outs.height,
+                    time: outs.time,
+                    is_coinbase: outs.is_coinbase,
+                }))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Whether transaction `txid` still has unspent outputs
+    pub fn has_unspent(&self, txid: &str) -> Result<bool> {
+        Ok(self.bc.utxo_tree()?.contains_key(txid.as_bytes())?)
+    }
+
+    pub fn blockchain(&self) -> &Blockchain {
+        self.bc
+    }
+
+    /// Number of transactions that still have unspent outputs
+    pub fn count_transactions(&self) -> Result<usize> {
+        Ok(self.bc.utxo_tree()?.len())
+    }
+
+    /// Applies `block` to the index from inside a sled transaction: the
+    /// outputs its inputs spend are removed and its own outputs are added.
+    /// Returns the spent outputs, which is the undo data of the block.
+    pub(crate) fn update(
+        utxos: &TransactionalTree,
+        block: &Block
+    ) -> ConflictableTransactionResult<Vec<SpentOutput>, Error> {
+        let mut spent = Vec::new();
+
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                for vin in tx.get_ins() {
+                    let txid = vin.get_txid();
+                    let encoded = utxos.get(txid.as_bytes())?.ok_or_else(|| {
+                        ConflictableTransactionError::Abort(
+                            format_err!("Output {}:{} is not in the UTXO set", txid, vin.get_vout()))
+                    })?;
+                    let mut outs: TXOutputs = deserialize(&encoded).map_err(abort)?;
+
+                    let output = outs.outputs.remove(&vin.get_vout()).ok_or_else(|| {
+                        ConflictableTransactionError::Abort(
+                            format_err!("Output {}:{} is already spent", txid, vin.get_vout()))
+                    })?;
+                    spent.push(SpentOutput {
+                        txid: txid.clone(),
+                        vout: vin.get_vout(),
+                        output,
+                        height: outs.height,
+                        time: outs.time,
+                        is_coinbase: outs.is_coinbase,
+                    });
+
+                    if outs.outputs.is_empty() {
+                        utxos.remove(txid.as_bytes())?;
+                    } else {
+                        utxos.insert(txid.as_bytes(), serialize(&outs).map_err(abort)?)?;
+                    }
+                }
+            }
+
+            let outs = TXOutputs {
+                outputs: (0..).zip(tx.get_outs()).collect(),
+                height: block.get_height(),
+                time: block.get_timestamp(),
+                is_coinbase: tx.is_coinbase(),
+            };
+            if utxos.insert(tx.get_id().as_bytes(), serialize(&outs).map_err(abort)?)?.is_some() {
+                return Err(ConflictableTransactionError::Abort(
+                    format_err!("Transaction {} still has unspent outputs", tx.get_id())));
+            }
+        }
+
+        Ok(spent)
+    }
+
+    /// Undoes `update` for `block`: its outputs are removed and the outputs
+    /// it spent, as recorded in `spent`, are restored.
+    pub(crate) fn revert(
+        utxos: &TransactionalTree,
+        block: &Block,
+        spent: &[SpentOutput]
+    ) -> ConflictableTransactionResult<(), Error> {
+        let created: HashSet<String> = block.get_transactions().iter().map(|tx| tx.get_id()).collect();
+        for txid in &created {
+            utxos.remove(txid.as_bytes())?;
+        }
+
+        // Outputs created and spent inside the block are gone with it
+        for spent in spent.iter().filter(|spent| !created.contains(&spent.txid)) {
+            let mut outs: TXOutputs = match utxos.get(spent.txid.as_bytes())? {
+                Some(encoded) => deserialize(&encoded).map_err(abort)?,
+                None => TXOutputs {
+                    outputs: HashMap::new(),
+                    height: spent.height,
+                    time: spent.time,
+                    is_coinbase: spent.is_coinbase,
+                },
+            };
+            outs.outputs.insert(spent.vout, spent.output.clone());
+            utxos.insert(spent.txid.as_bytes(), serialize(&outs).map_err(abort)?)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn abort<E: Into<Error>>(e: E) -> ConflictableTransactionError<Error> {
+    ConflictableTransactionError::Abort(e.into())
+}
@@ -0,0 +1,499 @@
+use std::collections::{HashMap, HashSet};
+
+use failure::format_err;
+use crate::amount::Amount;
+use crate::block::{self, Block, BlockHeader};
+use crate::blockchain::Blockchain;
+use crate::chainparams::ChainParams;
+use crate::errors::Result;
+use crate::transaction::{RelativeLock, Transaction, MAX_COINBASE_DATA_SIZE};
+use crate::utxoset::{Coin, UTXOSet};
+
+/// How far ahead of the local clock a block timestamp may be, in milliseconds
+pub const MAX_FUTURE_BLOCK_TIME_MS: u128 = 2 * 60 * 60 * 1000;
+
+/// Number of previous blocks whose median timestamp a new block must exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Source of the coins the transactions of a block may spend
+pub trait CoinView {
+    /// Output `vout` of `txid`, if it exists and is unspent
+    fn unspent_output(&self, txid: &str, vout: u32) -> Result<Option<Coin>>;
+
+    /// Whether `txid` has any unspent output
+    fn has_unspent(&self, txid: &str) -> Result<bool>;
+}
+
+impl CoinView for UTXOSet<'_> {
+    fn unspent_output(&self, txid: &str, vout: u32) -> Result<Option<Coin>> {
+        self.get_coin(txid, vout)
+    }
+
+    fn has_unspent(&self, txid: &str) -> Result<bool> {
+        UTXOSet::has_unspent(self, txid)
+    }
+}
+
+/// Coins rebuilt in memory while replaying the chain from genesis
+#[derive(Debug, Default)]
+pub struct MemoryCoinView {
+    utxos: HashMap<String, HashMap<u32, Coin>>,
+}
+
+impl MemoryCoinView {
+    pub fn apply_block(&mut self, block: &Block) {
+        for tx in block.get_transactions() {
+            if !tx.is_coinbase() {
+                for vin in tx.get_ins() {
+                    if let Some(outs) = self.utxos.get_mut(&vin.get_txid()) {
+                        outs.remove(&vin.get_vout());
+                        if outs.is_empty() {
+                            self.utxos.remove(&vin.get_txid());
+                        }
+                    }
+                }
+            }
+
+            let outs = (0..).zip(tx.get_outs()).map(|(vout, output)| (vout, Coin {
+                output,
+                height: block.get_height(),
+                time: block.get_timestamp(),
+                is_coinbase: tx.is_coinbase(),
+            }));
+            self.utxos.insert(tx.get_id(), outs.collect());
+        }
+    }
+}
+
+impl CoinView for MemoryCoinView {
+    fn unspent_output(&self, txid: &str, vout: u32) -> Result<Option<Coin>> {
+        Ok(self.utxos.get(txid).and_then(|outs| outs.get(&vout)).cloned())
+    }
+
+    fn has_unspent(&self, txid: &str) -> Result<bool> {
+        Ok(self.utxos.contains_key(txid))
+    }
+}
+
+/// Checks the header of `block` against `prev`, the header of the block it
+/// extends, or against the genesis rules if there is none: proof of work,
+/// difficulty, prev hash linkage, height and timestamp
+pub fn check_header(bc: &Blockchain, block: &Block, prev: Option<&BlockHeader>) -> Result<()> {
+    match prev {
+        Some(prev) => {
+            if block.get_prev_hash() != prev.hash()? {
+                return Err(format_err!("does not link to the previous block"));
+            }
+            if block.get_height() != prev.get_height() + 1 {
+                return Err(format_err!(
+                    "has height {}, expected {}", block.get_height(), prev.get_height() + 1));
+            }
+
+            bc.check_proof_of_work(block)?;
+
+            let median = median_time_past(bc, prev)?;
+            if block.get_timestamp() <= median {
+                return Err(format_err!(
+                    "has timestamp {} not after the median time {}", block.get_timestamp(), median));
+            }
+        }
+        None => {
+            if block.get_height() != 0 {
+                return Err(format_err!("genesis block has height {}", block.get_height()));
+            }
+            if block.get_bits() != bc.params().pow_limit_bits || !block.validate()? {
+                return Err(format_err!("genesis block has an invalid merkle root or proof of work"));
+            }
+        }
+    }
+
+    if block.get_timestamp() > block::now() + MAX_FUTURE_BLOCK_TIME_MS {
+        return Err(format_err!("has a timestamp too far in the future"));
+    }
+
+    Ok(())
+}
+
+/// Median timestamp of `prev` and the blocks before it
+pub fn median_time_past(bc: &Blockchain, prev: &BlockHeader) -> Result<u128> {
+    let mut timestamps = vec![prev.get_timestamp()];
+    let mut header = prev.clone();
+
+    while timestamps.len() < MEDIAN_TIME_SPAN && header.get_height() > 0 {
+        header = bc.get_header(&header.get_prev_hash())?;
+        timestamps.push(header.get_timestamp());
+    }
+
+    timestamps.sort_unstable();
+    Ok(timestamps[timestamps.len() / 2])
+}
+
+/// Checks a non-coinbase transaction to be included at `spend_height` against
+/// the coins in `view`: its txid is the hash of its contents, it has inputs,
+/// each spends an existing unspent and mature output with a valid signature
+/// and it does not create value. Returns its fee, the value of the inputs it
+/// leaves unclaimed.
+pub fn check_transaction(
+    tx: &Transaction,
+    view: &impl CoinView,
+    params: &ChainParams,
+    spend_height: usize
+) -> Result<Amount> {
+    let txid = tx.get_id();
+    if tx.compute_id() != txid {
+        return Err(format_err!("transaction {} does not hash to its txid", txid));
+    }
+    if tx.is_coinbase() {
+        return Err(format_err!("transaction {} is a coinbase", txid));
+    }
+    if tx.get_ins().is_empty() {
+        return Err(format_err!("transaction {} has no inputs", txid));
+    }
+
+    let mut outpoints = HashSet::new();
+    let mut prev_outs = Vec::new();
+    let mut value_in = Amount::ZERO;
+
+    for vin in tx.get_ins() {
+        if !outpoints.insert((vin.get_txid(), vin.get_vout())) {
+            return Err(format_err!(
+                "transaction {} spends output {}:{} twice", txid, vin.get_txid(), vin.get_vout()));
+        }
+
+        let coin = view.unspent_output(&vin.get_txid(), vin.get_vout())?
+            .ok_or_else(|| format_err!(
+                "transaction {} spends missing or spent output {}:{}", txid, vin.get_txid(), vin.get_vout()))?;
+        if !params.is_mature(coin.is_coinbase, coin.height, spend_height) {
+            return Err(format_err!(
+                "transaction {} spends coinbase output {}:{} created at height {} before it matures",
+                txid, vin.get_txid(), vin.get_vout(), coin.height));
+        }
+        value_in = value_in.checked_add(coin.output.get_value())
+            .ok_or_else(|| format_err!("transaction {} inputs overflow", txid))?;
+        prev_outs.push(coin.output);
+    }
+
+    let fee = value_in.checked_sub(tx.value_out()?)
+        .ok_or_else(|| format_err!("transaction {} spends more than its inputs", txid))?;
+
+    for (index, prev_out) in prev_outs.iter().enumerate() {
+        if !tx.verify_input(index, prev_out)? {
+            return Err(format_err!("transaction {} has an invalid signature", txid));
+        }
+    }
+
+    Ok(fee)
+}
+
+/// Checks that the lock times of `tx` allow it in the block at
+/// `spend_height`, whose previous blocks have the median time past
+/// `median_time`: its lock time has passed and every input spends an output
+/// old enough for its relative lock. The other checks are left to
+/// `check_transaction`, so a transaction failing only these can wait.
+pub fn check_lock_times(
+    tx: &Transaction,
+    view: &impl CoinView,
+    spend_height: usize,
+    median_time: u128
+) -> Result<()> {
+    let txid = tx.get_id();
+    if !tx.is_final(spend_height, median_time) {
+        return Err(format_err!("transaction {} is locked until {}", txid, tx.get_lock_time()));
+    }
+    if tx.is_coinbase() {
+        return Ok(());
+    }
+
+    for vin in tx.get_ins() {
+        let lock = match vin.relative_lock() {
+            Some(lock) => lock,
+            None => continue,
+        };
+        let coin = view.unspent_output(&vin.get_txid(), vin.get_vout())?
+            .ok_or_else(|| format_err!(
+                "transaction {} spends missing or spent output {}:{}", txid, vin.get_txid(), vin.get_vout()))?;
+        let unlocked = match lock {
+            RelativeLock::Blocks(blocks) => spend_height >= coin.height.saturating_add(blocks),
+            RelativeLock::Time(time) => median_time >= coin.time.saturating_add(time),
+        };
+        if !unlocked {
+            return Err(format_err!(
+                "transaction {} spends output {}:{} before its relative lock {:?} expires",
+                txid, vin.get_txid(), vin.get_vout(), lock));
+        }
+    }
+
+    Ok(())
+}
+
+/// Coins of `view` plus the outputs of the transactions seen so far in the
+/// block at `height`
+struct BlockView<'v, V> {
+    view: &'v V,
+    height: usize,
+    time: u128,
+    created: HashMap<String, Transaction>,
+}
+
+impl<V: CoinView> CoinView for BlockView<'_, V> {
+    fn unspent_output(&self, txid: &str, vout: u32) -> Result<Option<Coin>> {
+        match self.created.get(txid) {
+            Some(tx) => Ok(tx.get_outs().get(vout as usize).cloned().map(|output| Coin {
+                output,
+                height: self.height,
+                time: self.time,
+                is_coinbase: tx.is_coinbase(),
+            })),
+            None => self.view.unspent_output(txid, vout),
+        }
+    }
+
+    fn has_unspent(&self, txid: &str) -> Result<bool> {
+        Ok(self.created.contains_key(txid) || self.view.has_unspent(txid)?)
+    }
+}
+
+/// Checks the transactions of `block` against the coins in `view`: the block
+/// fits in `max_block_size`, no transaction reuses the txid of one with
+/// unspent outputs, the first transaction and only that one is a coinbase,
+/// every other transaction passes `check_transaction` without spending an
+/// output already spent earlier in the block, and the coinbase claims at most
+/// the subsidy scheduled for the height of the block plus its fees. Lock
+/// times are checked against `median_time`, the median time past of the
+/// previous block.
+pub fn check_transactions(
+    block: &Block,
+    view: &impl CoinView,
+    params: &ChainParams,
+    median_time: u128
+) -> Result<()> {
+    if bincode::serialized_size(block)? as usize > params.max_block_size {
+        return Err(format_err!("is larger than {} bytes", params.max_block_size));
+    }
+    let transactions = block.get_transactions();
+    let coinbase = transactions.first().filter(|tx| tx.is_coinbase())
+        .ok_or_else(|| format_err!("does not start with a coinbase"))?;
+    if let Some(tx) = transactions.iter().skip(1).find(|tx| tx.is_coinbase()) {
+        return Err(format_err!("has coinbase {} that is not its first transaction", tx.get_id()));
+    }
+
+    let mut spent: HashSet<(String, u32)> = HashSet::new();
+    let mut block_view = BlockView {
+        view,
+        height: block.get_height(),
+        time: block.get_timestamp(),
+        created: HashMap::new(),
+    };
+    let mut fees = Amount::ZERO;
+
+    for tx in transactions {
+        let txid = tx.get_id();
+        if block_view.created.contains_key(&txid) {
+            return Err(format_err!("contains transaction {} twice", txid));
+        }
+        // Its outputs would replace those still unspent under the same txid
+        if view.has_unspent(&txid)? {
+            return Err(format_err!("reuses txid {} that still has unspent outputs", txid));
+        }
+
+        check_lock_times(tx, &block_view, block.get_height(), median_time)?;
+
+        if tx.is_coinbase() {
+            if tx.coinbase_height() != Some(block.get_height()) {
+                return Err(format_err!("has coinbase {} that does not commit to its height", txid));
+            }
+            let data = tx.coinbase_data().unwrap_or_default();
+            if data.len() > MAX_COINBASE_DATA_SIZE {
+                return Err(format_err!("has coinbase {} with more than {} bytes of data", txid, MAX_COINBASE_DATA_SIZE));
+            }
+            if block.get_height() == 0 && data != params.genesis_message.as_bytes() {
+                return Err(format_err!("has a genesis coinbase without the genesis message"));
+            }
+        } else {
+            for vin in tx.get_ins() {
+                if !spent.insert((vin.get_txid(), vin.get_vout())) {
+                    return Err(format_err!("spends output {}:{} twice", vin.get_txid(), vin.get_vout()));
+                }
+            }
+            fees = fees.checked_add(check_transaction(tx, &block_view, params, block.get_height())?)
+                .ok_or_else(|| format_err!("has fees that overflow"))?;
+        }
+
+        block_view.created.insert(txid, tx.clone());
+    }
+
+    let allowed = params.subsidy(block.get_height()).checked_add(fees)
+        .ok_or_else(|| format_err!("has fees that overflow"))?;
+    if coinbase.value_out()? > allowed {
+        return Err(format_err!(
+            "has coinbase {} claiming more than the subsidy and fees {}", coinbase.get_id(), allowed));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chainparams::ChainParams;
+    use crate::script::Script;
+    use crate::transaction::{
+        LOCKTIME_THRESHOLD, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_TYPE_FLAG
+    };
+    use crate::tx::{TXInput, TXOutput};
+    use crate::testutil::{new_address, test_chain_with_key, test_params};
+    use crate::wallet::{new_wallet, sign_digest};
+
+    fn unlocked(txid: &str, value: Amount, script_sig: Script) -> Transaction {
+        let vin = vec![TXInput::new(txid.to_string(), 0, script_sig)];
+        Transaction::new(vin, vec![TXOutput::new(value, new_address(), &ChainParams::regtest()).unwrap()]).unwrap()
+    }
+
+    fn spend(bc: &Blockchain, txid: &str, value: Amount, private_key: &[u8]) -> Transaction {
+        let mut tx = unlocked(txid, value, Script::new());
+        bc.sign_transaction(&mut tx, private_key).unwrap();
+        tx
+    }
+
+    fn locked(bc: &Blockchain, txid: &str, value: Amount, sequence: u32, lock_time: u64, private_key: &[u8]) -> Transaction {
+        let mut vin = TXInput::new(txid.to_string(), 0, Script::new());
+        vin.set_sequence(sequence);
+        let vout = vec![TXOutput::new(value, new_address(), &ChainParams::regtest()).unwrap()];
+        let mut tx = Transaction::with_lock_time(vec![vin], vout, lock_time).unwrap();
+        bc.sign_transaction(&mut tx, private_key).unwrap();
+        tx
+    }
+
+    #[test]
+    fn test_lock_times() {
+        let (mut bc, alice) = test_chain_with_key(test_params());
+        let reward = bc.params().subsidy(0);
+        let owner = alice.get_address(bc.params().address_version);
+        let genesis_tx = bc.iter().next().unwrap().get_transactions()[0].get_id();
+        let coinbase = |height| Transaction::new_coinbase(owner.clone(), height, &[], reward, &ChainParams::regtest()).unwrap();
+        let private_key = alice.private_key.clone();
+
+        // A height lock allows the blocks after it, unless every input is final
+        let tx = locked(&bc, &genesis_tx, reward, SEQUENCE_FINAL - 1, 2, &private_key);
+        assert!(!tx.is_final(2, 0));
+        assert!(locked(&bc, &genesis_tx, reward, SEQUENCE_FINAL, 2, &private_key).is_final(2, 0));
+        assert!(bc.add_block(vec![coinbase(1), tx.clone()]).is_err());
+        let first = bc.add_block(vec![coinbase(1)]).unwrap().get_transactions()[0].get_id();
+        assert!(bc.add_block(vec![coinbase(2), tx.clone()]).is_err());
+        let second = bc.add_block(vec![coinbase(2)]).unwrap().get_transactions()[0].get_id();
+        bc.add_block(vec![coinbase(3), tx]).unwrap();
+
+        // A relative lock counts the confirmations of the spent output
+        let tx = locked(&bc, &second, reward, 3, 0, &private_key);
+        assert!(bc.add_block(vec![coinbase(4), tx.clone()]).is_err());
+        let fourth = bc.add_block(vec![coinbase(4)]).unwrap().get_transactions()[0].get_id();
+        bc.add_block(vec![coinbase(5), tx]).unwrap();
+        let tx = locked(&bc, &fourth, reward, SEQUENCE_LOCKTIME_DISABLE_FLAG | 100, 0, &private_key);
+        bc.add_block(vec![coinbase(6), tx]).unwrap();
+
+        // Time locks compare with the median time past, in 512 second units
+        // for relative ones
+        let later = (block::now() / 1000) as u64 + 3600;
+        let tx = locked(&bc, &first, reward, SEQUENCE_FINAL - 1, later, &private_key);
+        assert!(bc.add_block(vec![coinbase(7), tx]).is_err());
+        let tx = locked(&bc, &first, reward, SEQUENCE_LOCKTIME_TYPE_FLAG | 1, 0, &private_key);
+        assert!(bc.add_block(vec![coinbase(7), tx]).is_err());
+        let tx = locked(&bc, &first, reward, SEQUENCE_LOCKTIME_TYPE_FLAG, LOCKTIME_THRESHOLD, &private_key);
+        bc.add_block(vec![coinbase(7), tx]).unwrap();
+
+        assert_eq!(bc.verify_chain(None).unwrap(), 8);
+    }
+
+    #[test]
+    fn test_block_validation() {
+        let (mut bc, alice) = test_chain_with_key(ChainParams { coinbase_maturity: 2, ..ChainParams::regtest() });
+        let reward = bc.params().subsidy(0);
+        let genesis_tx = bc.iter().next().unwrap().get_transactions()[0].get_id();
+        let private_key = alice.private_key.clone();
+
+        let coinbase = |height| Transaction::new_coinbase(new_address(), height, &[], reward, &ChainParams::regtest()).unwrap();
+
+        // The genesis coinbase only matures at height 2
+        let tx = spend(&bc, &genesis_tx, reward, &private_key);
+        assert!(bc.add_block(vec![coinbase(1), tx]).is_err());
+        bc.add_block(vec![coinbase(1)]).unwrap();
+
+        // Spending more than the input creates value
+        let tx = spend(&bc, &genesis_tx, reward.checked_add(Amount::from_base_units(1)).unwrap(), &private_key);
+        assert!(bc.add_block(vec![coinbase(2), tx]).is_err());
+
+        // A signature by another key is rejected, and so is another key
+        // signing for itself
+        let mallory = new_wallet();
+        let signature = sign_digest(&mallory.private_key, &[7; 32]).unwrap();
+        let tx = unlocked(&genesis_tx, reward, Script::p2pkh_sig(&signature, &alice.public_key));
+        assert!(bc.add_block(vec![coinbase(2), tx]).is_err());
+        let tx = unlocked(&genesis_tx, reward, Script::p2pkh_sig(&signature, &mallory.public_key));
+        assert!(bc.add_block(vec![coinbase(2), tx]).is_err());
+        assert!(bc.sign_transaction(&mut unlocked(&genesis_tx, reward, Script::new()), &mallory.private_key).is_err());
+
+        // A misplaced coinbase, one for another height or an oversized one
+        // is rejected
+        let tx = spend(&bc, &genesis_tx, reward, &private_key);
+        assert!(bc.add_block(vec![tx.clone(), coinbase(2)]).is_err());
+        assert!(bc.add_block(vec![coinbase(1)]).is_err());
+        let greedy = Transaction::new_coinbase(new_address(), 2, &[], Amount::MAX, bc.params()).unwrap();
+        assert!(bc.add_block(vec![greedy]).is_err());
+
+        bc.add_block(vec![coinbase(2), tx.clone()]).unwrap();
+        assert_eq!(bc.get_best_height().unwrap(), 2);
+
+        // The output is spent now
+        assert!(bc.add_block(vec![coinbase(3), tx]).is_err());
+        assert_eq!(bc.get_best_height().unwrap(), 2);
+
+        // A block on another branch with no more work is kept aside
+        let tip = bc.get_tip();
+        let side = Block::new(vec![Transaction::new_coinbase(new_address(), 2, &[], reward, bc.params()).unwrap()],
+            bc.iter().nth(1).unwrap().get_hash(), 2, bc.params().pow_limit_bits, 0).unwrap();
+        bc.accept_block(&side).unwrap();
+        assert_eq!(bc.get_tip(), tip);
+        assert!(bc.accept_block(&side).is_err());
+
+        // A block whose coinbase reuses a txid with unspent outputs would
+        // wipe them out
+        let first = bc.iter().nth(1).unwrap();
+        let mut view = MemoryCoinView::default();
+        assert!(check_transactions(&first, &view, bc.params(), 0).is_ok());
+        view.apply_block(&first);
+        assert!(check_transactions(&first, &view, bc.params(), 0).is_err());
+
+        assert_eq!(bc.verify_chain(None).unwrap(), 3);
+        assert_eq!(bc.verify_chain(Some(1)).unwrap(), 1);
+        assert_eq!(bc.verify_chain(Some(10)).unwrap(), 3);
+    }
+
+    fn block_of(txs: Vec<Transaction>) -> Block {
+        Block::new(txs, "0".repeat(64), 1, ChainParams::regtest().pow_limit_bits, 0).unwrap()
+    }
+
+    #[test]
+    fn test_missing_coinbase() {
+        let params = ChainParams::regtest();
+        let coinbase = Transaction::new_coinbase(new_address(), 0, &[], params.subsidy(0), &params).unwrap();
+        let mut view = MemoryCoinView::default();
+        view.apply_block(&block_of(vec![coinbase.clone()]));
+
+        // A block spending a coin without minting one has no coinbase
+        let tx = unlocked(&coinbase.get_id(), params.subsidy(0), Script::new());
+        let error = check_transactions(&block_of(vec![tx]), &view, &params, 0).unwrap_err();
+        assert!(error.to_string().contains("does not start with a coinbase"), "{}", error);
+    }
+
+    #[test]
+    fn test_second_coinbase() {
+        let params = ChainParams::regtest();
+        let coinbase = || Transaction::new_coinbase(new_address(), 1, &[], params.subsidy(1), &params).unwrap();
+        let view = MemoryCoinView::default();
+        assert!(check_transactions(&block_of(vec![coinbase()]), &view, &params, 0).is_ok());
+
+        // Each coinbase would mint the subsidy again
+        let error = check_transactions(&block_of(vec![coinbase(), coinbase()]), &view, &params, 0).unwrap_err();
+        assert!(error.to_string().contains("is not its first transaction"), "{}", error);
+    }
+}
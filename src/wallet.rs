@@ -1,13 +1,64 @@
-use std::{collections::HashMap, hash::Hash, str::FromStr};
+use std::{collections::{HashMap, HashSet}, fmt, fs, io::{self, Write}};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 
 use crypto::{digest::Digest, ripemd160};
-use log::{error, info};
+use failure::format_err;
+use log::info;
 use serde::{Serialize, Deserialize};
-use sled::Db;
 use secp256k1; 
-use secp256k1::schnorr::Signature;
 use sha2::{Sha256, Digest as Sha256Digest};
 use bs58; 
+use crate::amount::Amount;
+use crate::chainparams::ChainParams;
+use crate::errors::Result;
+use crate::hd::{self, ExtendedKey};
+use crate::keystore::{self, MasterKey};
+use crate::script::{Script, MAX_SCRIPT_ELEMENT_SIZE};
+use crate::transaction::hash_pub_key;
+use crate::utxoset::UTXOSet;
+
+/// Tree of the wallet database holding the redeem scripts of multisig
+/// addresses, keyed by address
+pub const MULTISIG_TREE: &str = "multisig";
+
+/// Tree of the wallet database holding the key pairs, keyed by address, with
+/// the private keys encrypted under the master key
+pub const KEYS_TREE: &str = "keys";
+
+/// Tree of the wallet database holding the encrypted master key
+pub const KEYSTORE_TREE: &str = "keystore";
+
+/// Tree of the wallet database marking the internal addresses that change
+/// is sent to, keyed by address
+pub const CHANGE_TREE: &str = "change";
+
+/// Tree of the wallet database holding the labels of addresses and
+/// transactions, keyed by address or txid
+pub const LABELS_TREE: &str = "labels";
+
+/// Tree of the wallet database holding the encrypted HD seed and the next
+/// index of each derivation chain
+pub const HD_TREE: &str = "hd";
+
+/// Tree of the wallet database holding the derivation path of each HD
+/// address, keyed by address
+pub const HD_PATHS_TREE: &str = "hdpaths";
+
+
+/// Derivation chain of the addresses handed out to receive payments
+pub const RECEIVE_CHAIN: u32 = 0;
+
+/// Derivation chain of the addresses change is sent back to
+pub const CHANGE_CHAIN: u32 = 1;
+
+/// Number of unused addresses in a row after which `Wallets::rescan` stops
+/// looking for more
+pub const GAP_LIMIT: u32 = 20;
+
+const MASTER_KEY: &str = "master";
+const SEED_KEY: &str = "seed";
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Wallet {
@@ -36,26 +87,12 @@ impl Wallet {
         (private_key.secret_bytes().to_vec(), public_key.serialize().to_vec())
     }
 
-    fn sign(&self, message: &[u8]) -> Vec<u8> {
-        let secp = secp256k1::Secp256k1::new();
-        let message = secp256k1::Message::from_digest_slice(message).unwrap();
-        let private_key = secp256k1::SecretKey::from_slice(&self.private_key).unwrap();
-        let public_key = secp256k1::PublicKey::from_slice(&self.public_key).unwrap();
-        let keypair = secp256k1::Keypair::from_secret_key(&secp, &private_key);
-        let signature = secp.sign_schnorr_no_aux_rand(&message, &keypair);
-        signature.serialize().to_vec()
+    /// Checks a signature made by `sign_digest`
+    pub(crate) fn verify(public_key: &[u8], digest: &[u8], signature: &[u8]) -> bool {
+        verify_digest(public_key, digest, signature)
     }
 
-    fn verify(public_key: &[u8], message: &[u8], signature: &str) -> bool {
-        let secp = secp256k1::Secp256k1::new();
-        let message = secp256k1::Message::from_digest_slice(message).unwrap();
-        let signature = secp256k1::schnorr::Signature::from_str(signature).unwrap();
-        let xonly_pubkey = secp256k1::XOnlyPublicKey::from_slice(&public_key).unwrap();
-
-        secp.verify_schnorr(&signature, &message, &xonly_pubkey).is_ok()
-    }
-
-    fn get_address_helper(public_key: &[u8]) -> String {
+    fn get_address_helper(public_key: &[u8], version: u8) -> String {
         let public_key = secp256k1::PublicKey::from_slice(public_key).unwrap();
         let public_key = public_key.serialize().to_vec();
         let mut hasher = Sha256::new();
@@ -66,38 +103,12 @@ impl Wallet {
         let num_bytes = ripemd.output_bytes();
         let mut out = vec![0; num_bytes];
         ripemd.result(&mut out);
-        let mut payload = vec![0x00];
-        payload.extend(out);
-        hasher = Sha256::new();
-        hasher.update(&payload);
-        let checksum = hasher.finalize();
-
-        payload.extend(&checksum[..4]);
-        bs58::encode(payload).into_string()
-    }
-
-    fn get_address(&self) -> String {
-        Wallet::get_address_helper(&self.public_key)
-    }
-
-    fn verify_address(address: &str) -> bool {
-        let decoded = bs58::decode(address).into_vec().unwrap();
-        let checksum = &decoded[decoded.len() - 4..];
-        let payload = &decoded[..decoded.len() - 4];
-        let mut hasher = Sha256::new();
-        hasher.update(&payload);
-        let new_checksum = hasher.finalize();
-        checksum == &new_checksum[..4]
+        encode_address(version, &out)
     }
 
-    fn from_address(address: &str) -> Wallet {
-        let decoded = bs58::decode(address).into_vec().unwrap();
-        let public_key_hash = &decoded[1..decoded.len() - 4];
-        let public_key = secp256k1::PublicKey::from_slice(public_key_hash).unwrap();
-        Wallet {
-            private_key: vec![],
-            public_key: public_key.serialize().to_vec(),
-        }
+    /// Address of this wallet on the network whose addresses start with `version`
+    pub fn get_address(&self, version: u8) -> String {
+        Wallet::get_address_helper(&self.public_key, version)
     }
 
     fn from_pub_key(public_key: &[u8]) -> Wallet {
@@ -106,22 +117,114 @@ impl Wallet {
             public_key: public_key.to_vec(),
         }
     }
+}
 
-    fn from_pub_key_hash(public_key_hash: &[u8]) -> Wallet {
-        let public_key = secp256k1::PublicKey::from_slice(public_key_hash).unwrap();
-        Wallet {
-            private_key: vec![],
-            public_key: public_key.serialize().to_vec(),
-        }
-    }
-
-    fn to_string(&self) -> String {
+impl fmt::Display for Wallet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let private_key = bs58::encode(&self.private_key).into_string();
         let public_key = bs58::encode(&self.public_key).into_string();
-        format!("Private key: {}\nPublic key: {}", private_key, public_key)
+        write!(f, "Private key: {}\nPublic key: {}", private_key, public_key)
     }
 }
 
+/// Base58Check address of the 20-byte `hash`, starting with `version`
+pub fn encode_address(version: u8, hash: &[u8]) -> String {
+    let mut payload = vec![version];
+    payload.extend(hash);
+    let mut hasher = Sha256::new();
+    hasher.update(&payload[..]);
+    let checksum = hasher.finalize();
+
+    payload.extend(&checksum[..4]);
+    bs58::encode(payload).into_string()
+}
+
+/// Locking script paying to `address`: a pay-to-pubkey-hash script for a
+/// key address and a pay-to-script-hash script for a script address of the
+/// network of `params`
+pub fn decode_destination(address: &str, params: &ChainParams) -> Result<Script> {
+    let (version, hash) = decode_base58check(address)?;
+    if version == params.address_version {
+        Ok(Script::p2pkh(&hash))
+    } else if version == params.script_address_version {
+        Ok(Script::p2sh(&hash))
+    } else {
+        Err(format_err!("ERROR: Address '{}' belongs to another network", address))
+    }
+}
+
+/// Address of the network of `params` that `script_pub_key` pays to, if it
+/// is a pay-to-pubkey-hash or pay-to-script-hash script
+pub fn encode_destination(script_pub_key: &Script, params: &ChainParams) -> Option<String> {
+    if let Some(hash) = script_pub_key.get_pub_key_hash() {
+        Some(encode_address(params.address_version, hash))
+    } else {
+        script_pub_key.get_script_hash().map(|hash| encode_address(params.script_address_version, hash))
+    }
+}
+
+/// Decodes a Base58Check address into the public key hash it pays to,
+/// rejecting malformed addresses and addresses of another network
+pub fn decode_address(address: &str, version: u8) -> Result<Vec<u8>> {
+    let (address_version, pub_key_hash) = decode_base58check(address)?;
+    if address_version != version {
+        return Err(format_err!("ERROR: Address '{}' belongs to another network", address));
+    }
+
+    Ok(pub_key_hash)
+}
+
+/// Splits a Base58Check address into its version byte and public key hash
+/// after checking its length and checksum, whatever network it belongs to
+pub fn decode_base58check(address: &str) -> Result<(u8, Vec<u8>)> {
+    let decoded = bs58::decode(address).into_vec()
+        .map_err(|_| format_err!("ERROR: Invalid address '{}'", address))?;
+    if decoded.len() != 25 {
+        return Err(format_err!("ERROR: Invalid address '{}'", address));
+    }
+
+    let (payload, checksum) = decoded.split_at(21);
+    let mut hasher = Sha256::new();
+    hasher.update(payload);
+    if checksum != &hasher.finalize()[..4] {
+        return Err(format_err!("ERROR: Invalid checksum in address '{}'", address));
+    }
+
+    Ok((payload[0], payload[1..].to_vec()))
+}
+
+/// ECDSA signature over secp256k1 of a 32-byte digest, in 64-byte compact form
+pub fn sign_digest(private_key: &[u8], digest: &[u8]) -> Result<Vec<u8>> {
+    let secp = secp256k1::Secp256k1::signing_only();
+    let message = secp256k1::Message::from_digest_slice(digest)?;
+    let private_key = secp256k1::SecretKey::from_slice(private_key)
+        .map_err(|_| format_err!("ERROR: Invalid private key"))?;
+    Ok(secp.sign_ecdsa(&message, &private_key).serialize_compact().to_vec())
+}
+
+/// Whether `signature` is a valid `sign_digest` signature of `digest` by the
+/// owner of the serialized `public_key`. Malformed inputs are invalid.
+pub fn verify_digest(public_key: &[u8], digest: &[u8], signature: &[u8]) -> bool {
+    let secp = secp256k1::Secp256k1::verification_only();
+    let (message, public_key, signature) = match (
+        secp256k1::Message::from_digest_slice(digest),
+        secp256k1::PublicKey::from_slice(public_key),
+        secp256k1::ecdsa::Signature::from_compact(signature),
+    ) {
+        (Ok(message), Ok(public_key), Ok(signature)) => (message, public_key, signature),
+        _ => return false,
+    };
+    secp.verify_ecdsa(&message, &signature, &public_key).is_ok()
+}
+
+/// Compressed public key of `private_key`
+pub fn derive_pub_key(private_key: &[u8]) -> Result<Vec<u8>> {
+    let secp = secp256k1::Secp256k1::signing_only();
+    let private_key = secp256k1::SecretKey::from_slice(private_key)
+        .map_err(|_| format_err!("ERROR: Invalid private key"))?;
+    Ok(secp256k1::PublicKey::from_secret_key(&secp, &private_key).serialize().to_vec())
+}
+
 pub fn get_pub_key_hash(public_key: &[u8]) -> Vec<u8> {
     let public_key = secp256k1::PublicKey::from_slice(public_key).unwrap();
     let public_key = public_key.serialize().to_vec();
@@ -136,89 +239,757 @@ pub fn get_pub_key_hash(public_key: &[u8]) -> Vec<u8> {
     out.to_vec()
 }
 
+/// A key pair as stored in `KEYS_TREE`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EncryptedWallet {
+    public_key: Vec<u8>,
+    encrypted_key: Vec<u8>,
+}
+
+/// The master key written by `Wallets::unlock_for`, valid until `until`, in
+/// milliseconds since the epoch
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UnlockSession {
+    key: Vec<u8>,
+    until: u128,
+}
+
+/// Where an HD address sits: the coin type of its account, its chain in
+/// the account and its index there
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPath {
+    pub coin_type: u32,
+    pub chain: u32,
+    pub index: u32,
+}
+
+impl fmt::Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}/{}", account_path(self.coin_type), self.chain, self.index)
+    }
+}
+
+/// Path of the account HD keys are derived under on a network with BIP44
+/// `coin_type`
+pub fn account_path(coin_type: u32) -> String {
+    format!("m/44'/{}'/0'", coin_type)
+}
+
+/// A fresh key for the change of a spend. `Wallets::add_change_key` only
+/// stores it once the spend turns out to need change, so failed spends do
+/// not leave unused keys behind.
+#[derive(Debug, Clone)]
+pub struct ChangeKey {
+    wallet: Wallet,
+    path: Option<KeyPath>,
+}
+
+impl ChangeKey {
+    /// Locking script of the change output
+    pub fn get_script_pub_key(&self) -> Script {
+        Script::p2pkh(&get_pub_key_hash(&self.wallet.public_key))
+    }
+}
+
+/// The wallets of a node.
+///
+/// Private keys are encrypted under a random master key, which is itself
+/// encrypted under the wallet passphrase. While the wallet is locked the
+/// wallets only carry their public key. The master key is only stored in
+/// the clear in the session file of `unlock_for`, until its timeout.
+///
+/// Once a mnemonic is set, new keys are derived from its seed along
+/// the `account_path` of the network, so the mnemonic alone is enough to
+/// restore them.
 #[derive(Debug)]
 pub struct Wallets {
+    db: sled::Db,
+    address_version: u8,
+    script_address_version: u8,
+    coin_type: u32,
     wallets: HashMap<String, Wallet>,
+    master: Option<MasterKey>,
+    master_key: Option<Vec<u8>>,
+    unlocked_until: Option<u128>,
 }
 
 impl Wallets {
-    pub fn new() -> Self {
+    /// Loads the wallets stored in `db`. New wallets get addresses and
+    /// derivation paths of the network of `params`. The private keys stay
+    /// encrypted until the wallet is unlocked.
+    pub fn new(db: sled::Db, params: &ChainParams) -> Result<Wallets> {
         let mut wallets = HashMap::new();
 
-        let db = sled::open("data/wallets").unwrap();
-
         for wallet in db.iter() {
-            let i = wallet.unwrap();
-            let addr = String::from_utf8(i.0.to_vec()).unwrap();
-            let wallet = Wallet::from_pub_key_hash(&i.1);
+            let i = wallet?;
+            let addr = String::from_utf8(i.0.to_vec())?;
+            // Older databases kept the keys unencrypted or only the public key
+            let wallet = match bincode::deserialize::<Wallet>(&i.1) {
+                Ok(wallet) => wallet,
+                Err(_) => Wallet::from_pub_key(&i.1),
+            };
             wallets.insert(addr, wallet);
         }
 
-        drop(db);
-        Wallets { wallets }
+        for entry in db.open_tree(KEYS_TREE)?.iter() {
+            let (addr, encoded) = entry?;
+            let stored: EncryptedWallet = bincode::deserialize(&encoded)?;
+            wallets.insert(String::from_utf8(addr.to_vec())?, Wallet::from_pub_key(&stored.public_key));
+        }
+
+        let keystore = db.open_tree(KEYSTORE_TREE)?;
+        let master = match keystore.get(MASTER_KEY)? {
+            Some(encoded) => Some(bincode::deserialize(&encoded)?),
+            None => None,
+        };
+
+        Ok(Wallets {
+            db,
+            address_version: params.address_version,
+            script_address_version: params.script_address_version,
+            coin_type: params.bip44_coin_type,
+            wallets,
+            master,
+            master_key: None,
+            unlocked_until: None,
+        })
     }
 
-    pub fn create_wallet(&mut self) -> String {
-        let wallet = Wallet::new();
-        let address = wallet.get_address();
-        
-        let db = sled::open("data/wallets").unwrap();
-        db.insert(address.as_bytes(), wallet.public_key).unwrap();
-        db.flush().unwrap();
-        drop(db);
+    /// Creates a key pair and stores it encrypted, which needs the wallet to
+    /// have a passphrase and be unlocked. The key is the next one of the
+    /// receive chain if the wallet has a mnemonic, and a random one otherwise.
+    pub fn create_wallet(&mut self) -> Result<String> {
+        self.get_master_key()?;
+        if self.has_seed()? {
+            return self.derive_next(RECEIVE_CHAIN);
+        }
+
+        let address = self.store_key(Wallet::new(), None, false)?;
         info!("Created wallet with address '{}'", address);
-        address    
+        Ok(address)
     }
 
-    pub fn get_wallet(&self, address: &str) -> Option<Wallet> {
-        let db = sled::open("data/wallets").unwrap();
+    /// The key the change of the next spend goes to: the next one of the
+    /// change chain if the wallet has a mnemonic, and a random one otherwise
+    pub fn next_change_key(&self) -> Result<ChangeKey> {
+        self.get_master_key()?;
+        if !self.has_seed()? {
+            return Ok(ChangeKey { wallet: Wallet::new(), path: None });
+        }
 
-        let wallet = db.get(address.as_bytes()).unwrap();
-        let wallet = match wallet {
-            Some(wallet) => Some(Wallet::from_pub_key_hash(&wallet)),
-            None => None,
+        let path = KeyPath {
+            coin_type: self.coin_type,
+            chain: CHANGE_CHAIN,
+            index: self.get_next_index(CHANGE_CHAIN)?,
         };
+        let private_key = self.get_account_key()?.derive_path(&[path.chain, path.index])?.private_key;
+        let wallet = Wallet { public_key: derive_pub_key(&private_key)?, private_key };
+        Ok(ChangeKey { wallet, path: Some(path) })
+    }
 
-        drop(db);
-        wallet.clone()
+    /// Stores `key` as an internal address once a spend sent change to it,
+    /// and returns its address
+    pub fn add_change_key(&mut self, key: ChangeKey) -> Result<String> {
+        let address = self.store_key(key.wallet, key.path, true)?;
+        if let Some(path) = key.path {
+            self.set_next_index(path.chain, path.index + 1)?;
+        }
+        info!("Added change address '{}'", address);
+        Ok(address)
     }
 
-    pub fn get_wallets(&self) -> HashMap<String, Wallet> {
-        let mut wallets = HashMap::new();
+    /// Whether `address` is an internal address change was sent to
+    pub fn is_change(&self, address: &str) -> Result<bool> {
+        Ok(self.db.open_tree(CHANGE_TREE)?.contains_key(address.as_bytes())?)
+    }
 
-        let db = sled::open("data/wallets").unwrap();
+    /// Spendable and immature balance of all the key addresses of the wallet,
+    /// change included
+    pub fn get_balance(&self, utxo_set: &UTXOSet) -> Result<(Amount, Amount)> {
+        let mut balance = Amount::ZERO;
+        let mut immature = Amount::ZERO;
+        for wallet in self.wallets.values() {
+            let (spendable, locked) = utxo_set.get_balance(&Script::p2pkh(&get_pub_key_hash(&wallet.public_key)))?;
+            balance = balance.checked_add(spendable).ok_or_else(|| format_err!("ERROR: Amount overflow"))?;
+            immature = immature.checked_add(locked).ok_or_else(|| format_err!("ERROR: Amount overflow"))?;
+        }
+        Ok((balance, immature))
+    }
 
-        for wallet in db.iter() {
-            let i = wallet.unwrap();
-            let addr = String::from_utf8(i.0.to_vec()).unwrap();
-            let wallet = Wallet::from_pub_key_hash(&i.1);
-            wallets.insert(addr, wallet);
+    /// Whether new keys are derived from a mnemonic
+    pub fn has_seed(&self) -> Result<bool> {
+        Ok(self.db.open_tree(HD_TREE)?.contains_key(SEED_KEY)?)
+    }
+
+    /// Derives the keys of this wallet from the seed of `mnemonic` from now
+    /// on, which needs the wallet to be unlocked. A wallet has a single
+    /// mnemonic, so it cannot be replaced once set.
+    pub fn set_mnemonic(&mut self, mnemonic: &str) -> Result<()> {
+        let master_key = self.get_master_key()?.to_vec();
+        if self.has_seed()? {
+            return Err(format_err!("ERROR: The wallet already has a mnemonic"));
+        }
+        let seed = hd::mnemonic_to_seed(mnemonic, "")?;
+
+        let tree = self.db.open_tree(HD_TREE)?;
+        tree.insert(SEED_KEY, keystore::encrypt(&master_key, &seed, SEED_KEY.as_bytes()))?;
+        tree.flush()?;
+        info!("Set the wallet mnemonic");
+        Ok(())
+    }
+
+    /// Derives the next key of `chain`, stores it like `create_wallet` does
+    /// and returns its address
+    pub fn derive_next(&mut self, chain: u32) -> Result<String> {
+        let account = self.get_account_key()?;
+        let index = self.get_next_index(chain)?;
+        let address = self.store_derived(&account, KeyPath { coin_type: self.coin_type, chain, index })?;
+        self.set_next_index(chain, index + 1)?;
+        info!("Derived wallet with address '{}'", address);
+        Ok(address)
+    }
+
+    /// Derivation path of an address derived from the mnemonic
+    pub fn get_key_path(&self, address: &str) -> Result<Option<KeyPath>> {
+        match self.db.open_tree(HD_PATHS_TREE)?.get(address.as_bytes())? {
+            Some(encoded) => Ok(Some(bincode::deserialize(&encoded)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Looks for the keys of both chains that `is_used` says received coins,
+    /// given their public key hash, until `gap_limit` keys in a row are
+    /// unused. Stores every key up to the last used one of each chain and
+    /// resumes derivation after it. Returns the addresses found used.
+    pub fn rescan(&mut self, gap_limit: u32, is_used: impl Fn(&[u8]) -> bool) -> Result<Vec<String>> {
+        let account = self.get_account_key()?;
+        let mut used = Vec::new();
+
+        for chain in [RECEIVE_CHAIN, CHANGE_CHAIN] {
+            let chain_key = account.derive_child(chain)?;
+            let mut next = 0;
+            let mut index = 0;
+            while index < next + gap_limit {
+                let public_key = derive_pub_key(&chain_key.derive_child(index)?.private_key)?;
+                if is_used(&get_pub_key_hash(&public_key)) {
+                    next = index + 1;
+                }
+                index += 1;
+            }
+
+            for index in 0..next {
+                let address = self.store_derived(&account, KeyPath { coin_type: self.coin_type, chain, index })?;
+                if is_used(&decode_address(&address, self.address_version)?) {
+                    used.push(address);
+                }
+            }
+            self.set_next_index(chain, next.max(self.get_next_index(chain)?))?;
+        }
+
+        Ok(used)
+    }
+
+    /// The account key of the seed, which needs the wallet to be unlocked
+    fn get_account_key(&self) -> Result<ExtendedKey> {
+        let master_key = self.get_master_key()?;
+        let encrypted = self.db.open_tree(HD_TREE)?.get(SEED_KEY)?
+            .ok_or_else(|| format_err!("ERROR: The wallet has no mnemonic"))?;
+        let seed = keystore::decrypt(master_key, &encrypted, SEED_KEY.as_bytes())?;
+        ExtendedKey::from_seed(&seed)?.derive_path(&hd::parse_path(&account_path(self.coin_type))?)
+    }
+
+    /// Derives the key at `path` under `account` and stores it, as an
+    /// internal address if it is on the change chain
+    fn store_derived(&mut self, account: &ExtendedKey, path: KeyPath) -> Result<String> {
+        let private_key = account.derive_path(&[path.chain, path.index])?.private_key;
+        let wallet = Wallet { public_key: derive_pub_key(&private_key)?, private_key };
+        self.store_key(wallet, Some(path), path.chain == CHANGE_CHAIN)
+    }
+
+    /// Stores `wallet` encrypted along with its derivation path, marking it
+    /// as a `change` address
+    fn store_key(&mut self, wallet: Wallet, path: Option<KeyPath>, change: bool) -> Result<String> {
+        let master_key = self.get_master_key()?.to_vec();
+        let address = wallet.get_address(self.address_version);
+        self.store_encrypted(&address, &wallet, &master_key)?;
+
+        if let Some(path) = path {
+            let paths = self.db.open_tree(HD_PATHS_TREE)?;
+            paths.insert(address.as_bytes(), bincode::serialize(&path)?)?;
+            paths.flush()?;
+        }
+        if change {
+            let tree = self.db.open_tree(CHANGE_TREE)?;
+            tree.insert(address.as_bytes(), &[])?;
+            tree.flush()?;
+        }
+        self.wallets.insert(address.clone(), wallet);
+        Ok(address)
+    }
+
+    fn get_next_index(&self, chain: u32) -> Result<u32> {
+        match self.db.open_tree(HD_TREE)?.get(chain.to_be_bytes())? {
+            Some(index) => Ok(bincode::deserialize(&index)?),
+            None => Ok(0),
+        }
+    }
+
+    fn set_next_index(&self, chain: u32, index: u32) -> Result<()> {
+        let tree = self.db.open_tree(HD_TREE)?;
+        tree.insert(chain.to_be_bytes(), bincode::serialize(&index)?)?;
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Whether the wallet has a passphrase
+    pub fn is_encrypted(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// Whether the wallet has a passphrase and the private keys are not
+    /// decrypted, or the timeout of `unlock_for` has passed
+    pub fn is_locked(&self) -> bool {
+        self.is_encrypted() && (self.master_key.is_none()
+            || self.unlocked_until.is_some_and(|until| until <= crate::block::now()))
+    }
+
+    /// Protects the wallet with `passphrase`, encrypting the keys of older
+    /// databases that were stored in the clear. Leaves the wallet unlocked.
+    pub fn set_passphrase(&mut self, passphrase: &str) -> Result<()> {
+        if self.is_encrypted() {
+            return Err(format_err!("ERROR: The wallet already has a passphrase"));
+        }
+        if passphrase.is_empty() {
+            return Err(format_err!("ERROR: The passphrase is empty"));
         }
 
-        drop(db);
-        wallets
+        let (master, master_key) = MasterKey::new(passphrase)?;
+        for (address, wallet) in self.wallets.iter().filter(|(_, wallet)| !wallet.private_key.is_empty()) {
+            self.store_encrypted(address, wallet, &master_key)?;
+            self.db.remove(address.as_bytes())?;
+        }
+
+        let keystore = self.db.open_tree(KEYSTORE_TREE)?;
+        keystore.insert(MASTER_KEY, bincode::serialize(&master)?)?;
+        keystore.flush()?;
+        self.db.flush()?;
+        info!("Encrypted the wallet");
+        self.master = Some(master);
+        self.master_key = Some(master_key);
+        Ok(())
+    }
+
+    /// Decrypts the private keys for the lifetime of this `Wallets`
+    pub fn unlock(&mut self, passphrase: &str) -> Result<()> {
+        let master = self.master.as_ref()
+            .ok_or_else(|| format_err!("ERROR: The wallet has no passphrase"))?;
+        let master_key = master.unlock(passphrase)?;
+        self.decrypt_keys(master_key)?;
+        self.unlocked_until = None;
+        Ok(())
+    }
+
+    /// Like `unlock`, but only for the next `timeout` seconds. The master key
+    /// is also written to the `session` file, readable by its owner only, so
+    /// that `resume_session` unlocks later processes until then.
+    pub fn unlock_for(&mut self, passphrase: &str, timeout: u64, session: &Path) -> Result<()> {
+        self.unlock(passphrase)?;
+        let until = crate::block::now() + timeout as u128 * 1000;
+        let unlocked = UnlockSession { key: self.get_master_key()?.to_vec(), until };
+
+        Wallets::end_session(session)?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        options.open(session)?.write_all(&bincode::serialize(&unlocked)?)?;
+        self.unlocked_until = Some(until);
+        Ok(())
+    }
+
+    /// Unlocks the wallet with the key `unlock_for` wrote to `session`, until
+    /// its timeout. Removes the file once the timeout has passed.
+    pub fn resume_session(&mut self, session: &Path) -> Result<()> {
+        let encoded = match fs::read(session) {
+            Ok(encoded) => encoded,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let unlocked: UnlockSession = bincode::deserialize(&encoded)?;
+        if unlocked.until <= crate::block::now() {
+            return Wallets::end_session(session);
+        }
+
+        self.decrypt_keys(unlocked.key)?;
+        self.unlocked_until = Some(unlocked.until);
+        Ok(())
+    }
+
+    /// Removes the `session` file, so later processes start locked
+    pub fn end_session(session: &Path) -> Result<()> {
+        match fs::remove_file(session) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Forgets the master key and the private keys it decrypted
+    pub fn lock(&mut self) -> Result<()> {
+        let keys = self.db.open_tree(KEYS_TREE)?;
+        for (address, wallet) in self.wallets.iter_mut() {
+            if keys.contains_key(address.as_bytes())? {
+                wallet.private_key.clear();
+            }
+        }
+        self.master_key = None;
+        self.unlocked_until = None;
+        Ok(())
+    }
+
+    fn get_master_key(&self) -> Result<&[u8]> {
+        match &self.master_key {
+            Some(master_key) if !self.is_locked() => Ok(master_key),
+            _ if self.is_encrypted() => Err(format_err!("ERROR: The wallet is locked")),
+            _ => Err(format_err!("ERROR: The wallet has no passphrase")),
+        }
+    }
+
+    /// `wallet` without its private key once the timeout of `unlock_for`
+    /// has passed
+    fn unless_locked(&self, wallet: &Wallet) -> Wallet {
+        if self.is_locked() {
+            Wallet::from_pub_key(&wallet.public_key)
+        } else {
+            wallet.clone()
+        }
+    }
+
+    /// Stores the key pair of `wallet` with its private key encrypted under
+    /// `master_key`, bound to its public key
+    fn store_encrypted(&self, address: &str, wallet: &Wallet, master_key: &[u8]) -> Result<()> {
+        let stored = EncryptedWallet {
+            public_key: wallet.public_key.clone(),
+            encrypted_key: keystore::encrypt(master_key, &wallet.private_key, &wallet.public_key),
+        };
+        let keys = self.db.open_tree(KEYS_TREE)?;
+        keys.insert(address.as_bytes(), bincode::serialize(&stored)?)?;
+        keys.flush()?;
+        Ok(())
+    }
+
+    fn decrypt_keys(&mut self, master_key: Vec<u8>) -> Result<()> {
+        for entry in self.db.open_tree(KEYS_TREE)?.iter() {
+            let (addr, encoded) = entry?;
+            let stored: EncryptedWallet = bincode::deserialize(&encoded)?;
+            let private_key = keystore::decrypt(&master_key, &stored.encrypted_key, &stored.public_key)?;
+            self.wallets.insert(String::from_utf8(addr.to_vec())?, Wallet {
+                private_key,
+                public_key: stored.public_key,
+            });
+        }
+        self.master_key = Some(master_key);
+        Ok(())
+    }
+
+    pub fn get_wallet(&self, address: &str) -> Option<Wallet> {
+        self.wallets.get(address).map(|wallet| self.unless_locked(wallet))
+    }
+
+    pub fn get_wallets(&self) -> HashMap<String, Wallet> {
+        self.wallets.iter().map(|(address, wallet)| (address.clone(), self.unless_locked(wallet))).collect()
     }
 
     pub fn get_addresses(&self) -> Vec<String> {
-        let mut addresses = Vec::new();
+        self.wallets.keys().cloned().collect()
+    }
+
+    /// Remembers the multisig `redeem_script` so that the local keys it
+    /// lists can spend from its address. Returns that address, which starts
+    /// with `script_address_version`.
+    pub fn add_multisig(&self, redeem_script: &Script, script_address_version: u8) -> Result<String> {
+        if redeem_script.parse_multisig().is_none() {
+            return Err(format_err!("ERROR: Not a multisig script"));
+        }
+        if redeem_script.len() > MAX_SCRIPT_ELEMENT_SIZE {
+            return Err(format_err!("ERROR: Redeem script is longer than {} bytes", MAX_SCRIPT_ELEMENT_SIZE));
+        }
 
-        let db = sled::open("data/wallets").unwrap();
+        let mut script_hash = redeem_script.as_bytes().to_vec();
+        hash_pub_key(&mut script_hash);
+        let address = encode_address(script_address_version, &script_hash);
 
-        for wallet in db.iter() {
-            let i = wallet.unwrap();
-            let addr = String::from_utf8(i.0.to_vec()).unwrap();
-            addresses.push(addr);
+        let tree = self.db.open_tree(MULTISIG_TREE)?;
+        tree.insert(address.as_bytes(), redeem_script.as_bytes())?;
+        tree.flush()?;
+        info!("Added multisig address '{}'", address);
+        Ok(address)
+    }
+
+    /// Redeem script of a multisig address added with `add_multisig`
+    pub fn get_multisig(&self, address: &str) -> Result<Option<Script>> {
+        let tree = self.db.open_tree(MULTISIG_TREE)?;
+        Ok(tree.get(address.as_bytes())?.map(|script| Script::from_bytes(script.to_vec())))
+    }
+
+    /// Locking scripts of the key addresses and of the multisig addresses of
+    /// the wallet
+    pub fn get_owned_scripts(&self) -> Result<HashSet<Script>> {
+        let mut scripts: HashSet<Script> = self.wallets.values()
+            .map(|wallet| Script::p2pkh(&get_pub_key_hash(&wallet.public_key)))
+            .collect();
+        for address in self.db.open_tree(MULTISIG_TREE)?.iter().keys() {
+            let (_, hash) = decode_base58check(&String::from_utf8(address?.to_vec())?)?;
+            scripts.insert(Script::p2sh(&hash));
+        }
+        Ok(scripts)
+    }
+
+    /// Attaches `label` to an address of the network of the wallet or a
+    /// transaction id, or removes its label if `label` is empty
+    pub fn set_label(&self, key: &str, label: &str) -> Result<()> {
+        let is_txid = key.len() == 64 && key.chars().all(|c| c.is_ascii_hexdigit());
+        if !is_txid {
+            let (version, _) = decode_base58check(key)
+                .map_err(|_| format_err!("ERROR: '{}' is neither an address nor a transaction id", key))?;
+            if version != self.address_version && version != self.script_address_version {
+                return Err(format_err!("ERROR: Address '{}' is for another network", key));
+            }
+        }
+
+        let tree = self.db.open_tree(LABELS_TREE)?;
+        if label.is_empty() {
+            tree.remove(key.as_bytes())?;
+        } else {
+            tree.insert(key.as_bytes(), label.as_bytes())?;
         }
+        tree.flush()?;
+        Ok(())
+    }
+
+    /// Label of an address or a transaction id
+    pub fn get_label(&self, key: &str) -> Result<Option<String>> {
+        match self.db.open_tree(LABELS_TREE)?.get(key.as_bytes())? {
+            Some(label) => Ok(Some(String::from_utf8(label.to_vec())?)),
+            None => Ok(None),
+        }
+    }
 
-        drop(db);
-        addresses
+    /// Wallets holding the private key of one of `keys`
+    pub fn get_signers(&self, keys: &[Vec<u8>]) -> Vec<Wallet> {
+        self.wallets.values()
+            .map(|wallet| self.unless_locked(wallet))
+            .filter(|wallet| !wallet.private_key.is_empty() && keys.contains(&wallet.public_key))
+            .collect()
     }
 }
 
-pub fn new_wallets() -> Wallets {
-    Wallets::new()
+pub fn new_wallets(db: sled::Db, params: &ChainParams) -> Result<Wallets> {
+    Wallets::new(db, params)
 }
 
 pub fn new_wallet() -> Wallet {
     Wallet::new()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{temporary_db, temporary_dir};
+
+    #[test]
+    fn test_address_network() {
+        let wallet = Wallet::new();
+        let main = ChainParams::main().address_version;
+        let regtest = ChainParams::regtest().address_version;
+
+        let address = wallet.get_address(main);
+        assert_eq!(decode_address(&address, main).unwrap(), get_pub_key_hash(&wallet.public_key));
+        assert!(decode_address(&address, regtest).is_err());
+        assert!(decode_address(&wallet.get_address(regtest), regtest).is_ok());
+
+        let mut tampered = address.clone();
+        tampered.pop();
+        assert!(decode_address(&tampered, main).is_err());
+        assert!(decode_address("0OIl", main).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_keys() {
+        let db = temporary_db();
+        let params = ChainParams::regtest();
+        let version = params.address_version;
+
+        // Keys of older databases are encrypted along with the new ones
+        let legacy = Wallet::new();
+        let legacy_address = legacy.get_address(version);
+        db.insert(legacy_address.as_bytes(), bincode::serialize(&legacy).unwrap()).unwrap();
+
+        let mut wallets = Wallets::new(db.clone(), &params).unwrap();
+        assert!(!wallets.is_encrypted());
+        assert!(wallets.create_wallet().is_err());
+        assert!(wallets.set_passphrase("").is_err());
+        wallets.set_passphrase("secret").unwrap();
+        assert!(wallets.set_passphrase("other").is_err());
+        let address = wallets.create_wallet().unwrap();
+        let private_key = wallets.get_wallet(&address).unwrap().private_key;
+        assert!(!db.contains_key(legacy_address.as_bytes()).unwrap());
+        assert!(!db.open_tree(KEYS_TREE).unwrap().iter().values()
+            .any(|stored| stored.unwrap().windows(32).any(|bytes| bytes == private_key.as_slice())));
+
+        // A new process starts locked
+        let mut wallets = Wallets::new(db.clone(), &params).unwrap();
+        assert!(wallets.is_locked());
+        assert!(wallets.get_wallet(&address).unwrap().private_key.is_empty());
+        assert!(wallets.create_wallet().is_err());
+        assert!(wallets.unlock("wrong").is_err());
+        wallets.unlock("secret").unwrap();
+        assert_eq!(wallets.get_wallet(&address).unwrap().private_key, private_key);
+        assert_eq!(wallets.get_wallet(&legacy_address).unwrap().private_key, legacy.private_key);
+        assert!(Wallets::new(db.clone(), &params).unwrap().is_locked());
+
+        // Unlocking with a timeout lasts across processes through the
+        // session file, until it expires or the session ends
+        let dir = temporary_dir();
+        let session = dir.path().join("wallet.session");
+        let resumed = |session: &Path| {
+            let mut wallets = Wallets::new(db.clone(), &params).unwrap();
+            wallets.resume_session(session).unwrap();
+            wallets
+        };
+        assert!(resumed(&session).is_locked());
+        assert!(wallets.unlock_for("wrong", 60, &session).is_err());
+        assert!(!session.exists());
+        wallets.unlock_for("secret", 60, &session).unwrap();
+        assert!(!wallets.is_locked());
+        assert_eq!(resumed(&session).get_wallet(&address).unwrap().private_key, private_key);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&session).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        Wallets::end_session(&session).unwrap();
+        assert!(resumed(&session).is_locked());
+
+        wallets.unlock_for("secret", 0, &session).unwrap();
+        assert!(wallets.is_locked());
+        assert!(resumed(&session).is_locked());
+        assert!(!session.exists());
+        assert!(wallets.get_wallet(&address).unwrap().private_key.is_empty());
+        assert!(wallets.create_wallet().is_err());
+        wallets.unlock("secret").unwrap();
+        wallets.lock().unwrap();
+        assert!(wallets.get_wallet(&address).unwrap().private_key.is_empty());
+    }
+
+    #[test]
+    fn test_hd_wallet() {
+        let mnemonic = hd::entropy_to_mnemonic(&[1; 16]).unwrap();
+        let params = ChainParams::regtest();
+        let version = params.address_version;
+        let db = temporary_db();
+        let mut wallets = Wallets::new(db.clone(), &params).unwrap();
+        wallets.set_passphrase("secret").unwrap();
+        let random = wallets.create_wallet().unwrap();
+        assert_eq!(wallets.get_key_path(&random).unwrap(), None);
+
+        // Keys follow the receive chain once the mnemonic is set
+        assert!(wallets.set_mnemonic("abandon abandon about").is_err());
+        wallets.set_mnemonic(&mnemonic).unwrap();
+        assert!(wallets.set_mnemonic(&mnemonic).is_err());
+        let first = wallets.create_wallet().unwrap();
+        let second = wallets.create_wallet().unwrap();
+        let change = wallets.derive_next(CHANGE_CHAIN).unwrap();
+        assert_eq!(wallets.get_key_path(&second).unwrap(), Some(KeyPath { coin_type: 1, chain: RECEIVE_CHAIN, index: 1 }));
+        assert_eq!(wallets.get_key_path(&change).unwrap().unwrap().to_string(), "m/44'/1'/0'/1/0");
+        assert!(wallets.is_change(&change).unwrap());
+        assert!(!wallets.is_change(&first).unwrap());
+
+        // Change keys follow the change chain, which only moves on once one
+        // is stored
+        let change_key = wallets.next_change_key().unwrap();
+        assert_eq!(change_key.path, Some(KeyPath { coin_type: 1, chain: CHANGE_CHAIN, index: 1 }));
+        assert_eq!(wallets.next_change_key().unwrap().path, change_key.path);
+        let next_change = wallets.add_change_key(change_key).unwrap();
+        assert!(wallets.is_change(&next_change).unwrap());
+        assert_eq!(wallets.next_change_key().unwrap().path, Some(KeyPath { coin_type: 1, chain: CHANGE_CHAIN, index: 2 }));
+
+        let seed = hd::mnemonic_to_seed(&mnemonic, "").unwrap();
+        let account = ExtendedKey::from_seed(&seed).unwrap()
+            .derive_path(&hd::parse_path("m/44'/1'/0'").unwrap()).unwrap();
+        assert_eq!(wallets.get_wallet(&first).unwrap().private_key,
+            account.derive_path(&[RECEIVE_CHAIN, 0]).unwrap().private_key);
+
+        // Restoring the mnemonic elsewhere finds the used keys within the gap
+        // limit, and derivation resumes after the last one
+        let used: Vec<Vec<u8>> = [&second, &change].iter()
+            .map(|address| decode_address(address, version).unwrap())
+            .collect();
+        let far = account.derive_path(&[RECEIVE_CHAIN, 30]).unwrap();
+        let far = get_pub_key_hash(&derive_pub_key(&far.private_key).unwrap());
+        let is_used = |hash: &[u8]| used.iter().any(|used| used == hash) || hash == far.as_slice();
+
+        let mut restored = Wallets::new(temporary_db(), &params).unwrap();
+        assert!(restored.rescan(GAP_LIMIT, is_used).is_err());
+        restored.set_passphrase("other").unwrap();
+        restored.set_mnemonic(&mnemonic).unwrap();
+        let mut found = restored.rescan(GAP_LIMIT, is_used).unwrap();
+        found.sort();
+        let mut expected = vec![second.clone(), change.clone()];
+        expected.sort();
+        assert_eq!(found, expected);
+        assert!(restored.get_wallet(&first).is_some());
+        assert!(restored.get_wallet(&random).is_none());
+        assert!(restored.is_change(&change).unwrap());
+
+        // Main network accounts use coin type 0, so their keys differ
+        let mut main = Wallets::new(temporary_db(), &ChainParams::main()).unwrap();
+        main.set_passphrase("other").unwrap();
+        main.set_mnemonic(&mnemonic).unwrap();
+        let main_first = main.create_wallet().unwrap();
+        assert_eq!(main.get_key_path(&main_first).unwrap().unwrap().to_string(), "m/44'/0'/0'/0/0");
+        assert_ne!(main.get_wallet(&main_first).unwrap().public_key, wallets.get_wallet(&first).unwrap().public_key);
+        assert_eq!(restored.get_wallet(&second).unwrap().private_key,
+            wallets.get_wallet(&second).unwrap().private_key);
+        let next = restored.create_wallet().unwrap();
+        assert_eq!(restored.get_key_path(&next).unwrap(),
+            Some(KeyPath { coin_type: 1, chain: RECEIVE_CHAIN, index: 2 }));
+
+        // The seed is only readable while unlocked
+        let mut wallets = Wallets::new(db, &params).unwrap();
+        assert!(wallets.derive_next(RECEIVE_CHAIN).is_err());
+        wallets.unlock("secret").unwrap();
+        let next = wallets.create_wallet().unwrap();
+        assert_eq!(wallets.get_key_path(&next).unwrap(),
+            Some(KeyPath { coin_type: 1, chain: RECEIVE_CHAIN, index: 2 }));
+    }
+
+    #[test]
+    fn test_labels() {
+        let db = temporary_db();
+        let params = ChainParams::regtest();
+        let version = params.address_version;
+        let wallets = Wallets::new(db.clone(), &params).unwrap();
+        let address = Wallet::new().get_address(version);
+        let txid = "ab".repeat(32);
+
+        // Labels attach to addresses and transaction ids only, and last
+        // across processes until cleared
+        wallets.set_label(&address, "rent").unwrap();
+        wallets.set_label(&txid, "landlord").unwrap();
+        assert!(wallets.set_label("landlord", "rent").is_err());
+        let multisig = encode_address(params.script_address_version, &[0; 20]);
+        wallets.set_label(&multisig, "savings").unwrap();
+
+        // Addresses of another network would never match the history
+        let mainnet = Wallet::new().get_address(ChainParams::main().address_version);
+        assert!(wallets.set_label(&mainnet, "rent").is_err());
+        assert!(wallets.set_label(&encode_address(ChainParams::main().script_address_version, &[0; 20]), "rent")
+            .is_err());
+        assert_eq!(wallets.get_label(&mainnet).unwrap(), None);
+        let wallets = Wallets::new(db, &params).unwrap();
+        assert_eq!(wallets.get_label(&address).unwrap(), Some("rent".to_string()));
+        assert_eq!(wallets.get_label(&txid).unwrap(), Some("landlord".to_string()));
+        wallets.set_label(&address, "").unwrap();
+        assert_eq!(wallets.get_label(&address).unwrap(), None);
+    }
+}